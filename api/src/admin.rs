@@ -0,0 +1,222 @@
+//! Management of the library through the API, authorized with one of the admin tokens.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use subtle::ConstantTimeEq;
+use tracing::{error, warn};
+
+use crate::{ingest, APIState};
+
+/// Refuses requests without `Authorization: Bearer <token>` for one of the admin tokens with a
+/// 401, and every request with a 403 while no token is configured.
+pub async fn authorize(
+    State(state): State<Arc<APIState<'_>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let tokens = &state.configuration.admin.tokens;
+
+    if tokens.is_empty() {
+        warn!(
+            "Refused {} {}: no admin token",
+            request.method(),
+            request.uri()
+        );
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let bearer = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    // compared in constant time, so timing doesn't tell how much of a token matched
+    let authorized = bearer.is_some_and(|bearer| {
+        tokens
+            .iter()
+            .any(|t| bool::from(t.as_bytes().ct_eq(bearer.trim().as_bytes())))
+    });
+
+    if !authorized {
+        warn!("Refused {} {}: bad token", request.method(), request.uri());
+        return (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response();
+    }
+
+    next.run(request).await
+}
+
+/// Removes the master of `image` and everything derived from it, for takedowns.
+#[tracing::instrument]
+pub async fn delete_image(
+    Path(image): Path<String>,
+    State(state): State<Arc<APIState<'_>>>,
+) -> Result<StatusCode, StatusCode> {
+    match ingest::known_name(&state, &image) {
+        Ok(true) => {}
+        Ok(false) => return Err(StatusCode::BAD_REQUEST),
+        Err(e) => {
+            error!("Failed to read the index: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    match ingest::remove(&state, &image) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Failed to remove {:?}: {}", image, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use axum::{body::Body, middleware, routing::delete, Router};
+    use configuration::config::Settings;
+    use storage::index::IndexEntry;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::testing;
+
+    fn state(test: &str, tokens: &[&str]) -> Arc<APIState<'static>> {
+        let mut settings = Settings::default();
+        settings.admin.tokens = tokens.iter().map(|t| t.to_string()).collect();
+
+        testing::state("admin", test, settings)
+    }
+
+    async fn delete_request(
+        state: &Arc<APIState<'static>>,
+        image: &str,
+        token: Option<&str>,
+    ) -> StatusCode {
+        let app = Router::new()
+            .route("/images/:image", delete(delete_image))
+            .route_layer(middleware::from_fn_with_state(Arc::clone(state), authorize))
+            .with_state(Arc::clone(state));
+
+        let mut request = Request::delete(format!("/images/{}", image));
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_admin_token_required() {
+        let disabled = state("disabled", &[]);
+        assert_eq!(
+            delete_request(&disabled, "fox", Some("")).await,
+            StatusCode::FORBIDDEN
+        );
+
+        let state = state("token", &["new", "old"]);
+        assert_eq!(
+            delete_request(&state, "fox", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            delete_request(&state, "fox", Some("ne")).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // authorized, there is just nothing to delete
+        assert_eq!(
+            delete_request(&state, "fox", Some("old")).await,
+            StatusCode::NOT_FOUND
+        );
+
+        testing::clean(&disabled);
+        testing::clean(&state);
+    }
+
+    #[tokio::test]
+    async fn test_delete_removes_derivatives() {
+        let state = state("delete", &["token"]);
+        let output_path = &state.configuration.image.output_path;
+
+        fs::write(output_path.join("fox.avif"), b"master").unwrap();
+        fs::write(output_path.join("fox.pdf"), b"source").unwrap();
+        fs::create_dir_all(output_path.join("tiles/fox/0")).unwrap();
+        fs::write(output_path.join("fox-other.avif"), b"master").unwrap();
+        state
+            .index
+            .lock()
+            .unwrap()
+            .insert(IndexEntry::new("fox", 0))
+            .unwrap();
+
+        assert_eq!(
+            delete_request(&state, "fox", Some("token")).await,
+            StatusCode::NO_CONTENT
+        );
+
+        assert!(!output_path.join("fox.avif").exists());
+        assert!(!output_path.join("fox.pdf").exists());
+        assert!(!output_path.join("tiles/fox").exists());
+        assert!(output_path.join("fox-other.avif").exists());
+        assert!(state.index.lock().unwrap().get("fox").is_none());
+
+        assert_eq!(
+            delete_request(&state, "fox", Some("token")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            delete_request(&state, "..", Some("token")).await,
+            StatusCode::BAD_REQUEST
+        );
+
+        testing::clean(&state);
+    }
+
+    #[tokio::test]
+    async fn test_delete_dropped_file_ids() {
+        let state = state("dropped", &["token"]);
+        let output_path = &state.configuration.image.output_path;
+
+        // ids of files dropped in the input path are their names, dots and spaces included
+        fs::write(output_path.join("fox photo.v2.avif"), b"master").unwrap();
+        state
+            .index
+            .lock()
+            .unwrap()
+            .insert(IndexEntry::new("fox photo.v2", 0))
+            .unwrap();
+
+        assert_eq!(
+            delete_request(&state, "fox%20photo.v2", Some("token")).await,
+            StatusCode::NO_CONTENT
+        );
+        assert!(!output_path.join("fox photo.v2.avif").exists());
+
+        // not in the library anymore, so not a valid name to delete
+        assert_eq!(
+            delete_request(&state, "fox%20photo.v2", Some("token")).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            delete_request(&state, "..%2Ffox", Some("token")).await,
+            StatusCode::BAD_REQUEST
+        );
+
+        testing::clean(&state);
+    }
+}
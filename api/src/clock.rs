@@ -0,0 +1,13 @@
+//! Wall clock time, for the expiry of signed URLs and the ingest time of masters.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    seconds(SystemTime::now()).unwrap_or(0)
+}
+
+/// Seconds from the Unix epoch to `time`, `None` before it.
+pub fn seconds(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
@@ -0,0 +1,794 @@
+//! Transforms of images fetched from origin URLs, `/fetch/<options>/<url>`. Origins are limited
+//! to the allowed domains and to public addresses, so requests can't reach internal services.
+
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use image_processing::{dimensions, svg, transcoder::PixelSize};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect, Client, Url,
+};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+
+use crate::{
+    accept,
+    format::RequestedFormat,
+    hints::{ClientHints, SIZED_HINTS, UNSIZED_HINTS},
+    image_response, ingest, limits,
+    options::RequestOptions,
+    read_master,
+    transform::{transform, Transform},
+    APIState, Master,
+};
+
+const FETCHED_PREFIX: &str = "fetch-";
+
+/// An origin that can't be fetched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchError {
+    InvalidUrl,
+    NotAllowed(String),
+    PrivateAddress(IpAddr),
+    TooManyRedirects,
+    TooLarge,
+    Status(StatusCode),
+    Timeout,
+    Failed(String),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::InvalidUrl => write!(f, "invalid origin URL"),
+            FetchError::NotAllowed(host) => write!(f, "{:?} is not an allowed origin", host),
+            FetchError::PrivateAddress(ip) => write!(f, "{} is not a public address", ip),
+            FetchError::TooManyRedirects => write!(f, "too many redirects"),
+            FetchError::TooLarge => write!(f, "origin image is too large"),
+            FetchError::Status(status) => write!(f, "origin answered {}", status),
+            FetchError::Timeout => write!(f, "origin timed out"),
+            FetchError::Failed(e) => write!(f, "fetch failed: {}", e),
+        }
+    }
+}
+
+impl Error for FetchError {}
+
+impl FetchError {
+    fn status(&self) -> StatusCode {
+        match self {
+            FetchError::InvalidUrl => StatusCode::BAD_REQUEST,
+            FetchError::NotAllowed(_) | FetchError::PrivateAddress(_) => StatusCode::FORBIDDEN,
+            FetchError::Status(StatusCode::NOT_FOUND) => StatusCode::NOT_FOUND,
+            FetchError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        // errors of the resolver and the redirect policy are wrapped by reqwest
+        let mut source = e.source();
+        while let Some(cause) = source {
+            if let Some(fetch_error) = cause.downcast_ref::<FetchError>() {
+                return fetch_error.clone();
+            }
+            source = cause.source();
+        }
+
+        if e.is_timeout() {
+            FetchError::Timeout
+        } else {
+            FetchError::Failed(e.to_string())
+        }
+    }
+}
+
+/// Options in the path, comma separated `<key>_<value>` pairs or `-` for none: `w_<width>`,
+/// `h_<height>`, `o_<extension>` and `q_<quality>`. A missing width or height keeps the aspect
+/// ratio of the original.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FetchOptions {
+    width: Option<u32>,
+    height: Option<u32>,
+    format: RequestedFormat,
+    quality: Option<u8>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            width: None,
+            height: None,
+            format: RequestedFormat::Auto,
+            quality: None,
+        }
+    }
+}
+
+impl FromStr for FetchOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = FetchOptions::default();
+
+        if s == "-" {
+            return Ok(options);
+        }
+
+        for option in s.split(',') {
+            let (key, value) = option
+                .split_once('_')
+                .ok_or_else(|| anyhow::anyhow!("invalid option {:?}", option))?;
+
+            match key {
+                "w" => options.width = Some(value.parse()?),
+                "h" => options.height = Some(value.parse()?),
+                "q" => options.quality = Some(value.parse()?),
+                "o" => {
+                    options.format = RequestedFormat::from_extension(value)
+                        .ok_or_else(|| anyhow::anyhow!("unknown format {:?}", value))?
+                }
+                _ => anyhow::bail!("unknown option {:?}", key),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+impl FetchOptions {
+    /// Size to resize to, the missing side is computed from the size of the original.
+    fn size(&self, original: &[u8]) -> anyhow::Result<Option<PixelSize>> {
+        let scaled = |side: u32, from: u32, to: u32| {
+            ((side as u64 * to as u64) / from.max(1) as u64).max(1) as u32
+        };
+
+        Ok(match (self.width, self.height) {
+            (Some(width), Some(height)) => Some(PixelSize::new(width, height)),
+            (Some(width), None) => {
+                let (w, h) = dimensions(original)?;
+                Some(PixelSize::new(width, scaled(width, w, h)))
+            }
+            (None, Some(height)) => {
+                let (w, h) = dimensions(original)?;
+                Some(PixelSize::new(scaled(height, h, w), height))
+            }
+            (None, None) => None,
+        })
+    }
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn serve_fetched(
+    Path((fetch_options, url)): Path<(String, String)>,
+    Query(mut options): Query<RequestOptions>,
+    headers: HeaderMap,
+    State(state): State<Arc<APIState<'static>>>,
+) -> axum::response::Result<Response> {
+    if !state.configuration.fetch.enabled {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    let Ok(fetch_options) = fetch_options.parse::<FetchOptions>() else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    if let RequestedFormat::Fixed(extension) = fetch_options.format {
+        if !state.configuration.image.formats.contains(&extension) {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+    }
+
+    if fetch_options.quality.is_some() {
+        options.quality = fetch_options.quality;
+    }
+
+    let url = match origin_url(&url, &state) {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Refused to fetch {:?}: {}", url, e);
+            return Err(e.status().into());
+        }
+    };
+
+    let id = fetched_id(&url);
+    let original = match load(&id, &url, &state).await {
+        Ok(original) => original,
+        Err(e) => {
+            warn!("Failed to fetch {}: {}", url, e);
+            return Err(e.status().into());
+        }
+    };
+
+    // reading the size of an AVIF original decodes it
+    let (original, size) = tokio::task::spawn_blocking(move || {
+        let size = fetch_options.size(&original.bytes);
+        (original, size)
+    })
+    .await
+    .map_err(|e| {
+        error!("Failed to read the size of {}: {}", url, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let size = match size {
+        Ok(size) => size,
+        Err(e) => {
+            warn!("Failed to read the size of {}: {}", url, e);
+            return Err(StatusCode::BAD_GATEWAY.into());
+        }
+    };
+
+    if let Some(size) = &size {
+        if let Err(e) = limits::check(size, &state.configuration.image) {
+            warn!("Refused to resize {}: {}", url, e);
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+    }
+
+    let request = Transform {
+        format: fetch_options.format,
+        accept: accept(&headers).map(str::to_owned),
+        size,
+        options,
+        hints: ClientHints::from_request(&headers, &state.configuration.image.client_hints),
+    };
+
+    let encoded_image = transform(id, original, None, request, Arc::clone(&state)).await;
+
+    Ok(image_response(
+        encoded_image,
+        fetch_options.format,
+        if size.is_some() {
+            &SIZED_HINTS[..]
+        } else {
+            &UNSIZED_HINTS[..]
+        },
+        &state,
+    ))
+}
+
+/// Id of the master a fetched original is persisted as.
+fn fetched_id(url: &Url) -> String {
+    let digest = Sha256::digest(url.as_str().as_bytes());
+    let hex: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!("{}{}", FETCHED_PREFIX, hex)
+}
+
+/// Original of `url`, from storage when fetched originals are persisted.
+async fn load(id: &str, url: &Url, state: &Arc<APIState<'static>>) -> Result<Master, FetchError> {
+    let config = state.configuration;
+    let extension = url
+        .path()
+        .rsplit_once('.')
+        .map(|(_, e)| e.to_lowercase())
+        .unwrap_or_default();
+
+    if !config.fetch.persist {
+        return Ok(Master {
+            bytes: fetch(url, state).await?,
+            extension,
+        });
+    }
+
+    if let Ok(master) = read_master(id, config).await {
+        return Ok(master);
+    }
+
+    let original = fetch(url, state).await?;
+
+    let ingested = {
+        let state = Arc::clone(state);
+        let (id, extension, original) = (id.to_owned(), extension.clone(), original.clone());
+
+        tokio::task::spawn_blocking(move || ingest::ingest(&state, &id, &extension, original))
+            .await
+            .unwrap_or_else(|e| Err(e.into()))
+    };
+
+    // a fetched image that can't be stored, a rejected duplicate, is still served
+    match ingested {
+        Ok(_) => {
+            info!("Persisted {} as {:?}", url, id);
+            read_master(id, config)
+                .await
+                .map_err(|e| FetchError::Failed(e.to_string()))
+        }
+        Err(e) => {
+            warn!("Failed to persist {}: {}", url, e);
+            Ok(Master {
+                bytes: original,
+                extension,
+            })
+        }
+    }
+}
+
+async fn fetch(url: &Url, state: &APIState<'_>) -> Result<Vec<u8>, FetchError> {
+    let config = state.configuration;
+    let max_bytes = config
+        .fetch
+        .max_bytes
+        .min(config.image.decode.max_input_bytes);
+
+    let mut response = client(state)?.get(url.clone()).send().await?;
+
+    if !response.status().is_success() {
+        return Err(FetchError::Status(response.status()));
+    }
+
+    if response.content_length().is_some_and(|l| l > max_bytes) {
+        return Err(FetchError::TooLarge);
+    }
+
+    // the length can be missing or wrong, so the body is capped as it is read
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > max_bytes {
+            return Err(FetchError::TooLarge);
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    // resvg would load the files an SVG links to
+    if svg::is_svg(&body) {
+        return svg::sanitize(&body).map_err(|e| FetchError::Failed(e.to_string()));
+    }
+
+    Ok(body)
+}
+
+fn client<'a>(state: &'a APIState<'_>) -> Result<&'a Client, FetchError> {
+    if let Some(client) = state.fetch_client.get() {
+        return Ok(client);
+    }
+
+    let config = &state.configuration.fetch;
+    let timeout = Duration::from_secs(config.timeout);
+
+    let allowed_domains = config.allowed_domains.clone();
+    let allow_private = config.allow_private_addresses;
+    let max_redirects = config.max_redirects;
+
+    let policy = redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_redirects {
+            return attempt.error(FetchError::TooManyRedirects);
+        }
+
+        match check_url(attempt.url(), &allowed_domains, allow_private) {
+            Ok(()) => attempt.follow(),
+            Err(e) => attempt.error(e),
+        }
+    });
+
+    let mut builder = Client::builder()
+        .timeout(timeout)
+        .connect_timeout(timeout)
+        .redirect(policy)
+        .no_proxy()
+        .user_agent(concat!("wire-img/", env!("CARGO_PKG_VERSION")));
+
+    if !allow_private {
+        builder = builder.dns_resolver(Arc::new(PublicResolver));
+    }
+
+    let client = builder
+        .build()
+        .map_err(|e| FetchError::Failed(e.to_string()))?;
+
+    Ok(state.fetch_client.get_or_init(|| client))
+}
+
+/// Origin URL from the path. `?` must be encoded in the path, the query string is for the
+/// options of the request.
+fn origin_url(url: &str, state: &APIState<'_>) -> Result<Url, FetchError> {
+    let url = Url::parse(url).map_err(|_| FetchError::InvalidUrl)?;
+    let config = &state.configuration.fetch;
+
+    check_url(
+        &url,
+        &config.allowed_domains,
+        config.allow_private_addresses,
+    )?;
+
+    Ok(url)
+}
+
+/// Refuses URLs that are not HTTP, not on an allowed domain or on a literal non-public address.
+/// Names are checked once resolved, by [`PublicResolver`].
+fn check_url(url: &Url, allowed_domains: &[String], allow_private: bool) -> Result<(), FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::InvalidUrl);
+    }
+
+    let host = url.host_str().ok_or(FetchError::InvalidUrl)?;
+
+    if !domain_allowed(host, allowed_domains) {
+        return Err(FetchError::NotAllowed(host.to_owned()));
+    }
+
+    // IPv6 hosts are bracketed in URLs
+    let ip = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok();
+
+    match ip {
+        Some(ip) if !allow_private && !is_public(ip) => Err(FetchError::PrivateAddress(ip)),
+        _ => Ok(()),
+    }
+}
+
+fn domain_allowed(host: &str, allowed_domains: &[String]) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+
+    allowed_domains.iter().any(|allowed| {
+        let allowed = allowed.to_lowercase();
+
+        match allowed.strip_prefix("*.") {
+            Some(parent) => host == parent || host.ends_with(&format!(".{}", parent)),
+            None => host == allowed,
+        }
+    })
+}
+
+/// Tells if `ip` is reachable on the internet, loopback, private, link-local, shared, reserved
+/// and documentation ranges are not.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "this network", shared address space, IETF protocol assignments and benchmarking
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b))
+        // reserved
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    // IPv4 mapped and NAT64 addresses reach the IPv4 address they embed
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_public_v4(v4);
+    }
+
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [a, b] = segments[6].to_be_bytes();
+        let [c, d] = segments[7].to_be_bytes();
+
+        return is_public_v4(Ipv4Addr::new(a, b, c, d));
+    }
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // documentation
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+}
+
+/// Resolves names to their public addresses only, the addresses connected to are the ones
+/// checked so a name can't resolve to a public address for the check and a private one after.
+#[derive(Debug)]
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let resolved: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+
+            let public: Vec<SocketAddr> = resolved
+                .iter()
+                .copied()
+                .filter(|a| is_public(a.ip()))
+                .collect();
+
+            match (public.is_empty(), resolved.first()) {
+                (true, Some(blocked)) => Err(FetchError::PrivateAddress(blocked.ip()).into()),
+                _ => Ok(Box::new(public.into_iter()) as Addrs),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body, extract::Request, http::header::CONTENT_TYPE, response::Redirect, routing::get,
+        Router,
+    };
+    use configuration::config::{Settings, SizeMode};
+    use storage::index::ImageIndex;
+    use tokio::net::TcpListener;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        testing::{self, resource},
+        EFFECTIVE_SIZE_HEADER,
+    };
+
+    /// Stand-in for an origin, on a local port.
+    async fn origin() -> SocketAddr {
+        let fox = resource("100x100.jpg");
+
+        let app = Router::new()
+            .route("/fox.jpg", get(move || async move { fox.clone() }))
+            .route(
+                "/redirect/:count",
+                get(|Path(count): Path<u32>| async move {
+                    match count {
+                        0 => Redirect::temporary("/fox.jpg"),
+                        n => Redirect::temporary(&format!("/redirect/{}", n - 1)),
+                    }
+                }),
+            )
+            .route(
+                "/elsewhere",
+                get(|headers: HeaderMap| async move {
+                    let port = headers["host"].to_str().unwrap().rsplit(':').next();
+                    Redirect::temporary(&format!("http://localhost:{}/fox.jpg", port.unwrap()))
+                }),
+            );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        address
+    }
+
+    fn state(test: &str, configure: impl FnOnce(&mut Settings)) -> Arc<APIState<'static>> {
+        let mut settings = Settings::default();
+        settings.fetch.enabled = true;
+        settings.fetch.allowed_domains = vec!["127.0.0.1".to_owned()];
+        settings.fetch.allow_private_addresses = true;
+        configure(&mut settings);
+
+        testing::state("fetch", test, settings)
+    }
+
+    async fn get_fetched(state: &Arc<APIState<'static>>, uri: String) -> Response {
+        let app = Router::new()
+            .route("/fetch/:options/*url", get(serve_fetched))
+            .with_state(Arc::clone(state));
+
+        app.oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_public_addresses() {
+        for ip in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_allowed_domains() {
+        let allowed = vec![
+            "images.example.com".to_owned(),
+            "*.cdn.example.com".to_owned(),
+        ];
+
+        assert!(domain_allowed("images.example.com", &allowed));
+        assert!(domain_allowed("IMAGES.example.com.", &allowed));
+        assert!(domain_allowed("cdn.example.com", &allowed));
+        assert!(domain_allowed("eu.cdn.example.com", &allowed));
+        assert!(!domain_allowed("example.com", &allowed));
+        assert!(!domain_allowed("evilcdn.example.com", &allowed));
+        assert!(!domain_allowed("images.example.com.evil.net", &allowed));
+    }
+
+    #[test]
+    fn test_fetch_options() {
+        let options: FetchOptions = "w_300,o_png,q_80".parse().unwrap();
+        assert_eq!(options.width, Some(300));
+        assert_eq!(options.height, None);
+        assert_eq!(options.quality, Some(80));
+        assert_ne!(options.format, RequestedFormat::Auto);
+
+        assert_eq!(
+            "-".parse::<FetchOptions>().unwrap(),
+            FetchOptions::default()
+        );
+        assert!("w300".parse::<FetchOptions>().is_err());
+        assert!("x_1".parse::<FetchOptions>().is_err());
+        assert!("o_bmp".parse::<FetchOptions>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_transform() {
+        let origin = origin().await;
+        let state = state("transform", |_| {});
+
+        let response = get_fetched(
+            &state,
+            format!("/fetch/w_50,o_png/http://{}/fox.jpg", origin),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+        assert_eq!(response.headers()[EFFECTIVE_SIZE_HEADER], "50x50");
+
+        let response = get_fetched(&state, format!("/fetch/-/http://{}/redirect/2", origin)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // nothing is stored unless asked to
+        assert!(ImageIndex::open(&state.configuration.image.output_path)
+            .unwrap()
+            .is_empty());
+
+        testing::clean(&state);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_sizes_allowed() {
+        let origin = origin().await;
+        let state = state("allowed", |s| {
+            s.image.sizes.mode = SizeMode::Allowed;
+            s.image.sizes.allowed = vec![[50, 50]];
+        });
+        let status = |options: &str| {
+            let uri = format!("/fetch/{}/http://{}/fox.jpg", options, origin);
+            let state = state.clone();
+            async move { get_fetched(&state, uri).await.status() }
+        };
+
+        assert_eq!(status("w_50,o_png").await, StatusCode::OK);
+        assert_eq!(status("h_50,o_png").await, StatusCode::OK);
+        assert_eq!(status("w_60,o_png").await, StatusCode::BAD_REQUEST);
+        assert_eq!(status("h_40,o_png").await, StatusCode::BAD_REQUEST);
+        assert_eq!(status("w_50,h_40,o_png").await, StatusCode::BAD_REQUEST);
+
+        testing::clean(&state);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_refused() {
+        let origin = origin().await;
+        let status = |state: &Arc<APIState<'static>>, path: &'static str| {
+            let state = Arc::clone(state);
+            async move {
+                get_fetched(&state, format!("/fetch/-/http://{}{}", origin, path))
+                    .await
+                    .status()
+            }
+        };
+
+        // the origin is on loopback, like an internal service would be
+        let public_only = state("public", |s| s.fetch.allow_private_addresses = false);
+        assert_eq!(
+            status(&public_only, "/fox.jpg").await,
+            StatusCode::FORBIDDEN
+        );
+
+        let resolved = state("resolved", |s| {
+            s.fetch.allow_private_addresses = false;
+            s.fetch.allowed_domains = vec!["localhost".to_owned()];
+        });
+        let response = get_fetched(
+            &resolved,
+            format!("/fetch/-/http://localhost:{}/fox.jpg", origin.port()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let other_domain = state("domain", |s| {
+            s.fetch.allowed_domains = vec!["images.example.com".to_owned()]
+        });
+        assert_eq!(
+            status(&other_domain, "/fox.jpg").await,
+            StatusCode::FORBIDDEN
+        );
+
+        let redirects = state("redirects", |_| {});
+        assert_eq!(
+            status(&redirects, "/redirect/3").await,
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            status(&redirects, "/elsewhere").await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(&redirects, "/missing.jpg").await,
+            StatusCode::NOT_FOUND
+        );
+
+        let small = state("small", |s| s.fetch.max_bytes = 100);
+        assert_eq!(status(&small, "/fox.jpg").await, StatusCode::BAD_GATEWAY);
+
+        let disabled = state("disabled", |s| s.fetch.enabled = false);
+        assert_eq!(status(&disabled, "/fox.jpg").await, StatusCode::NOT_FOUND);
+
+        for state in [
+            public_only,
+            resolved,
+            other_domain,
+            redirects,
+            small,
+            disabled,
+        ] {
+            testing::clean(&state);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_persisted() {
+        let origin = origin().await;
+        let state = state("persist", |s| s.fetch.persist = true);
+
+        let uri = format!("/fetch/w_20,h_10,o_png/http://{}/fox.jpg", origin);
+        assert_eq!(
+            get_fetched(&state, uri.clone()).await.status(),
+            StatusCode::OK
+        );
+
+        let url = Url::parse(&format!("http://{}/fox.jpg", origin)).unwrap();
+        let id = fetched_id(&url);
+        let output_path = &state.configuration.image.output_path;
+
+        assert!(output_path.join(format!("{}.avif", id)).exists());
+        assert!(state.index.lock().unwrap().get(&id).is_some());
+
+        // served from storage once persisted
+        assert_eq!(get_fetched(&state, uri).await.status(), StatusCode::OK);
+
+        testing::clean(&state);
+    }
+}
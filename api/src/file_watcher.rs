@@ -1,15 +1,9 @@
 use std::{env, fs, io::Read, path::PathBuf, sync::Arc};
 
-use configuration::ImageEncoding;
-use image_processing::{
-    transcoder::{Encoder, Transcoder},
-    ImageFormat,
-};
 use notify::{Config, RecommendedWatcher, Watcher};
-use storage::disk::{DiskStorage, File};
-use tracing::{error, info};
+use tracing::error;
 
-use crate::APIState;
+use crate::{ingest, APIState};
 
 #[derive(Debug)]
 pub struct ImageWatcher<'a> {
@@ -67,7 +61,9 @@ impl<'a> ImageWatcher<'a> {
             notify::EventKind::Create(create_kind) => match create_kind {
                 notify::event::CreateKind::File => {
                     if let Some(p) = event.paths.into_iter().next() {
-                        let _ = self.load_file(p, &self.state.transcoder);
+                        if let Err(e) = self.load_file(p.clone()) {
+                            error!("'{:?}' was not ingested: {}", p, e);
+                        }
                     }
                     Ok(())
                 }
@@ -81,7 +77,7 @@ impl<'a> ImageWatcher<'a> {
     }
 
     #[tracing::instrument]
-    pub fn load_file(&self, path: PathBuf, transcoder: &Transcoder) -> anyhow::Result<()> {
+    pub fn load_file(&self, path: PathBuf) -> anyhow::Result<()> {
         let content_result = fs::OpenOptions::new().read(true).open(path.clone());
 
         match content_result {
@@ -94,9 +90,11 @@ impl<'a> ImageWatcher<'a> {
 
                 let extension = path
                     .extension()
-                    .expect("file has no stem (filename)")
-                    .to_str()
-                    .expect("filename is not valid UTF8");
+                    .and_then(|e| e.to_str())
+                    .unwrap_or_default();
+
+                // refused before reading, the decoders check the rest of the limits
+                ingest::check_size(f.metadata()?.len(), &self.state)?;
 
                 let mut buf: Vec<u8> = Vec::new();
 
@@ -104,24 +102,7 @@ impl<'a> ImageWatcher<'a> {
                     f.read_to_end(&mut buf)?;
                 }
 
-                let storage_format = match self.state.configuration.image.storage_format {
-                    ImageEncoding::PNG => ImageFormat::Png,
-                    ImageEncoding::JPEG => ImageFormat::Jpeg,
-                    ImageEncoding::AVIF => ImageFormat::Avif,
-                };
-
-                let _new_format = transcoder.transcode(
-                    &buf,
-                    extension.to_owned(),
-                    storage_format,
-                    // image_processing::ImageFormat::Avif,
-                    None,
-                )?;
-
-                let output_path = &self.state.configuration.image.output_path;
-
-                let storage = DiskStorage::from_path(output_path)?;
-                let new_path = storage.add_new_file(File::new(filename, "avif"), &_new_format);
+                ingest::ingest(&self.state, filename, extension, buf)?;
 
                 // TODO: make a global settings struct for env vars
                 if env::var("DELETE_ORIGINAL_FILE").is_ok()
@@ -130,9 +111,7 @@ impl<'a> ImageWatcher<'a> {
                     fs::remove_file(path.clone())?;
                 }
 
-                info!("'{:?}' stored at '{:?}'", &path, new_path);
-
-                return Ok(());
+                Ok(())
             }
             Err(e) => anyhow::bail!("failed to read file '{}'", e),
         }
@@ -0,0 +1,195 @@
+use configuration::ImageEncoding;
+
+/// Format asked for in the URL. `auto` lets the server choose from the `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestedFormat {
+    Fixed(ImageEncoding),
+    Auto,
+}
+
+impl RequestedFormat {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "auto" => Some(RequestedFormat::Auto),
+            _ => ImageEncoding::from_extension(extension)
+                .filter(image_processing::is_supported)
+                .map(RequestedFormat::Fixed),
+        }
+    }
+}
+
+/// Format served when nothing else decides: JPEG when allowed, as every client decodes it and
+/// photos are large in lossless formats, else the first allowed format.
+pub fn fallback_format(allowed: &[ImageEncoding]) -> ImageEncoding {
+    if allowed.contains(&ImageEncoding::JPEG) {
+        return ImageEncoding::JPEG;
+    }
+
+    allowed.first().copied().unwrap_or(ImageEncoding::JPEG)
+}
+
+/// What the negotiation needs to know about the served image.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImageTraits {
+    pub transparent: bool,
+    pub animated: bool,
+}
+
+/// Quality value `accept` gives to `content_type`, `None` when it isn't listed. Wildcards are
+/// ignored, `image/*` is sent by clients that don't decode AVIF.
+fn listed_quality(accept: &str, content_type: &str) -> Option<f32> {
+    accept.split(',').find_map(|range| {
+        let mut params = range.split(';').map(str::trim);
+
+        if !params.next()?.eq_ignore_ascii_case(content_type) {
+            return None;
+        }
+
+        let quality = params
+            .filter_map(|p| p.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok());
+
+        // a malformed quality doesn't count as acceptance
+        Some(quality.unwrap_or(0.0))
+    })
+}
+
+/// Picks the best allowed format the client accepts. JPEG, PNG and GIF are assumed to be
+/// supported by every client unless refused with `q=0`, AVIF, WebP and JPEG XL only when listed
+/// in `accept` with a quality above 0. Transparent images only get formats with an alpha channel
+/// and animations formats that keep every frame. When the client accepts none of the allowed
+/// formats, the first allowed one is served.
+pub fn negotiate(
+    accept: Option<&str>,
+    allowed: &[ImageEncoding],
+    traits: ImageTraits,
+) -> ImageEncoding {
+    let listed =
+        |encoding: &ImageEncoding| accept.and_then(|a| listed_quality(a, encoding.content_type()));
+
+    let accepts = |encoding: &ImageEncoding| match encoding {
+        ImageEncoding::AVIF | ImageEncoding::WEBP => listed(encoding).is_some_and(|q| q > 0.0),
+        ImageEncoding::JXL => listed(encoding).is_some_and(|q| q > 0.0),
+        ImageEncoding::JPEG | ImageEncoding::PNG | ImageEncoding::GIF => {
+            listed(encoding).is_none_or(|q| q > 0.0)
+        }
+    };
+
+    let preference: &[ImageEncoding] = if traits.animated {
+        &[ImageEncoding::WEBP, ImageEncoding::GIF, ImageEncoding::PNG]
+    } else if traits.transparent {
+        &[ImageEncoding::WEBP, ImageEncoding::PNG]
+    } else {
+        &[
+            ImageEncoding::AVIF,
+            ImageEncoding::WEBP,
+            ImageEncoding::JPEG,
+            ImageEncoding::PNG,
+        ]
+    };
+
+    preference
+        .iter()
+        .find(|e| allowed.contains(e) && accepts(e))
+        .or_else(|| allowed.iter().find(|e| accepts(e)))
+        .or(allowed.first())
+        .copied()
+        .unwrap_or(ImageEncoding::JPEG)
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::ImageEncoding::{self, AVIF, GIF, JPEG, PNG, WEBP};
+
+    use super::*;
+
+    const ALL: [ImageEncoding; 4] = [AVIF, WEBP, JPEG, PNG];
+    const OPAQUE: ImageTraits = ImageTraits {
+        transparent: false,
+        animated: false,
+    };
+    const TRANSPARENT: ImageTraits = ImageTraits {
+        transparent: true,
+        animated: false,
+    };
+    const BROWSER_ACCEPT: &str = "image/avif,image/webp,image/apng,image/*,*/*;q=0.8";
+
+    #[test]
+    fn test_negotiate_prefers_modern_formats() {
+        assert_eq!(negotiate(Some(BROWSER_ACCEPT), &ALL, OPAQUE), AVIF);
+        assert_eq!(negotiate(Some("image/webp,*/*"), &ALL, OPAQUE), WEBP);
+        assert_eq!(negotiate(Some("*/*"), &ALL, OPAQUE), JPEG);
+        assert_eq!(negotiate(None, &ALL, OPAQUE), JPEG);
+    }
+
+    #[test]
+    fn test_negotiate_transparent() {
+        assert_eq!(negotiate(Some(BROWSER_ACCEPT), &ALL, TRANSPARENT), WEBP);
+        assert_eq!(negotiate(Some("*/*"), &ALL, TRANSPARENT), PNG);
+    }
+
+    #[test]
+    fn test_negotiate_animated() {
+        let animated = ImageTraits {
+            transparent: false,
+            animated: true,
+        };
+
+        assert_eq!(
+            negotiate(Some(BROWSER_ACCEPT), &[AVIF, WEBP, GIF], animated),
+            WEBP
+        );
+        assert_eq!(negotiate(Some("*/*"), &[AVIF, WEBP, GIF], animated), GIF);
+        assert_eq!(negotiate(Some("*/*"), &ALL, animated), PNG);
+    }
+
+    #[test]
+    fn test_negotiate_respects_allowed_formats() {
+        assert_eq!(negotiate(Some(BROWSER_ACCEPT), &[JPEG, PNG], OPAQUE), JPEG);
+        assert_eq!(
+            negotiate(Some(BROWSER_ACCEPT), &[AVIF, JPEG], TRANSPARENT),
+            AVIF
+        );
+        assert_eq!(negotiate(Some("*/*"), &[AVIF], OPAQUE), AVIF);
+        assert_eq!(negotiate(Some("image/png"), &[WEBP, AVIF], OPAQUE), WEBP);
+    }
+
+    #[test]
+    fn test_negotiate_quality_values() {
+        assert_eq!(
+            negotiate(Some("image/avif;q=0,image/webp,*/*"), &ALL, OPAQUE),
+            WEBP
+        );
+        assert_eq!(
+            negotiate(Some("image/avif; q=0.0, image/webp;q=0"), &ALL, OPAQUE),
+            JPEG
+        );
+        assert_eq!(
+            negotiate(Some("image/avif;q=0.5,*/*;q=0.1"), &ALL, OPAQUE),
+            AVIF
+        );
+        assert_eq!(negotiate(Some("image/avif;q=x"), &ALL, OPAQUE), JPEG);
+        assert_eq!(
+            negotiate(Some("image/jpeg;q=0,*/*"), &[AVIF, JPEG, PNG], OPAQUE),
+            PNG
+        );
+    }
+
+    #[test]
+    fn test_requested_format() {
+        assert_eq!(
+            RequestedFormat::from_extension("auto"),
+            Some(RequestedFormat::Auto)
+        );
+        assert_eq!(
+            RequestedFormat::from_extension("jpg"),
+            Some(RequestedFormat::Fixed(JPEG))
+        );
+        assert_eq!(RequestedFormat::from_extension("bmp"), None);
+        assert_eq!(
+            RequestedFormat::from_extension("jxl").is_some(),
+            image_processing::codecs::jxl::is_supported()
+        );
+    }
+}
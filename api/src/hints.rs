@@ -0,0 +1,251 @@
+//! Client Hints: the device pixel ratio, display width and data saver preference sent by
+//! browsers, used to pick the size and quality of the served image.
+
+use axum::http::{HeaderMap, HeaderName};
+use configuration::config::{ClientHintSettings, EncoderSettings};
+use image_processing::transcoder::PixelSize;
+
+pub const SEC_CH_DPR: HeaderName = HeaderName::from_static("sec-ch-dpr");
+pub const SEC_CH_WIDTH: HeaderName = HeaderName::from_static("sec-ch-width");
+pub const SEC_CH_VIEWPORT_WIDTH: HeaderName = HeaderName::from_static("sec-ch-viewport-width");
+pub const SAVE_DATA: HeaderName = HeaderName::from_static("save-data");
+pub const ACCEPT_CH: HeaderName = HeaderName::from_static("accept-ch");
+pub const CONTENT_DPR: HeaderName = HeaderName::from_static("content-dpr");
+
+/// Hints requested from browsers with `Accept-CH`, `Save-Data` is sent without asking.
+pub const REQUESTED_HINTS: &str = "Sec-CH-DPR, Sec-CH-Width, Sec-CH-Viewport-Width";
+/// Hints changing an image requested at a fixed size.
+pub const SIZED_HINTS: [&str; 2] = ["Sec-CH-DPR", "Save-Data"];
+/// Hints changing an image requested without a size.
+pub const UNSIZED_HINTS: [&str; 4] = [
+    "Sec-CH-DPR",
+    "Sec-CH-Width",
+    "Sec-CH-Viewport-Width",
+    "Save-Data",
+];
+
+/// Highest pixel ratio honored, phones top out at 4.
+const MAX_DPR: f32 = 4.0;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ClientHints {
+    pub dpr: Option<f32>,
+    /// Display width of the image in physical pixels.
+    pub width: Option<u32>,
+    /// Viewport width in CSS pixels.
+    pub viewport_width: Option<u32>,
+    pub save_data: bool,
+}
+
+/// Size to render and the resulting `Content-DPR`, physical pixels per CSS pixel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HintedSize {
+    pub size: PixelSize,
+    pub content_dpr: f32,
+}
+
+impl ClientHints {
+    /// Hints of a request, none when they are disabled.
+    pub fn from_request(headers: &HeaderMap, config: &ClientHintSettings) -> Self {
+        if !config.enabled {
+            return ClientHints::default();
+        }
+
+        Self::from_headers(headers)
+    }
+
+    /// Tells if the request hints at the width the image is displayed at.
+    pub fn has_width(&self) -> bool {
+        self.width.is_some() || self.viewport_width.is_some()
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &HeaderName| headers.get(name).and_then(|v| v.to_str().ok());
+
+        ClientHints {
+            dpr: header(&SEC_CH_DPR)
+                .and_then(|v| v.trim().parse::<f32>().ok())
+                .filter(|d| d.is_finite() && *d > 0.0)
+                .map(|d| d.min(MAX_DPR)),
+            width: header(&SEC_CH_WIDTH)
+                .and_then(|v| v.trim().parse().ok())
+                .filter(|w| *w > 0),
+            viewport_width: header(&SEC_CH_VIEWPORT_WIDTH)
+                .and_then(|v| v.trim().parse().ok())
+                .filter(|w| *w > 0),
+            save_data: header(&SAVE_DATA).is_some_and(|v| v.trim().eq_ignore_ascii_case("on")),
+        }
+    }
+
+    /// Scales `size`, in CSS pixels, by the pixel ratio. `None` without a DPR hint, the size in
+    /// the URL is then served as is.
+    pub fn scale(&self, size: &PixelSize, widths: &[u32]) -> Option<HintedSize> {
+        let dpr = self.dpr?;
+        let (width, height) = (*size.width(), *size.height());
+
+        let target = snap((width as f32 * dpr).round() as u32, widths);
+
+        Some(HintedSize {
+            size: PixelSize::new(target, proportional(height, target, width)),
+            content_dpr: target as f32 / width as f32,
+        })
+    }
+
+    /// Size for an image of `width` x `height` requested without a size: the hinted display
+    /// width, or the viewport, when it is smaller than the image.
+    pub fn fit(&self, width: u32, height: u32, widths: &[u32]) -> Option<HintedSize> {
+        let dpr = self.dpr.unwrap_or(1.0);
+        let display = self
+            .width
+            .or_else(|| self.viewport_width.map(|w| (w as f32 * dpr).round() as u32))?;
+
+        let target = snap(display, widths);
+
+        if target >= width {
+            return None;
+        }
+
+        Some(HintedSize {
+            size: PixelSize::new(target, proportional(height, target, width)),
+            content_dpr: target as f32 * dpr / display as f32,
+        })
+    }
+
+    /// Lowers the quality of `settings` when the client saves data and the request doesn't pick
+    /// a quality itself.
+    pub fn apply(
+        &self,
+        mut settings: EncoderSettings,
+        requested_quality: Option<u8>,
+        config: &ClientHintSettings,
+    ) -> EncoderSettings {
+        if self.save_data && requested_quality.is_none() {
+            let quality = settings.quality.map_or(config.save_data_quality, |q| {
+                q.min(config.save_data_quality)
+            });
+
+            settings.quality = Some(quality);
+        }
+
+        settings
+    }
+}
+
+/// Rounds `width` up to the next width of the ladder, so nearby sizes share a variant. Widths
+/// below the ladder are kept, icons would grow a lot, and widths past it are capped.
+pub fn snap(width: u32, widths: &[u32]) -> u32 {
+    if widths.iter().all(|w| width < *w) {
+        return width;
+    }
+
+    match widths.iter().copied().filter(|w| *w >= width).min() {
+        Some(snapped) => snapped,
+        None => widths.iter().copied().max().unwrap_or(width),
+    }
+}
+
+fn proportional(height: u32, width: u32, of_width: u32) -> u32 {
+    ((height as f32 * width as f32 / of_width as f32).round() as u32).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    const LADDER: [u32; 4] = [320, 640, 1024, 1920];
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(SEC_CH_DPR, HeaderValue::from_static("2.5"));
+        headers.insert(SEC_CH_WIDTH, HeaderValue::from_static("nope"));
+        headers.insert(SAVE_DATA, HeaderValue::from_static("on"));
+
+        let hints = ClientHints::from_headers(&headers);
+        assert_eq!(hints.dpr, Some(2.5));
+        assert_eq!(hints.width, None);
+        assert!(hints.save_data);
+
+        headers.insert(SEC_CH_DPR, HeaderValue::from_static("100"));
+        assert_eq!(ClientHints::from_headers(&headers).dpr, Some(MAX_DPR));
+        assert_eq!(
+            ClientHints::from_headers(&HeaderMap::new()),
+            ClientHints::default()
+        );
+    }
+
+    #[test]
+    fn test_scale() {
+        let hints = ClientHints {
+            dpr: Some(2.0),
+            ..Default::default()
+        };
+
+        let scaled = hints.scale(&PixelSize::new(300, 200), &LADDER).unwrap();
+        assert_eq!(scaled.size, PixelSize::new(640, 427));
+        assert!((scaled.content_dpr - 2.1333).abs() < 0.001);
+
+        // past the ladder
+        let scaled = hints.scale(&PixelSize::new(1500, 1000), &LADDER).unwrap();
+        assert_eq!(scaled.size, PixelSize::new(1920, 1280));
+
+        // below the ladder
+        let scaled = hints.scale(&PixelSize::new(30, 30), &LADDER).unwrap();
+        assert_eq!(scaled.size, PixelSize::new(60, 60));
+
+        // without a ladder the size is only scaled
+        let scaled = hints.scale(&PixelSize::new(300, 200), &[]).unwrap();
+        assert_eq!(scaled.size, PixelSize::new(600, 400));
+
+        assert_eq!(
+            ClientHints::default().scale(&PixelSize::new(300, 200), &LADDER),
+            None
+        );
+    }
+
+    #[test]
+    fn test_fit() {
+        let width = ClientHints {
+            dpr: Some(2.0),
+            width: Some(600),
+            ..Default::default()
+        };
+        assert_eq!(
+            width.fit(4000, 2000, &LADDER).map(|h| h.size),
+            Some(PixelSize::new(640, 320))
+        );
+
+        let viewport = ClientHints {
+            viewport_width: Some(400),
+            ..Default::default()
+        };
+        assert_eq!(
+            viewport.fit(4000, 2000, &LADDER).map(|h| h.size),
+            Some(PixelSize::new(640, 320))
+        );
+
+        // never upscaled
+        assert_eq!(width.fit(500, 500, &LADDER), None);
+        assert_eq!(ClientHints::default().fit(4000, 2000, &LADDER), None);
+    }
+
+    #[test]
+    fn test_save_data() {
+        let config = ClientHintSettings::default();
+        let hints = ClientHints {
+            save_data: true,
+            ..Default::default()
+        };
+
+        let settings = hints.apply(EncoderSettings::default(), None, &config);
+        assert_eq!(settings.quality, Some(config.save_data_quality));
+
+        let settings = hints.apply(EncoderSettings::default(), Some(90), &config);
+        assert_eq!(settings.quality, None);
+
+        let settings = ClientHints::default().apply(EncoderSettings::default(), None, &config);
+        assert_eq!(settings, EncoderSettings::default());
+    }
+}
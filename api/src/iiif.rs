@@ -0,0 +1,814 @@
+//! IIIF Image API 3.0 service at compliance level 2: regions, sizes, rotations by 90 degrees,
+//! mirroring and the gray and bitonal qualities. Requests matching a tile listed in `info.json`
+//! are served from the tile cache, without a signature as viewers build their URLs, other
+//! requests must be signed when signing is enabled.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{
+        header::{ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_TYPE},
+        HeaderMap, StatusCode, Uri,
+    },
+    response::{IntoResponse, Redirect},
+    Json,
+};
+use configuration::{
+    config::{ImageSettings, UpscalePolicy},
+    ImageEncoding,
+};
+use image_processing::{
+    image_format,
+    tiles::{Pyramid, TILE_SIZE},
+    transcoder::{Encoder, Operations, PixelSize, Position, Rotation, Transcoder},
+};
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::{
+    base_url,
+    limits::{self, SizeOrigin},
+    signatures,
+    tiles::{default_tile_format, master, pyramid_of, tile_bytes},
+    APIState,
+};
+
+const CONTEXT: &str = "http://iiif.io/api/image/3/context.json";
+const PROTOCOL: &str = "http://iiif.io/api/image";
+/// Features required by level 2, listed as extras when the formats only allow a lower level.
+const LEVEL2_FEATURES: [&str; 12] = [
+    "baseUriRedirect",
+    "cors",
+    "jsonldMediaType",
+    "regionByPct",
+    "regionByPx",
+    "regionSquare",
+    "rotationBy90s",
+    "sizeByConfinedWh",
+    "sizeByH",
+    "sizeByPct",
+    "sizeByW",
+    "sizeByWh",
+];
+/// Features supported beyond level 2.
+const EXTRA_FEATURES: [&str; 2] = ["mirroring", "sizeUpscaling"];
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageInfo {
+    #[serde(rename = "@context")]
+    context: &'static str,
+    id: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    protocol: &'static str,
+    profile: &'static str,
+    width: u32,
+    height: u32,
+    max_width: u32,
+    max_height: u32,
+    max_area: u64,
+    tiles: Vec<TileInfo>,
+    preferred_formats: Vec<&'static str>,
+    extra_formats: Vec<&'static str>,
+    extra_qualities: Vec<&'static str>,
+    extra_features: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TileInfo {
+    width: u32,
+    #[serde(rename = "scaleFactors")]
+    scale_factors: Vec<u32>,
+}
+
+/// Part of the image to extract, before scaling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Region {
+    Full,
+    Square,
+    Pixels(u32, u32, u32, u32),
+    Percent(f64, f64, f64, f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SizeKind {
+    Max,
+    Width(u32),
+    Height(u32),
+    Percent(f64),
+    Exact(u32, u32),
+    /// `!w,h`, the largest size fitting in both while keeping the aspect ratio.
+    Fit(u32, u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Size {
+    /// The `^` prefix, required to return more pixels than the region has.
+    upscale: bool,
+    kind: SizeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quality {
+    Default,
+    Color,
+    Gray,
+    Bitonal,
+}
+
+/// Pixel rectangle, `(x, y, width, height)`.
+type Rect = (u32, u32, u32, u32);
+
+#[tracing::instrument]
+pub async fn serve_info(
+    Path(image): Path<String>,
+    headers: HeaderMap,
+    State(state): State<Arc<APIState<'_>>>,
+) -> axum::response::Result<impl IntoResponse> {
+    let config = state.configuration;
+    let pyramid = pyramid_of(&master(&image, config).await?.bytes)?;
+
+    let formats: Vec<&str> = config
+        .image
+        .formats
+        .iter()
+        .map(|f| iiif_format(*f))
+        .collect();
+    let profile = profile(&formats);
+
+    let mut extra_features = EXTRA_FEATURES.to_vec();
+    if config.image.allow_upscale == UpscalePolicy::Never {
+        extra_features.retain(|f| *f != "sizeUpscaling");
+    }
+    if profile != "level2" {
+        extra_features.extend(LEVEL2_FEATURES);
+    }
+
+    let info = ImageInfo {
+        context: CONTEXT,
+        id: format!("{}/iiif/{}", base_url(&headers), image),
+        kind: "ImageService3",
+        protocol: PROTOCOL,
+        profile,
+        width: pyramid.width,
+        height: pyramid.height,
+        max_width: config.image.max_width,
+        max_height: config.image.max_height,
+        max_area: config.image.max_pixels,
+        tiles: vec![TileInfo {
+            width: TILE_SIZE,
+            scale_factors: pyramid.scale_factors(),
+        }],
+        preferred_formats: vec![iiif_format(default_tile_format(config))],
+        extra_formats: formats
+            .into_iter()
+            .filter(|f| !["jpg", "png"].contains(f))
+            .collect(),
+        extra_qualities: vec!["color", "gray", "bitonal"],
+        extra_features,
+    };
+
+    Ok((
+        [
+            (CONTENT_TYPE, "application/ld+json"),
+            (ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+        ],
+        Json(info),
+    ))
+}
+
+/// The base URI of an image redirects to its `info.json`.
+pub async fn redirect_info(Path(image): Path<String>) -> Redirect {
+    Redirect::to(&format!("/iiif/{}/info.json", image))
+}
+
+/// Image request, `{region}/{size}/{rotation}/{quality}.{format}`, applied in that order.
+#[tracing::instrument]
+pub async fn serve_image(
+    Path((image, region, size, rotation, quality)): Path<(String, String, String, String, String)>,
+    uri: Uri,
+    State(state): State<Arc<APIState<'static>>>,
+) -> axum::response::Result<impl IntoResponse> {
+    let config = state.configuration;
+
+    let Some((quality, format)) = quality.rsplit_once('.') else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let (Some(region), Some(size), Some(quality), Some(format)) = (
+        parse_region(&region),
+        parse_size(&size),
+        parse_quality(quality),
+        ImageEncoding::from_extension(format),
+    ) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let (mirror, rotation) = parse_rotation(&rotation)?;
+
+    if !config.image.formats.contains(&format) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let unchanged =
+        !mirror && rotation.is_none() && matches!(quality, Quality::Default | Quality::Color);
+
+    // only tiles are served unsigned, other requests are refused before the master is read
+    let tile_shaped = unchanged
+        && matches!(region, Region::Full | Region::Pixels(..))
+        && matches!(size.kind, SizeKind::Width(_) | SizeKind::Exact(..));
+
+    if !tile_shaped {
+        signatures::check(&uri, &config.signing)?;
+    }
+
+    let master = master(&image, config).await?;
+    let pyramid = pyramid_of(&master.bytes)?;
+
+    let Some(rect) = region.resolve(pyramid.width, pyramid.height) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let Some((width, height)) = size.resolve((rect.2, rect.3), &config.image) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let content_type = [
+        (CONTENT_TYPE, format.content_type().to_owned()),
+        (ACCESS_CONTROL_ALLOW_ORIGIN, "*".to_owned()),
+    ];
+
+    if tile_shaped {
+        if let Some((level, x, y)) = find_tile(&pyramid, rect, (width, height)) {
+            let bytes = tile_bytes(&image, level, x, y, format, config).await?;
+
+            return Ok((content_type, bytes));
+        }
+
+        signatures::check(&uri, &config.signing)?;
+    }
+
+    let mut ops = Vec::new();
+
+    if rect != (0, 0, pyramid.width, pyramid.height) {
+        ops.push(Operations::Crop(
+            Position::new(rect.0, rect.1),
+            PixelSize::new(rect.2, rect.3),
+        ));
+    }
+
+    if (width, height) != (rect.2, rect.3) {
+        let size = PixelSize::new(width, height);
+
+        // tiles were served above, other sizes are asked for and must be allowed
+        if let Err(e) = limits::allowed(size, SizeOrigin::Requested, &config.image.sizes) {
+            warn!("Refused IIIF image of {:?}: {}", image, e);
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+
+        ops.push(Operations::Resize(size));
+    }
+
+    if mirror {
+        ops.push(Operations::Mirror);
+    }
+
+    if let Some(rotation) = rotation {
+        ops.push(Operations::Rotate(rotation));
+    }
+
+    match quality {
+        Quality::Gray => ops.push(Operations::Grayscale),
+        Quality::Bitonal => ops.push(Operations::Bitonal),
+        Quality::Default | Quality::Color => {}
+    }
+
+    let encoded = tokio::task::spawn_blocking(move || {
+        Transcoder.encode(
+            &master.bytes,
+            master.extension,
+            image_format(&format),
+            Some(ops),
+            &config.image.encoder,
+        )
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.into()));
+
+    let transcoded = encoded.map_err(|e| {
+        error!("Failed to render IIIF image of {:?}: {}", image, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((content_type, transcoded.bytes))
+}
+
+/// Highest compliance level the allowed formats reach, level 1 needs JPEG and level 2 PNG too.
+fn profile(formats: &[&str]) -> &'static str {
+    match (formats.contains(&"jpg"), formats.contains(&"png")) {
+        (true, true) => "level2",
+        (true, false) => "level1",
+        _ => "level0",
+    }
+}
+
+/// IIIF name of a format, which uses `jpg` and not `jpeg`.
+fn iiif_format(format: ImageEncoding) -> &'static str {
+    match format {
+        ImageEncoding::AVIF => "avif",
+        ImageEncoding::JPEG => "jpg",
+        ImageEncoding::PNG => "png",
+        ImageEncoding::WEBP => "webp",
+        ImageEncoding::GIF => "gif",
+        ImageEncoding::JXL => "jxl",
+    }
+}
+
+/// Decimal number as IIIF writes them: digits with an optional fraction, no sign or exponent.
+fn parse_number(value: &str) -> Option<f64> {
+    let valid = !value.is_empty()
+        && value.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && value.chars().filter(|c| *c == '.').count() <= 1;
+
+    valid.then(|| value.parse().ok()).flatten()
+}
+
+fn parse_region(region: &str) -> Option<Region> {
+    match region {
+        "full" => return Some(Region::Full),
+        "square" => return Some(Region::Square),
+        _ => {}
+    }
+
+    if let Some(values) = region.strip_prefix("pct:") {
+        let values: Vec<f64> = values.split(',').map(parse_number).collect::<Option<_>>()?;
+
+        return match values[..] {
+            [x, y, w, h] => Some(Region::Percent(x, y, w, h)),
+            _ => None,
+        };
+    }
+
+    let values: Vec<u32> = region
+        .split(',')
+        .map(|v| v.parse().ok())
+        .collect::<Option<_>>()?;
+
+    match values[..] {
+        [x, y, w, h] => Some(Region::Pixels(x, y, w, h)),
+        _ => None,
+    }
+}
+
+impl Region {
+    /// Rectangle of an image of `width` x `height`, clipped to its bounds. `None` when it is
+    /// empty or entirely outside.
+    fn resolve(&self, width: u32, height: u32) -> Option<Rect> {
+        let percent = |value: f64, of: u32| (value * of as f64 / 100.0).round() as u32;
+
+        let (x, y, w, h) = match *self {
+            Region::Full => (0, 0, width, height),
+            Region::Square => {
+                let side = width.min(height);
+                ((width - side) / 2, (height - side) / 2, side, side)
+            }
+            Region::Pixels(x, y, w, h) => (x, y, w, h),
+            Region::Percent(x, y, w, h) => (
+                percent(x, width),
+                percent(y, height),
+                percent(w, width),
+                percent(h, height),
+            ),
+        };
+
+        if w == 0 || h == 0 || x >= width || y >= height {
+            return None;
+        }
+
+        Some((x, y, w.min(width - x), h.min(height - y)))
+    }
+}
+
+fn parse_size(size: &str) -> Option<Size> {
+    let (upscale, size) = match size.strip_prefix('^') {
+        Some(size) => (true, size),
+        None => (false, size),
+    };
+
+    let kind = if size == "max" {
+        SizeKind::Max
+    } else if let Some(percent) = size.strip_prefix("pct:") {
+        SizeKind::Percent(parse_number(percent)?)
+    } else if let Some(size) = size.strip_prefix('!') {
+        let (w, h) = size.split_once(',')?;
+        SizeKind::Fit(w.parse().ok()?, h.parse().ok()?)
+    } else {
+        match size.split_once(',')? {
+            ("", h) => SizeKind::Height(h.parse().ok()?),
+            (w, "") => SizeKind::Width(w.parse().ok()?),
+            (w, h) => SizeKind::Exact(w.parse().ok()?, h.parse().ok()?),
+        }
+    };
+
+    Some(Size { upscale, kind })
+}
+
+impl Size {
+    /// Output size for a region of `width` x `height`. `None` when it is empty, past the size
+    /// limits of `config`, or larger than the region without `^` or when `config` doesn't allow
+    /// upscaling. `max` is the region scaled down to the limits, `^max` the largest size within
+    /// them.
+    fn resolve(&self, (width, height): (u32, u32), config: &ImageSettings) -> Option<(u32, u32)> {
+        let (rw, rh) = (width as f64, height as f64);
+
+        let (w, h) = match self.kind {
+            SizeKind::Max if self.upscale => {
+                let scale = (config.max_width as f64 / rw)
+                    .min(config.max_height as f64 / rh)
+                    .min((config.max_pixels as f64 / (rw * rh)).sqrt());
+                ((rw * scale).floor(), (rh * scale).floor())
+            }
+            SizeKind::Max => {
+                let size = limits::fit(PixelSize::new(width, height), config);
+                (*size.width() as f64, *size.height() as f64)
+            }
+            SizeKind::Width(w) => (w as f64, (rh * w as f64 / rw).round()),
+            SizeKind::Height(h) => ((rw * h as f64 / rh).round(), h as f64),
+            SizeKind::Percent(p) => ((rw * p / 100.0).round(), (rh * p / 100.0).round()),
+            SizeKind::Exact(w, h) => (w as f64, h as f64),
+            SizeKind::Fit(w, h) => {
+                let mut scale = (w as f64 / rw).min(h as f64 / rh);
+                if !self.upscale {
+                    scale = scale.min(1.0);
+                }
+
+                (
+                    (rw * scale).round().min(w as f64),
+                    (rh * scale).round().min(h as f64),
+                )
+            }
+        };
+
+        let (w, h) = (w as u32, h as u32);
+
+        limits::check(&PixelSize::new(w, h), config).ok()?;
+
+        if w <= width && h <= height {
+            return Some((w, h));
+        }
+
+        if !self.upscale {
+            return None;
+        }
+
+        // `^` asks for upscaling, the configured policy still applies
+        let size =
+            limits::upscale(PixelSize::new(w, h), (width, height), config.allow_upscale).ok()?;
+
+        Some((*size.width(), *size.height()))
+    }
+}
+
+/// Parses `[!]degrees`. Only multiples of 90 are implemented, other angles are a 501.
+fn parse_rotation(rotation: &str) -> Result<(bool, Option<Rotation>), StatusCode> {
+    let (mirror, degrees) = match rotation.strip_prefix('!') {
+        Some(degrees) => (true, degrees),
+        None => (false, rotation),
+    };
+
+    let Some(degrees) = parse_number(degrees).filter(|d| *d <= 360.0) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+
+    if degrees % 90.0 != 0.0 {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+
+    Ok((mirror, Rotation::from_degrees(degrees as u32)))
+}
+
+fn parse_quality(quality: &str) -> Option<Quality> {
+    match quality {
+        "default" => Some(Quality::Default),
+        "color" => Some(Quality::Color),
+        "gray" => Some(Quality::Gray),
+        "bitonal" => Some(Quality::Bitonal),
+        _ => None,
+    }
+}
+
+/// Level and position of the tile matching a region and size, as viewers compute them from the
+/// tile width and scale factors.
+fn find_tile(pyramid: &Pyramid, region: Rect, size: (u32, u32)) -> Option<(u32, u32, u32)> {
+    let (left, top, width, height) = region;
+
+    pyramid.scale_factors().into_iter().find_map(|scale| {
+        let level = pyramid.max_level() - scale.trailing_zeros();
+        let span = TILE_SIZE * scale;
+
+        if left % span != 0 || top % span != 0 {
+            return None;
+        }
+
+        let (x, y) = (left / span, top / span);
+        let rect = pyramid.tile_rect(level, x, y)?;
+
+        let matches = (width.div_ceil(scale), height.div_ceil(scale)) == (rect.width, rect.height)
+            && size == (rect.width, rect.height);
+
+        matches.then_some((level, x, y))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use configuration::config::{Settings, SigningKey, SizeMode};
+
+    use super::*;
+    use crate::testing::{self, resource};
+
+    fn resolve(region: &str, size: &str, image: (u32, u32)) -> Option<(Rect, (u32, u32))> {
+        resolve_with(region, size, image, &ImageSettings::default())
+    }
+
+    fn resolve_with(
+        region: &str,
+        size: &str,
+        (width, height): (u32, u32),
+        config: &ImageSettings,
+    ) -> Option<(Rect, (u32, u32))> {
+        let rect = parse_region(region)?.resolve(width, height)?;
+        let size = parse_size(size)?.resolve((rect.2, rect.3), config)?;
+
+        Some((rect, size))
+    }
+
+    #[test]
+    fn test_regions() {
+        let image = (600, 300);
+
+        assert_eq!(
+            resolve("full", "max", image),
+            Some(((0, 0, 600, 300), (600, 300)))
+        );
+        assert_eq!(
+            resolve("square", "max", image),
+            Some(((150, 0, 300, 300), (300, 300)))
+        );
+        assert_eq!(
+            resolve("pct:50,0,50,100", "max", image),
+            Some(((300, 0, 300, 300), (300, 300)))
+        );
+
+        // clipped to the image
+        assert_eq!(
+            resolve("500,200,500,500", "max", image),
+            Some(((500, 200, 100, 100), (100, 100)))
+        );
+
+        assert_eq!(resolve("600,0,10,10", "max", image), None);
+        assert_eq!(resolve("0,0,0,10", "max", image), None);
+        assert_eq!(parse_region("0,0,10"), None);
+        assert_eq!(parse_region("pct:-1,0,10,10"), None);
+    }
+
+    #[test]
+    fn test_sizes() {
+        let image = (600, 300);
+
+        assert_eq!(
+            resolve("full", "300,", image).map(|r| r.1),
+            Some((300, 150))
+        );
+        assert_eq!(
+            resolve("full", ",100", image).map(|r| r.1),
+            Some((200, 100))
+        );
+        assert_eq!(
+            resolve("full", "pct:25", image).map(|r| r.1),
+            Some((150, 75))
+        );
+        assert_eq!(
+            resolve("full", "100,100", image).map(|r| r.1),
+            Some((100, 100))
+        );
+        assert_eq!(
+            resolve("full", "!300,300", image).map(|r| r.1),
+            Some((300, 150))
+        );
+        assert_eq!(
+            resolve("full", "!3000,3000", image).map(|r| r.1),
+            Some((600, 300))
+        );
+
+        // upscaling needs ^
+        assert_eq!(resolve("full", "1200,", image), None);
+        assert_eq!(resolve("full", "pct:200", image), None);
+        assert_eq!(
+            resolve("full", "^1200,", image).map(|r| r.1),
+            Some((1200, 600))
+        );
+        assert_eq!(
+            resolve("full", "^!3000,3000", image).map(|r| r.1),
+            Some((3000, 1500))
+        );
+        // as large as the limits allow, 8192 pixels wide by default
+        assert_eq!(
+            resolve("full", "^max", image).map(|r| r.1),
+            Some((8192, 4096))
+        );
+
+        assert_eq!(resolve("full", "0,", image), None);
+        assert_eq!(resolve("full", "^100000,", image), None);
+
+        // the limits apply to every size, `max` is scaled down to them
+        let huge = (20000, 10000);
+        assert_eq!(
+            resolve("full", "max", huge).map(|r| r.1),
+            Some((8192, 4096))
+        );
+        assert_eq!(resolve("full", "10000,", huge), None);
+        assert_eq!(resolve("full", "pct:50", huge), None);
+        assert_eq!(
+            resolve("full", "pct:25", huge).map(|r| r.1),
+            Some((5000, 2500))
+        );
+        assert_eq!(resolve("full", "!9000,9000", huge).map(|r| r.1), None);
+        assert_eq!(parse_size("full"), None);
+        assert_eq!(parse_size("10"), None);
+    }
+
+    #[test]
+    fn test_upscale_policy() {
+        let image = (600, 300);
+        let mut config = ImageSettings {
+            max_pixels: 1_000_000,
+            ..ImageSettings::default()
+        };
+
+        let (w, h) = resolve_with("full", "^max", image, &config).unwrap().1;
+        assert!(w as u64 * h as u64 <= 1_000_000);
+        assert_eq!((w, h), (1414, 707));
+
+        config.allow_upscale = UpscalePolicy::Never;
+        assert_eq!(resolve_with("full", "^max", image, &config), None);
+        assert_eq!(resolve_with("full", "^1200,", image, &config), None);
+        assert_eq!(
+            resolve_with("full", "^300,", image, &config).map(|r| r.1),
+            Some((300, 150))
+        );
+
+        config.allow_upscale = UpscalePolicy::Clamp;
+        assert_eq!(
+            resolve_with("full", "^1200,", image, &config).map(|r| r.1),
+            Some((600, 300))
+        );
+        assert_eq!(
+            resolve_with("full", "^max", image, &config).map(|r| r.1),
+            Some((600, 300))
+        );
+    }
+
+    #[test]
+    fn test_rotation_and_quality() {
+        assert_eq!(parse_rotation("0"), Ok((false, None)));
+        assert_eq!(parse_rotation("360"), Ok((false, None)));
+        assert_eq!(parse_rotation("!90"), Ok((true, Some(Rotation::Quarter))));
+        assert_eq!(
+            parse_rotation("270.0"),
+            Ok((false, Some(Rotation::ThreeQuarters)))
+        );
+        assert_eq!(parse_rotation("22.5"), Err(StatusCode::NOT_IMPLEMENTED));
+        assert_eq!(parse_rotation("-90"), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(parse_rotation("450"), Err(StatusCode::BAD_REQUEST));
+
+        assert_eq!(parse_quality("gray"), Some(Quality::Gray));
+        assert_eq!(parse_quality("grey"), None);
+
+        assert_eq!(profile(&["avif", "jpg", "png"]), "level2");
+        assert_eq!(profile(&["jpg"]), "level1");
+        assert_eq!(profile(&["avif"]), "level0");
+    }
+
+    fn state(test: &str, configure: impl FnOnce(&mut Settings)) -> Arc<APIState<'static>> {
+        let mut settings = Settings::default();
+        configure(&mut settings);
+
+        let state = testing::state("iiif", test, settings);
+        let output_path = &state.configuration.image.output_path;
+        fs::write(output_path.join("fox.jpg"), resource("100x100.jpg")).unwrap();
+
+        state
+    }
+
+    async fn get(state: &Arc<APIState<'static>>, url: &str) -> StatusCode {
+        let uri: Uri = url.parse().unwrap();
+        let mut segments = uri.path().split('/').skip(2).map(str::to_owned);
+        let mut segment = || segments.next().unwrap();
+        let path = (segment(), segment(), segment(), segment(), segment());
+
+        serve_image(Path(path), uri, State(state.clone()))
+            .await
+            .into_response()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_sizes_allowed() {
+        let state = state("allowed", |s| {
+            s.image.sizes.mode = SizeMode::Allowed;
+            s.image.sizes.allowed = vec![[50, 50]];
+        });
+
+        assert_eq!(
+            get(&state, "/iiif/fox/full/max/0/default.jpg").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get(&state, "/iiif/fox/full/50,/0/gray.jpg").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get(&state, "/iiif/fox/full/60,/0/default.jpg").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            get(&state, "/iiif/fox/full/25,/0/gray.jpg").await,
+            StatusCode::BAD_REQUEST
+        );
+        // a tile of the pyramid, whatever its size
+        assert_eq!(
+            get(&state, "/iiif/fox/full/25,/0/default.jpg").await,
+            StatusCode::OK
+        );
+
+        testing::clean(&state);
+    }
+
+    #[tokio::test]
+    async fn test_tiles_served_unsigned() {
+        let state = state("signed", |s| {
+            s.signing.enabled = true;
+            s.signing.keys = vec![SigningKey {
+                id: "current".to_owned(),
+                secret: "secret".to_owned(),
+            }];
+        });
+
+        // viewers build tile URLs from info.json, they can't sign them
+        assert_eq!(
+            get(&state, "/iiif/fox/0,0,100,100/100,/0/default.jpg").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get(&state, "/iiif/fox/full/25,/0/default.jpg").await,
+            StatusCode::OK
+        );
+
+        let url = "/iiif/fox/full/60,/0/gray.jpg";
+        assert_eq!(get(&state, url).await, StatusCode::FORBIDDEN);
+
+        // shaped like a tile, but not one of the tiles of the image
+        assert_eq!(
+            get(&state, "/iiif/fox/full/60,/0/default.jpg").await,
+            StatusCode::FORBIDDEN
+        );
+
+        // refused before the master is read, it would be a 404 otherwise
+        assert_eq!(
+            get(&state, "/iiif/missing/full/60,/90/default.jpg").await,
+            StatusCode::FORBIDDEN
+        );
+        let signed = signatures::sign(url, &state.configuration.signing, None);
+        assert_eq!(get(&state, &signed).await, StatusCode::OK);
+
+        testing::clean(&state);
+    }
+
+    #[test]
+    fn test_find_tile() {
+        let pyramid = Pyramid::new(600, 300);
+        let tile = |region, size| {
+            let (rect, size) = resolve(region, size, (600, 300))?;
+            find_tile(&pyramid, rect, size)
+        };
+
+        // full resolution tiles
+        assert_eq!(tile("0,0,256,256", "256,"), Some((10, 0, 0)));
+        assert_eq!(tile("512,256,88,44", "88,"), Some((10, 2, 1)));
+        assert_eq!(tile("512,256,88,44", "88,44"), Some((10, 2, 1)));
+
+        // second level, one tile covers 512 pixels
+        assert_eq!(tile("512,0,88,300", "44,"), Some((9, 1, 0)));
+
+        // whole image in a single tile
+        assert_eq!(tile("full", "150,"), Some((8, 0, 0)));
+
+        assert_eq!(tile("10,0,256,256", "256,"), None);
+        assert_eq!(tile("0,0,256,256", "100,"), None);
+    }
+}
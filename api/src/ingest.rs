@@ -0,0 +1,534 @@
+//! Ingestion of new masters, shared by the file watcher and uploads: validation, conversion to
+//! the storage format, duplicate detection, storage and indexing.
+
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use configuration::config::{DuplicateAction, FrameSelection};
+use image_processing::{
+    alpha, animation, color, detect_encoding, dimensions, hash, image_format,
+    limits::LimitError,
+    svg,
+    transcoder::{input_format, Encoder, Operations, Transcoder},
+    ImageFormat, OutputFormat,
+};
+use storage::{
+    disk::{DiskStorage, File},
+    index::IndexEntry,
+};
+use tracing::{info, warn};
+
+use crate::APIState;
+
+const MAX_NAME_LENGTH: usize = 128;
+
+/// Extensions a master is stored with: the storage format, or the format of animations and
+/// vectors kept as they were uploaded.
+pub const MASTER_EXTENSIONS: [&str; 7] = ["avif", "webp", "png", "jpg", "gif", "jxl", "svg"];
+
+/// A near-duplicate refused because the duplicates action is `reject`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateError {
+    pub name: String,
+    pub original: String,
+}
+
+impl fmt::Display for DuplicateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "'{}' is a near-duplicate of '{}'",
+            self.name, self.original
+        )
+    }
+}
+
+impl std::error::Error for DuplicateError {}
+
+/// A new image refused because its id is taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExistsError(pub String);
+
+impl fmt::Display for ExistsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "an image named '{}' already exists", self.0)
+    }
+}
+
+impl std::error::Error for ExistsError {}
+
+/// Refuses inputs past `max_input_bytes` before they are read or decoded.
+pub fn check_size(size: u64, state: &APIState<'_>) -> Result<(), LimitError> {
+    if size > state.configuration.image.decode.max_input_bytes {
+        return Err(LimitError::InputTooLarge(size));
+    }
+
+    Ok(())
+}
+
+/// Stores `buf` as the master of `name`, replacing any previous one, and indexes it. `extension`
+/// is only used when the format can't be detected from the content.
+#[tracing::instrument(skip(state, buf))]
+pub fn ingest(
+    state: &APIState<'_>,
+    name: &str,
+    extension: &str,
+    buf: Vec<u8>,
+) -> anyhow::Result<IndexEntry> {
+    store(state, name, extension, buf, true)
+}
+
+/// Like [`ingest`], but refuses with an [`ExistsError`] when `name` already has a master or an
+/// index entry. The check holds the index and the master is created with `create_new`, so two
+/// uploads can't both create it.
+#[tracing::instrument(skip(state, buf))]
+pub fn ingest_new(
+    state: &APIState<'_>,
+    name: &str,
+    extension: &str,
+    buf: Vec<u8>,
+) -> anyhow::Result<IndexEntry> {
+    store(state, name, extension, buf, false)
+}
+
+fn store(
+    state: &APIState<'_>,
+    name: &str,
+    extension: &str,
+    buf: Vec<u8>,
+    replace: bool,
+) -> anyhow::Result<IndexEntry> {
+    check_size(buf.len() as u64, state)?;
+
+    let transcoder = &state.transcoder;
+
+    // kept next to the master to render other pages, stored with it once the checks passed
+    let mut source_pdf = None;
+
+    // vectors are kept as vectors and rendered at the requested size when served
+    let master = if svg::is_svg(&buf) {
+        info!("'{}' is an SVG, storing a sanitized copy", name);
+
+        svg::sanitize(&buf)?
+    } else if let Some(rendered) = render_pdf(state, &buf, transcoder)? {
+        info!("'{}' is a PDF, storing its first page", name);
+        source_pdf = Some(buf);
+
+        rendered
+    } else {
+        transcode_raster(state, name, buf, extension, transcoder)?
+    };
+
+    let duplicates = &state.configuration.image.duplicates;
+
+    let (mut entry, extension) = index_entry(name, &master)?;
+    let phash = entry.phash;
+    entry.ingested_at = Some(crate::clock::now());
+
+    let output_path = &state.configuration.image.output_path;
+
+    // held until the master is stored and indexed, so the id can't be taken in between
+    let mut index = state.index()?;
+
+    if !replace && (index.get(name).is_some() || master_extension(output_path, name).is_some()) {
+        return Err(ExistsError(name.to_owned()).into());
+    }
+
+    entry.duplicate_of = index
+        .similar(phash, duplicates.threshold, hash::hamming_distance)
+        .into_iter()
+        .find(|(e, _)| e.id != name)
+        .map(|(e, _)| e.id.clone());
+
+    if let Some(original) = &entry.duplicate_of {
+        if duplicates.action == DuplicateAction::Reject {
+            warn!("'{}' rejected: near-duplicate of '{}'", name, original);
+            return Err(DuplicateError {
+                name: name.to_owned(),
+                original: original.clone(),
+            }
+            .into());
+        }
+
+        warn!("'{}' flagged as near-duplicate of '{}'", name, original);
+    }
+
+    let storage = DiskStorage::from_path(output_path)?;
+
+    let source = File::new(name, crate::SOURCE_PDF_EXTENSION);
+
+    let new_path = if replace {
+        // the previous master may have been stored in another format
+        for previous in MASTER_EXTENSIONS.iter().filter(|e| **e != extension) {
+            delete_missing_ok(&storage, File::new(name, previous))?;
+        }
+
+        if let Some(document) = &source_pdf {
+            storage.add_new_file(source, document)?;
+        }
+
+        storage.add_new_file(File::new(name, extension), &master)?
+    } else {
+        // a source left without its master takes the id as well
+        if let Some(document) = &source_pdf {
+            create_new(&storage, source, document, name)?;
+        }
+
+        match create_new(&storage, File::new(name, extension), &master, name) {
+            Ok(path) => path,
+            Err(e) => {
+                if source_pdf.is_some() {
+                    delete_missing_ok(&storage, File::new(name, crate::SOURCE_PDF_EXTENSION))?;
+                }
+
+                return Err(e);
+            }
+        }
+    };
+
+    index.insert(entry.clone())?;
+    drop(index);
+
+    state.qualities.forget(name);
+    clear_derivatives(output_path, name, source_pdf.is_some())?;
+
+    info!("'{}' stored at '{:?}'", name, new_path);
+
+    Ok(entry)
+}
+
+/// Creates `file` for `name`, refusing with an [`ExistsError`] when it is already there.
+fn create_new(
+    storage: &DiskStorage<'_>,
+    file: File<'_>,
+    data: &[u8],
+    name: &str,
+) -> anyhow::Result<PathBuf> {
+    match storage.create_new_file(file, data) {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(ExistsError(name.to_owned()).into()),
+        created => Ok(created?),
+    }
+}
+
+/// Indexes the masters of the output path missing from the index, stored before it was kept.
+/// They are not checked for near-duplicates and their ingest time is the time they were last
+/// modified. Returns how many masters were indexed.
+pub fn backfill(state: &APIState<'_>) -> anyhow::Result<usize> {
+    let output_path = &state.configuration.image.output_path;
+    let mut entries = Vec::new();
+
+    for file in std::fs::read_dir(output_path)? {
+        let path = file?.path();
+
+        // ids can hold dots, only the last extension is the format
+        let (Some(name), Some(extension)) = (
+            path.file_stem().and_then(|s| s.to_str()),
+            path.extension().and_then(|e| e.to_str()),
+        ) else {
+            continue;
+        };
+
+        if !path.is_file()
+            || !MASTER_EXTENSIONS.contains(&extension)
+            || state.index()?.get(name).is_some()
+        {
+            continue;
+        }
+
+        let master = std::fs::read(&path)?;
+        let mut entry = match index_entry(name, &master) {
+            Ok((entry, _)) => entry,
+            Err(e) => {
+                warn!("Failed to index the master of '{}': {}", name, e);
+                continue;
+            }
+        };
+        entry.ingested_at = path
+            .metadata()?
+            .modified()
+            .ok()
+            .and_then(crate::clock::seconds);
+
+        entries.push(entry);
+    }
+
+    // persisted once for all of them, an upload may have indexed some in the meantime
+    let mut index = state.index()?;
+    entries.retain(|entry| index.get(&entry.id).is_none());
+    let indexed = entries.len();
+    if indexed > 0 {
+        index.insert_all(entries)?;
+    }
+
+    Ok(indexed)
+}
+
+/// Index entry of `master`, stored for `name`, with the extension it is stored with. Its ingest
+/// time and duplicate are left to the caller.
+fn index_entry(name: &str, master: &[u8]) -> anyhow::Result<(IndexEntry, &'static str)> {
+    // decoded once for everything computed from the pixels
+    let decoded = image_processing::decode(master)?;
+
+    let mut entry = IndexEntry::new(name, hash::dhash(&decoded));
+    entry.dimensions = dimensions(master).ok().map(|(w, h)| [w, h]);
+    entry.dominant_color = Some(color::extract_palette(&decoded, 1).dominant.hex());
+    entry.transparent = Some(alpha::has_transparency(&decoded));
+    let extension = master_format(master)
+        .ok_or_else(|| anyhow::anyhow!("the format of the master of '{}' is unknown", name))?;
+
+    entry.format = Some(extension.to_owned());
+    entry.bytes = Some(master.len() as u64);
+
+    Ok((entry, extension))
+}
+
+/// Removes the master of `name`, everything derived from it and its index entry. Returns whether
+/// there was a master to remove.
+#[tracing::instrument(skip(state))]
+pub fn remove(state: &APIState<'_>, name: &str) -> anyhow::Result<bool> {
+    let output_path = &state.configuration.image.output_path;
+
+    let removed = match master_extension(output_path, name) {
+        Some(extension) => {
+            DiskStorage::from_path(output_path)?.delete_file(File::new(name, extension))?;
+            true
+        }
+        None => false,
+    };
+
+    let indexed = state.index()?.remove(name)?.is_some();
+    state.qualities.forget(name);
+    clear_derivatives(output_path, name, false)?;
+
+    if removed {
+        info!("'{}' removed", name);
+    }
+
+    Ok(removed || indexed)
+}
+
+/// Drops what was derived from the previous master of `name`: its cached tiles and, unless
+/// `keep_pdf`, the PDF it was rendered from. Template outputs are rendered on each request, so
+/// nothing else is kept around.
+fn clear_derivatives(output_path: &Path, name: &str, keep_pdf: bool) -> io::Result<()> {
+    crate::tiles::clear_cache(output_path, name)?;
+    crate::picture::clear_cache(output_path, name)?;
+    crate::palette::clear_cache(output_path, name)?;
+
+    if keep_pdf {
+        return Ok(());
+    }
+
+    delete_missing_ok(
+        &DiskStorage::from_path(output_path)?,
+        File::new(name, crate::SOURCE_PDF_EXTENSION),
+    )
+}
+
+fn delete_missing_ok(storage: &DiskStorage<'_>, file: File<'_>) -> io::Result<()> {
+    match storage.delete_file(file) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Extension the master of `name` is stored with, `None` when there is no master.
+pub fn master_extension(output_path: &Path, name: &str) -> Option<&'static str> {
+    MASTER_EXTENSIONS.into_iter().find(|extension| {
+        output_path
+            .join(format!("{}.{}", name, extension))
+            .is_file()
+    })
+}
+
+/// Ids end up in file names and URLs, so they are limited to ASCII letters, digits, `-` and `_`.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Ids taken from the files dropped in `input_path` can have dots and spaces, they are still safe
+/// to join to the output path as long as they have no path separator and no `..`.
+pub fn safe_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['/', '\\', '\0']) && !name.contains("..")
+}
+
+/// Tells if `name` can be managed through the API: a valid id, or a safe one of an image already in
+/// the library.
+pub fn known_name(state: &APIState<'_>, name: &str) -> anyhow::Result<bool> {
+    Ok(valid_name(name) || (safe_name(name) && state.index()?.get(name).is_some()))
+}
+
+/// Extension of the format `master` is stored in.
+fn master_format(master: &[u8]) -> Option<&'static str> {
+    if svg::is_svg(master) {
+        return Some("svg");
+    }
+
+    let encoding = detect_encoding(master)?;
+    let extension = encoding.extension().trim_start_matches('.');
+
+    MASTER_EXTENSIONS.into_iter().find(|e| *e == extension)
+}
+
+/// Converts a raster upload to the storage format.
+fn transcode_raster(
+    state: &APIState<'_>,
+    name: &str,
+    buf: Vec<u8>,
+    extension: &str,
+    transcoder: &Transcoder,
+) -> anyhow::Result<Vec<u8>> {
+    let storage_format = image_format(&state.configuration.image.storage_format);
+
+    let format = input_format(&buf, extension)?;
+    let tiff_page = state.configuration.image.tiff_page;
+
+    let ops = (format == ImageFormat::Tiff && tiff_page > 0)
+        .then(|| vec![Operations::Frame(FrameSelection::Index(tiff_page))]);
+
+    let can_animate = matches!(
+        storage_format,
+        OutputFormat::Image(f) if animation::supports_animation(f)
+    );
+
+    let master = if !can_animate && animation::is_animated(&buf)? {
+        info!(
+            "'{}' is animated, {:?} can't keep its frames so it is stored as is",
+            name, storage_format
+        );
+
+        buf
+    } else if let Some(jxl) = recompress_jpeg(state, &buf, format, storage_format)? {
+        info!("'{}' losslessly recompressed to JPEG XL", name);
+
+        jxl
+    } else {
+        transcoder.transcode(&buf, extension.to_owned(), storage_format, ops)?
+    };
+
+    Ok(master)
+}
+
+/// Master rendered from the first page of a PDF upload. The caller stores the PDF next to it so
+/// requests can render other pages or resolutions.
+#[cfg(feature = "pdf")]
+fn render_pdf(
+    state: &APIState<'_>,
+    document: &[u8],
+    transcoder: &Transcoder,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    if !image_processing::pdf::is_pdf(document) {
+        return Ok(None);
+    }
+
+    let image = &state.configuration.image;
+    let ops = vec![Operations::Page(
+        image_processing::transcoder::PageRender::new(1, image.pdf_dpi),
+    )];
+    let rendered = transcoder.transcode(
+        document,
+        crate::SOURCE_PDF_EXTENSION.to_owned(),
+        image_format(&image.storage_format),
+        Some(ops),
+    )?;
+
+    Ok(Some(rendered))
+}
+
+#[cfg(not(feature = "pdf"))]
+fn render_pdf(
+    _state: &APIState<'_>,
+    _document: &[u8],
+    _transcoder: &Transcoder,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+/// Lossless JPEG XL version of a JPEG upload, when JPEG XL is the storage format and
+/// recompression is enabled. The original JPEG can be rebuilt from it bit for bit.
+fn recompress_jpeg(
+    state: &APIState<'_>,
+    image: &[u8],
+    format: ImageFormat,
+    storage_format: OutputFormat,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    if format != ImageFormat::Jpeg
+        || storage_format != OutputFormat::JpegXl
+        || !state.configuration.image.jpeg_recompression
+    {
+        return Ok(None);
+    }
+
+    image_processing::codecs::jxl::recompress_jpeg(image).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use configuration::config::Settings;
+
+    use super::*;
+    use crate::testing::{self, resource};
+
+    #[test]
+    fn test_backfill() -> anyhow::Result<()> {
+        let state = testing::state("ingest", "backfill", Settings::default());
+        let output_path = &state.configuration.image.output_path;
+
+        fs::write(output_path.join("old photo.v1.png"), resource("10x10.png"))?;
+        fs::write(output_path.join("indexed.png"), resource("10x10.png"))?;
+        fs::write(output_path.join("old photo.v1.pdf"), b"source")?;
+        state.index()?.insert(IndexEntry::new("indexed", 0))?;
+
+        assert_eq!(backfill(&state)?, 1);
+
+        let index = state.index()?;
+        let entry = index.get("old photo.v1").expect("indexed");
+        assert_eq!(entry.dimensions, Some([20, 20]));
+        assert_eq!(entry.format.as_deref(), Some("png"));
+        assert!(entry.ingested_at.is_some());
+        assert_eq!(index.get("indexed"), Some(&IndexEntry::new("indexed", 0)));
+        drop(index);
+
+        assert_eq!(backfill(&state)?, 0);
+
+        testing::clean(&state);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_refused_pdf_keeps_source() -> anyhow::Result<()> {
+        let mut settings = Settings::default();
+        settings.image.duplicates.threshold = 64;
+        settings.image.duplicates.action = DuplicateAction::Reject;
+        let state = testing::state("ingest", "refused-pdf", settings);
+        let output_path = &state.configuration.image.output_path;
+
+        fs::write(output_path.join("fox.jpg"), resource("100x100.jpg"))?;
+        fs::write(output_path.join("fox.pdf"), b"previous source")?;
+        state.index()?.insert(IndexEntry::new("fox", 0))?;
+
+        let document = resource("904856519207-9673649909-ticket.pdf");
+
+        // the id is taken
+        assert!(ingest_new(&state, "fox", "pdf", document.clone()).is_err());
+        // every image is a near-duplicate of `fox`
+        assert!(ingest(&state, "fox-copy", "pdf", document.clone()).is_err());
+
+        assert_eq!(fs::read(output_path.join("fox.pdf"))?, b"previous source");
+        assert!(!output_path.join("fox-copy.pdf").exists());
+
+        testing::clean(&state);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//! Listing of the stored images, from the index rather than the output folder.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+use storage::index::{IndexEntry, IndexQuery};
+use tracing::error;
+
+use crate::{base_url, signatures, APIState};
+
+/// Id, URL and metadata of a stored image. Metadata recorded after the image was indexed is
+/// missing.
+#[derive(Debug, Serialize)]
+pub struct ImageMetadata {
+    pub id: String,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ingested_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+}
+
+impl ImageMetadata {
+    pub fn new(entry: IndexEntry, headers: &HeaderMap, state: &APIState<'_>) -> Self {
+        let path = signatures::sign(
+            &format!("/{}", entry.id),
+            &state.configuration.signing,
+            None,
+        );
+
+        Self {
+            url: format!("{}{}", base_url(headers), path),
+            id: entry.id,
+            format: entry.format,
+            width: entry.dimensions.map(|[w, _]| w),
+            height: entry.dimensions.map(|[_, h]| h),
+            bytes: entry.bytes,
+            ingested_at: entry.ingested_at,
+            duplicate_of: entry.duplicate_of,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageList {
+    /// Images matching the filters, on every page.
+    total: usize,
+    offset: usize,
+    limit: usize,
+    images: Vec<ImageMetadata>,
+}
+
+/// Page of the stored images, filtered with `prefix`, `format`, `min_bytes`, `max_bytes`,
+/// `min_width`, `max_width`, `min_height`, `max_height`, `ingested_after` and `ingested_before`,
+/// sorted on `sort` in `order` and paginated with `offset` and `limit`.
+#[tracing::instrument]
+pub async fn list_images(
+    Query(query): Query<IndexQuery>,
+    headers: HeaderMap,
+    State(state): State<Arc<APIState<'_>>>,
+) -> Result<Json<ImageList>, StatusCode> {
+    let (total, entries) = {
+        let index = state.index().map_err(|e| {
+            error!("Failed to read the index: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let page = index.query(&query);
+
+        let entries: Vec<IndexEntry> = page.entries.into_iter().cloned().collect();
+        (page.total, entries)
+    };
+
+    Ok(Json(ImageList {
+        total,
+        offset: query.offset,
+        limit: query.limit(),
+        images: entries
+            .into_iter()
+            .map(|e| ImageMetadata::new(e, &headers, &state))
+            .collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::Request, http::StatusCode, routing::get, Router};
+    use configuration::config::Settings;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::testing;
+
+    async fn list(app: &Router, uri: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::get(uri)
+            .header("host", "img.test")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (
+            status,
+            serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_list_images() {
+        let state = testing::state("library", "list", Settings::default());
+        for (id, bytes) in [("fox", 300), ("cat", 100), ("crow", 200)] {
+            let mut entry = IndexEntry::new(id, 0);
+            entry.format = Some("avif".to_owned());
+            entry.bytes = Some(bytes);
+            entry.dimensions = Some([64, 48]);
+            state.index().unwrap().insert(entry).unwrap();
+        }
+
+        let app = Router::new()
+            .route("/images", get(list_images))
+            .with_state(Arc::clone(&state));
+
+        let (status, body) = list(&app, "/images?prefix=c&sort=bytes&order=desc").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 2);
+        assert_eq!(body["images"][0]["id"], "crow");
+        assert_eq!(body["images"][0]["url"], "http://img.test/crow");
+        assert_eq!(body["images"][0]["width"], 64);
+        assert_eq!(body["images"][1]["id"], "cat");
+
+        let (_, body) = list(&app, "/images?limit=1&offset=1").await;
+        assert_eq!(body["total"], 3);
+        assert_eq!(body["limit"], 1);
+        assert_eq!(body["images"].as_array().unwrap().len(), 1);
+        assert_eq!(body["images"][0]["id"], "crow");
+
+        let (_, body) = list(&app, "/images?min_bytes=150&max_bytes=250").await;
+        assert_eq!(body["total"], 1);
+
+        let (status, _) = list(&app, "/images?sort=colour").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        testing::clean(&state);
+    }
+}
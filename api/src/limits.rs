@@ -0,0 +1,298 @@
+//! Output size limits, checked on the requested size so oversized requests never read or decode
+//! an image, and the sizes allowed to be served.
+
+use std::fmt;
+
+use configuration::config::{ImageSettings, SizeMode, SizeSettings, UpscalePolicy};
+use image_processing::transcoder::PixelSize;
+
+/// A requested size the configuration doesn't allow, served as a 400.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeError {
+    Empty,
+    TooLarge(PixelSize),
+    Upscale(PixelSize),
+    NotAllowed(PixelSize),
+}
+
+impl fmt::Display for SizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SizeError::Empty => write!(f, "requested size is empty"),
+            SizeError::TooLarge(s) => {
+                write!(f, "{}x{} is past the size limits", s.width(), s.height())
+            }
+            SizeError::Upscale(s) => {
+                write!(f, "{}x{} is larger than the image", s.width(), s.height())
+            }
+            SizeError::NotAllowed(s) => {
+                write!(f, "{}x{} is not an allowed size", s.width(), s.height())
+            }
+        }
+    }
+}
+
+impl std::error::Error for SizeError {}
+
+/// Refuses sizes past `max_width`, `max_height` or `max_pixels`.
+pub fn check(size: &PixelSize, config: &ImageSettings) -> Result<(), SizeError> {
+    let (width, height) = (*size.width(), *size.height());
+
+    if width == 0 || height == 0 {
+        return Err(SizeError::Empty);
+    }
+
+    if width > config.max_width
+        || height > config.max_height
+        || width as u64 * height as u64 > config.max_pixels
+    {
+        return Err(SizeError::TooLarge(*size));
+    }
+
+    Ok(())
+}
+
+/// Scales `size` down, keeping its aspect ratio, until it is within the limits. Used on sizes
+/// multiplied by a pixel ratio, the requested size itself is checked with [`check`].
+pub fn fit(size: PixelSize, config: &ImageSettings) -> PixelSize {
+    let (width, height) = (*size.width() as f64, *size.height() as f64);
+
+    let factor = (config.max_width as f64 / width)
+        .min(config.max_height as f64 / height)
+        .min((config.max_pixels as f64 / (width * height)).sqrt());
+
+    if factor >= 1.0 {
+        return size;
+    }
+
+    // floor, rounding up could land one pixel past the limits
+    PixelSize::new(
+        ((width * factor) as u32).max(1),
+        ((height * factor) as u32).max(1),
+    )
+}
+
+/// Applies the upscaling policy to `size` for an image of `source` size.
+pub fn upscale(
+    size: PixelSize,
+    source: (u32, u32),
+    policy: UpscalePolicy,
+) -> Result<PixelSize, SizeError> {
+    let (width, height) = (*size.width(), *size.height());
+
+    if width <= source.0 && height <= source.1 {
+        return Ok(size);
+    }
+
+    match policy {
+        UpscalePolicy::Allowed => Ok(size),
+        UpscalePolicy::Never => Err(SizeError::Upscale(size)),
+        UpscalePolicy::Clamp => {
+            let factor = (source.0 as f64 / width as f64).min(source.1 as f64 / height as f64);
+
+            Ok(PixelSize::new(
+                ((width as f64 * factor) as u32).max(1),
+                ((height as f64 * factor) as u32).max(1),
+            ))
+        }
+    }
+}
+
+/// Where the size of a resize comes from, which decides how the allowed sizes apply to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeOrigin {
+    /// Asked for by the request, refused unless it is allowed.
+    Requested,
+    /// Multiplied by a pixel ratio or picked from client hints, rounded to the nearest allowed
+    /// size.
+    Derived,
+    /// Fixed by the configuration, templates and tiles are always served.
+    Configured,
+}
+
+/// Applies the allowed sizes to a size about to be resized to, every resize goes through it.
+/// `None` when a derived size has no allowed size to round to, the image is then not resized.
+pub fn allowed(
+    size: PixelSize,
+    origin: SizeOrigin,
+    config: &SizeSettings,
+) -> Result<Option<PixelSize>, SizeError> {
+    match origin {
+        SizeOrigin::Configured => Ok(Some(size)),
+        SizeOrigin::Derived => Ok(nearest_allowed(&size, config)),
+        SizeOrigin::Requested => match nearest_allowed(&size, config) {
+            Some(allowed) if allowed == size => Ok(Some(size)),
+            _ => Err(SizeError::NotAllowed(size)),
+        },
+    }
+}
+
+/// Allowed size nearest to `size`, `size` itself when it is allowed. `None` when no size is,
+/// images are then only resized by templates.
+pub fn nearest_allowed(size: &PixelSize, config: &SizeSettings) -> Option<PixelSize> {
+    match config.mode {
+        SizeMode::Any => Some(*size),
+        SizeMode::Templates => None,
+        SizeMode::Allowed => {
+            let (width, height) = (*size.width(), *size.height());
+
+            let stepped = config.step.filter(|s| *s > 0).map(|step| {
+                PixelSize::new(round_to_step(width, step), round_to_step(height, step))
+            });
+
+            config
+                .allowed
+                .iter()
+                .map(|[w, h]| PixelSize::new(*w, *h))
+                .chain(stepped)
+                .min_by_key(|s| {
+                    width.abs_diff(*s.width()) as u64 + height.abs_diff(*s.height()) as u64
+                })
+        }
+    }
+}
+
+/// Nearest multiple of `step`, at least `step`.
+fn round_to_step(side: u32, step: u32) -> u32 {
+    let steps = ((side as u64 + step as u64 / 2) / step as u64).max(1);
+
+    (steps * step as u64).min(u32::MAX as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let config = ImageSettings::default();
+
+        assert_eq!(check(&PixelSize::new(1920, 1080), &config), Ok(()));
+        assert_eq!(
+            check(&PixelSize::new(50000, 50000), &config),
+            Err(SizeError::TooLarge(PixelSize::new(50000, 50000)))
+        );
+        assert!(check(&PixelSize::new(u32::MAX, 1), &config).is_err());
+        // within both sides but not the area
+        assert!(check(&PixelSize::new(8000, 8000), &config).is_err());
+        assert_eq!(
+            check(&PixelSize::new(0, 10), &config),
+            Err(SizeError::Empty)
+        );
+    }
+
+    #[test]
+    fn test_fit() {
+        let config = ImageSettings::default();
+
+        assert_eq!(
+            fit(PixelSize::new(640, 480), &config),
+            PixelSize::new(640, 480)
+        );
+        assert_eq!(
+            fit(PixelSize::new(16000, 1000), &config),
+            PixelSize::new(8192, 512)
+        );
+
+        let fitted = fit(PixelSize::new(8000, 8000), &config);
+        assert!(check(&fitted, &config).is_ok());
+    }
+
+    #[test]
+    fn test_upscale() {
+        let size = PixelSize::new(400, 200);
+        let source = (200, 200);
+
+        assert_eq!(upscale(size, source, UpscalePolicy::Allowed), Ok(size));
+        assert_eq!(
+            upscale(size, source, UpscalePolicy::Never),
+            Err(SizeError::Upscale(size))
+        );
+        assert_eq!(
+            upscale(size, source, UpscalePolicy::Clamp),
+            Ok(PixelSize::new(200, 100))
+        );
+        assert_eq!(
+            upscale(PixelSize::new(100, 100), source, UpscalePolicy::Never),
+            Ok(PixelSize::new(100, 100))
+        );
+    }
+
+    #[test]
+    fn test_nearest_allowed() {
+        let mut config = SizeSettings::default();
+        let size = PixelSize::new(330, 250);
+
+        assert_eq!(nearest_allowed(&size, &config), Some(size));
+
+        config.mode = SizeMode::Allowed;
+        config.allowed = vec![[320, 240], [640, 480]];
+        assert_eq!(
+            nearest_allowed(&size, &config),
+            Some(PixelSize::new(320, 240))
+        );
+        let allowed = PixelSize::new(640, 480);
+        assert_eq!(nearest_allowed(&allowed, &config), Some(allowed));
+
+        config.step = Some(100);
+        assert_eq!(
+            nearest_allowed(&size, &config),
+            Some(PixelSize::new(320, 240))
+        );
+        assert_eq!(
+            nearest_allowed(&PixelSize::new(410, 390), &config),
+            Some(PixelSize::new(400, 400))
+        );
+        assert_eq!(
+            nearest_allowed(&PixelSize::new(20, 20), &config),
+            Some(PixelSize::new(100, 100))
+        );
+
+        config.allowed.clear();
+        config.step = None;
+        assert_eq!(nearest_allowed(&size, &config), None);
+
+        config.mode = SizeMode::Templates;
+        config.allowed = vec![[330, 250]];
+        assert_eq!(nearest_allowed(&size, &config), None);
+    }
+
+    #[test]
+    fn test_allowed() {
+        let config = SizeSettings {
+            mode: SizeMode::Allowed,
+            allowed: vec![[320, 240], [640, 480]],
+            ..SizeSettings::default()
+        };
+        let size = PixelSize::new(330, 250);
+        let listed = PixelSize::new(320, 240);
+
+        assert_eq!(
+            allowed(size, SizeOrigin::Requested, &config),
+            Err(SizeError::NotAllowed(size))
+        );
+        assert_eq!(
+            allowed(listed, SizeOrigin::Requested, &config),
+            Ok(Some(listed))
+        );
+        assert_eq!(
+            allowed(size, SizeOrigin::Derived, &config),
+            Ok(Some(listed))
+        );
+        assert_eq!(
+            allowed(size, SizeOrigin::Configured, &config),
+            Ok(Some(size))
+        );
+
+        let templates = SizeSettings {
+            mode: SizeMode::Templates,
+            ..SizeSettings::default()
+        };
+        assert!(allowed(listed, SizeOrigin::Requested, &templates).is_err());
+        assert_eq!(allowed(size, SizeOrigin::Derived, &templates), Ok(None));
+        assert_eq!(
+            allowed(size, SizeOrigin::Configured, &templates),
+            Ok(Some(size))
+        );
+    }
+}
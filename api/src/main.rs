@@ -1,32 +1,67 @@
+mod admin;
+mod clock;
+mod fetch;
 mod file_watcher;
+mod format;
+mod hints;
+mod iiif;
+mod ingest;
+mod library;
+mod limits;
+mod options;
+mod palette;
+mod picture;
+mod qualities;
+mod signatures;
+mod tiles;
+mod transform;
+mod upload;
+
+#[cfg(test)]
+mod testing;
 
 use anyhow::anyhow;
 use axum::{
-    extract::{Path, State},
-    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
-    response::IntoResponse,
-    routing::get,
-    Router,
-};
-use configuration::{
-    config::{Settings, TemplateSettings, TemplateType},
-    ImageEncoding,
+    extract::{DefaultBodyLimit, Path, Query, RawQuery, State},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE, HOST, VARY},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, put},
+    Json, Router,
 };
+use configuration::config::{Settings, SizeMismatch, TemplateSettings, TemplateType};
 use core::panic;
 use file_watcher::ImageWatcher;
-use image_processing::transcoder::{Encoder, Operations, PixelSize};
-use image_processing::{transcoder::Transcoder, ImageFormat};
+use format::RequestedFormat;
+use hints::{ClientHints, ACCEPT_CH, CONTENT_DPR, REQUESTED_HINTS, SIZED_HINTS, UNSIZED_HINTS};
+use image_processing::hash;
+use image_processing::transcoder::{PixelSize, Transcoder};
+use limits::SizeError;
+use options::{DprError, NoPagesError, RequestOptions};
+use qualities::QualityCache;
+use serde::{Deserialize, Serialize};
 use std::{
     env,
     fs::OpenOptions,
     io::{ErrorKind, Read},
     path::PathBuf,
-    str::FromStr,
-    sync::{Arc, LazyLock},
+    sync::{Arc, LazyLock, Mutex, MutexGuard, OnceLock},
 };
+use storage::index::ImageIndex;
 use tokio::{io::AsyncReadExt, net::TcpListener};
 use tracing::{error, info, warn, Level};
 use tracing_subscriber::fmt::format::FmtSpan;
+use transform::{transform, Processed, Transform};
+
+const DOMINANT_COLOR_HEADER: &str = "x-dominant-color";
+const ENCODER_QUALITY_HEADER: &str = "x-encoder-quality";
+/// Size the image was resized to, after the pixel ratio.
+const EFFECTIVE_SIZE_HEADER: &str = "x-effective-size";
+/// Extension of the original PDF stored next to the master rendered from its first page.
+const SOURCE_PDF_EXTENSION: &str = "pdf";
 
 static CONFIGURATION: LazyLock<Settings> = LazyLock::new(|| {
     let env_config_path_result = env::var("SETTINGS_PATH");
@@ -74,19 +109,39 @@ static CONFIGURATION: LazyLock<Settings> = LazyLock::new(|| {
 pub struct APIState<'a> {
     configuration: &'a Settings,
     transcoder: Transcoder,
+    index: Mutex<ImageIndex>,
+    /// Qualities found for `max_kb` requests.
+    qualities: QualityCache,
+    /// Client fetching origins for `/fetch`, built on first use.
+    fetch_client: OnceLock<reqwest::Client>,
 }
 
 impl<'a> APIState<'a> {
-    pub fn new(configuration: &'a Settings, transcoder: Transcoder) -> Self {
+    pub fn new(configuration: &'a Settings, transcoder: Transcoder, index: ImageIndex) -> Self {
         Self {
             configuration,
             transcoder,
+            index: Mutex::new(index),
+            qualities: QualityCache::default(),
+            fetch_client: OnceLock::new(),
         }
     }
+
+    /// Locked image index, a panic while it was held is reported instead of propagated.
+    pub fn index(&self) -> anyhow::Result<MutexGuard<'_, ImageIndex>> {
+        self.index
+            .lock()
+            .map_err(|_| anyhow!("the image index lock is poisoned"))
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("sign") {
+        return signatures::sign_command(&args[1..], &CONFIGURATION.signing);
+    }
+
     tracing_subscriber::fmt()
         .with_span_events(FmtSpan::FULL)
         .with_max_level(Level::DEBUG)
@@ -94,22 +149,61 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     let config = &*CONFIGURATION;
+    image_processing::limits::init(config.image.decode);
+    check_formats(config)?;
+    check_template_dprs(config)?;
+
     let transcoder = Transcoder;
+    let index = ImageIndex::open(&config.image.output_path)?;
 
-    let state = APIState::new(config, transcoder);
+    let state = APIState::new(config, transcoder, index);
     let state_arc = Arc::new(state);
 
     info!("Watching new images at {:?}", &config.image.input_path);
     info!("Storing encoded images at {:?}", &config.image.output_path);
 
+    // masters stored before the index was kept, indexed without holding the startup
+    let backfilled = Arc::clone(&state_arc);
+    tokio::task::spawn_blocking(move || match ingest::backfill(&backfilled) {
+        Ok(0) => {}
+        Ok(count) => info!("Indexed {} masters stored before the index", count),
+        Err(e) => error!("Failed to index the stored masters: {}", e),
+    });
+
     let watcher = ImageWatcher::new(config.image.input_path.clone(), Arc::clone(&state_arc))?;
     tokio::spawn(watcher.watch());
 
-    let app = Router::new()
-        .route("/", get(|| async { "home" }))
+    // transforms, which must be signed when signing is enabled
+    let transforms = Router::new()
         .route("/:image", get(default_serve_image))
+        .route("/:image/picture", get(picture::serve_picture))
         .route("/:image/:extension", get(serve_image))
         .route("/:width/:height/:image/:extension", get(serve_resized))
+        .route("/fetch/:options/*url", get(fetch::serve_fetched))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&state_arc),
+            signatures::verify,
+        ));
+
+    if config.admin.tokens.is_empty() {
+        warn!("No admin token configured, the library can't be listed or changed");
+    }
+
+    let app = Router::new()
+        .route("/", get(|| async { "home" }))
+        .route("/:image/palette", get(palette::serve_palette))
+        .route("/:image/similar", get(serve_similar))
+        .route("/:image/tiles.dzi", get(tiles::serve_dzi))
+        .route("/:image/tiles/:level/:tile", get(tiles::serve_tile))
+        .route("/iiif/:image", get(iiif::redirect_info))
+        .route("/iiif/:image/info.json", get(iiif::serve_info))
+        // the tiles listed in info.json are served unsigned, other requests are checked
+        .route(
+            "/iiif/:image/:region/:size/:rotation/:quality",
+            get(iiif::serve_image),
+        )
+        .merge(transforms)
+        .merge(library_routes(&state_arc))
         .with_state(Arc::clone(&state_arc));
 
     let address = format!("{}:{}", config.server.host, config.server.port);
@@ -122,101 +216,398 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The library and changes to it, which need an admin token.
+fn library_routes(state: &Arc<APIState<'static>>) -> Router<Arc<APIState<'static>>> {
+    let config = state.configuration;
+    let upload_limit = config.image.decode.max_input_bytes as usize + upload::MULTIPART_OVERHEAD;
+
+    Router::new()
+        .route(
+            "/images",
+            get(library::list_images).post(upload::upload_image),
+        )
+        .route(
+            "/images/:image",
+            put(upload::replace_image).delete(admin::delete_image),
+        )
+        .layer(DefaultBodyLimit::max(upload_limit))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(state),
+            admin::authorize,
+        ))
+}
+
 pub async fn serve_resized(
     Path((width, height, image, ext)): Path<(u32, u32, String, String)>,
+    Query(options): Query<RequestOptions>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    State(state): State<Arc<APIState<'static>>>,
+) -> axum::response::Result<Response> {
+    let resize_params = PixelSize::new(width, height);
+
+    let Some(format) = RequestedFormat::from_extension(&ext) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    // before anything is read, a huge size would only fail after allocating it
+    if let Err(e) = limits::check(&resize_params, &state.configuration.image) {
+        warn!("Refused to resize {:?}: {}", image, e);
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let sizes = &state.configuration.image.sizes;
+    match limits::nearest_allowed(&resize_params, sizes) {
+        Some(allowed) if allowed == resize_params => {}
+        Some(allowed) if sizes.mismatch == SizeMismatch::Redirect => {
+            let location = format!(
+                "/{}/{}/{}/{}{}",
+                allowed.width(),
+                allowed.height(),
+                image,
+                ext,
+                query
+                    .as_ref()
+                    .map(|q| format!("?{}", q))
+                    .unwrap_or_default()
+            );
+            // the signature was for the requested size, the redirect keeps its expiry
+            let expires = query.as_deref().and_then(signing::expires);
+            let location = signatures::sign(&location, &state.configuration.signing, expires);
+
+            return Ok(Redirect::temporary(&location).into_response());
+        }
+        _ => {
+            warn!(
+                "Refused to resize {:?} to a size not allowed: {}x{}",
+                image, width, height
+            );
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+    }
+
+    let request = Transform {
+        format,
+        accept: accept(&headers).map(str::to_owned),
+        size: Some(resize_params),
+        options,
+        hints: ClientHints::from_request(&headers, &state.configuration.image.client_hints),
+    };
+
+    let encoded_image = process(image, request, Arc::clone(&state)).await;
+
+    Ok(image_response(encoded_image, format, &SIZED_HINTS, &state))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimilarParams {
+    distance: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SimilarImage {
+    id: String,
+    distance: u32,
+}
+
+#[tracing::instrument]
+pub async fn serve_similar(
+    Path(image): Path<String>,
+    Query(params): Query<SimilarParams>,
     State(state): State<Arc<APIState<'_>>>,
 ) -> axum::response::Result<impl IntoResponse> {
-    let resize_params = PixelSize::new(width, height);
+    let max_distance = params
+        .distance
+        .unwrap_or(state.configuration.image.duplicates.threshold);
 
-    let extension = match ext.as_str() {
-        "png" => ImageEncoding::PNG,
-        "jpg" | "jpeg" => ImageEncoding::JPEG,
-        "avif" => ImageEncoding::AVIF,
-        _ => return Err(StatusCode::BAD_REQUEST.into()),
+    let indexed_hash = match state.index() {
+        Ok(index) => index.get(&image).map(|e| e.phash),
+        Err(e) => {
+            error!("Failed to read the index: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        }
     };
 
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, extension.content_type().parse().unwrap());
+    let phash = match indexed_hash {
+        Some(h) => h,
+        None => {
+            // masters stored before hashing existed are not indexed yet
+            let bytes = match read_master(&image, state.configuration).await {
+                Ok(master) => master.bytes,
+                Err(e) => {
+                    error!("Failed to read image {:?}: {}", image, e);
+                    return Err(StatusCode::NOT_FOUND.into());
+                }
+            };
+
+            let hashed = tokio::task::spawn_blocking(move || hash::perceptual_hash(&bytes))
+                .await
+                .unwrap_or_else(|e| Err(e.into()));
 
-    let encoded_image_bytes =
-        process(image, extension, Some(resize_params), &state.configuration).await;
+            match hashed {
+                Ok(h) => h,
+                Err(e) => {
+                    error!("Failed to hash image {:?}: {}", image, e);
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+                }
+            }
+        }
+    };
 
-    match encoded_image_bytes {
-        Ok(b) => Ok((headers, b).into_response()),
+    let index = match state.index() {
+        Ok(index) => index,
         Err(e) => {
-            error!("Failed to encode image to {:?}: {}", ext, e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+            error!("Failed to read the index: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
         }
-    }
+    };
+
+    let similar: Vec<SimilarImage> = index
+        .similar(phash, max_distance, hash::hamming_distance)
+        .into_iter()
+        .filter(|(e, _)| e.id != image)
+        .map(|(e, distance)| SimilarImage {
+            id: e.id.clone(),
+            distance,
+        })
+        .collect();
+
+    Ok(Json(similar))
 }
+
 #[tracing::instrument]
 pub async fn default_serve_image(
     Path(image): Path<String>,
-    state: State<Arc<APIState<'_>>>,
+    options: Query<RequestOptions>,
+    headers: HeaderMap,
+    state: State<Arc<APIState<'static>>>,
 ) -> axum::response::Result<impl IntoResponse> {
-    // TODO: Choose default based on Accept header. Order: avif, jpg, png
-    serve_image(Path((image, "avif".to_string())), state).await
+    serve_image(Path((image, "auto".to_string())), options, headers, state).await
 }
 
 #[tracing::instrument]
 pub async fn serve_image(
     Path((image, ext)): Path<(String, String)>,
-    State(state): State<Arc<APIState<'_>>>,
+    Query(options): Query<RequestOptions>,
+    headers: HeaderMap,
+    State(state): State<Arc<APIState<'static>>>,
 ) -> axum::response::Result<impl IntoResponse> {
-    let extension = match ext.as_str() {
-        "png" => ImageEncoding::PNG,
-        "jpg" | "jpeg" => ImageEncoding::JPEG,
-        "avif" => ImageEncoding::AVIF,
-        _ => return Err(StatusCode::BAD_REQUEST.into()),
+    let Some(format) = RequestedFormat::from_extension(&ext) else {
+        return Err(StatusCode::BAD_REQUEST.into());
     };
 
     let allowed_formats = &state.configuration.image.formats;
 
-    if !allowed_formats.contains(&extension) {
-        // TODO: serve an image with written error
-        return Err(StatusCode::BAD_REQUEST.into());
+    if let RequestedFormat::Fixed(extension) = format {
+        if !allowed_formats.contains(&extension) {
+            // TODO: serve an image with written error
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+    }
+
+    let request = Transform {
+        format,
+        accept: accept(&headers).map(str::to_owned),
+        size: None,
+        options,
+        hints: ClientHints::from_request(&headers, &state.configuration.image.client_hints),
+    };
+
+    let encoded_image = process(image, request, Arc::clone(&state)).await;
+
+    Ok(image_response(
+        encoded_image,
+        format,
+        &UNSIZED_HINTS,
+        &state,
+    ))
+}
+
+/// Absolute URL of the server as the client reaches it, for links that can't be relative.
+fn base_url(headers: &HeaderMap) -> String {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+    format!(
+        "{}://{}",
+        header("x-forwarded-proto").unwrap_or("http"),
+        header(HOST.as_str()).unwrap_or("localhost")
+    )
+}
+
+fn accept(headers: &HeaderMap) -> Option<&str> {
+    headers.get(ACCEPT).and_then(|v| v.to_str().ok())
+}
+
+/// Refuses to start with formats this build can't encode, rather than failing each request for
+/// them.
+fn check_formats(config: &Settings) -> anyhow::Result<()> {
+    let templates = config.templates.iter().map(|t| &t.format);
+    let unsupported: Vec<_> = config
+        .image
+        .formats
+        .iter()
+        .chain(templates)
+        .chain([&config.image.storage_format])
+        .filter(|f| !image_processing::is_supported(f))
+        .collect();
+
+    if !unsupported.is_empty() {
+        return Err(anyhow!(
+            "{:?} configured but not supported by this build, JPEG XL needs the jpegxl feature",
+            unsupported
+        ));
+    }
+
+    Ok(())
+}
+
+/// Refuses to start with a template pixel ratio out of range, which would answer every request
+/// for the template with a 400.
+fn check_template_dprs(config: &Settings) -> anyhow::Result<()> {
+    for template in &config.templates {
+        if let Err(e) = RequestOptions::default().dpr(template.dpr) {
+            return Err(anyhow!("Template {:?}: {}", template.name, e));
+        }
     }
 
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, extension.content_type().parse().unwrap());
+    Ok(())
+}
+
+/// `hinted` lists the client hints that can change the response, they are added to `Vary`.
+fn image_response(
+    encoded_image: anyhow::Result<Processed>,
+    requested: RequestedFormat,
+    hinted: &[&str],
+    state: &APIState<'_>,
+) -> Response {
+    match encoded_image {
+        Ok(Processed {
+            encoding,
+            transcoded: t,
+            size,
+            content_dpr,
+            dominant_color,
+        }) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, encoding.content_type().parse().unwrap());
+
+            let mut vary = Vec::new();
+
+            if requested == RequestedFormat::Auto {
+                vary.push(ACCEPT.as_str());
+            }
+
+            if state.configuration.image.client_hints.enabled {
+                headers.insert(ACCEPT_CH, HeaderValue::from_static(REQUESTED_HINTS));
+                vary.extend(hinted);
+            }
+
+            if !vary.is_empty() {
+                headers.insert(VARY, vary.join(", ").parse().unwrap());
+            }
+
+            if let Some(dpr) = content_dpr {
+                let dpr = format!("{}", (dpr * 100.0).round() / 100.0);
+                headers.insert(CONTENT_DPR, HeaderValue::from_str(&dpr).unwrap());
+            }
+
+            if let Some(size) = size {
+                let size = format!("{}x{}", size.width(), size.height());
+                headers.insert(EFFECTIVE_SIZE_HEADER, HeaderValue::from_str(&size).unwrap());
+            }
+
+            if let Some(color) = dominant_color.and_then(|c| HeaderValue::from_str(&c).ok()) {
+                headers.insert(DOMINANT_COLOR_HEADER, color);
+            }
 
-    let encoded_image_bytes = process(image, extension, None, &state.configuration).await;
+            if let Some(quality) = t.quality {
+                headers.insert(ENCODER_QUALITY_HEADER, u16::from(quality).into());
+            }
 
-    match encoded_image_bytes {
-        Ok(b) => Ok((headers, b).into_response()),
+            (headers, t.bytes).into_response()
+        }
+        Err(e) if e.is::<SizeError>() => {
+            warn!("Refused to resize image: {}", e);
+            StatusCode::BAD_REQUEST.into_response()
+        }
+        Err(e) if e.is::<NoPagesError>() => {
+            warn!("Refused to render a page: {}", e);
+            StatusCode::BAD_REQUEST.into_response()
+        }
+        Err(e) if e.is::<DprError>() => {
+            warn!("Refused to scale image: {}", e);
+            StatusCode::BAD_REQUEST.into_response()
+        }
         Err(e) => {
-            error!("Failed to encode image to {:?}: {}", ext, e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR.into())
+            error!("Failed to encode image to {:?}: {}", requested, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
 
 async fn process(
     name: String,
-    target_format: ImageEncoding,
-    new_size: Option<PixelSize>,
-    config: &Settings,
-) -> anyhow::Result<Vec<u8>> {
-    let mut full_path = config.image.input_path.clone();
-    full_path.push(&name);
-    full_path.set_extension("avif");
-
-    let mut op = Vec::new();
-
-    if let Some(resize_param) = new_size {
-        op.push(Operations::Resize(resize_param));
-    }
+    request: Transform,
+    state: Arc<APIState<'static>>,
+) -> anyhow::Result<Processed> {
+    let config = state.configuration;
+    let template = check_templates(&name, &config.templates).ok();
+
+    let image_name = if let Some(template) = template {
+        remove_template_pattern(&name, template)
+    } else {
+        name
+    };
+
+    // the master is the first page, other pages are rendered from the PDF kept at ingest
+    let master = if request.options.renders_page() {
+        let source = format!("{}.{}", image_name, SOURCE_PDF_EXTENSION);
 
-    let template_result = check_templates(&name, &config.templates);
+        if !cfg!(feature = "pdf") || !config.image.output_path.join(source).is_file() {
+            return Err(NoPagesError(image_name).into());
+        }
 
-    if let Ok(template) = template_result {
-        let image_name = remove_template_pattern(&name, template);
-        full_path.push(image_name);
+        Master {
+            bytes: read_stored(&image_name, SOURCE_PDF_EXTENSION, config).await?,
+            extension: SOURCE_PDF_EXTENSION.to_owned(),
+        }
     } else {
-        full_path.push(&name);
+        read_master(&image_name, config).await?
+    };
+
+    transform(image_name, master, template, request, state).await
+}
+
+/// Image read for a request, with the extension of the file it came from. The extension is only
+/// used when the format can't be detected from the content.
+pub struct Master {
+    bytes: Vec<u8>,
+    extension: String,
+}
+
+/// Reads the stored master of `name` from the output path, in whichever format it was stored.
+async fn read_master(name: &str, config: &Settings) -> anyhow::Result<Master> {
+    // decoded path segments can hold `/`, they must not reach outside of the output path
+    if !ingest::safe_name(name) {
+        return Err(anyhow!("Not found"));
     }
 
-    full_path.set_extension("avif");
+    let extension = ingest::master_extension(&config.image.output_path, name)
+        .ok_or_else(|| anyhow!("Not found"))?;
+
+    Ok(Master {
+        bytes: read_stored(name, extension, config).await?,
+        extension: extension.to_owned(),
+    })
+}
+
+/// Reads the file stored for `name` with `extension` from the output path.
+async fn read_stored(name: &str, extension: &str, config: &Settings) -> anyhow::Result<Vec<u8>> {
+    // not `set_extension`, ids may contain dots
+    let full_path = config
+        .image
+        .output_path
+        .join(format!("{}.{}", name, extension));
 
     let handle = tokio::fs::OpenOptions::new()
         .read(true)
@@ -231,42 +622,7 @@ async fn process(
             match read_result {
                 Ok(s) => {
                     info!("Read {} bytes for {:?}", s, &full_path);
-
-                    let encoded_image_bytes = if let Ok(template) = template_result {
-                        let format = match template.format {
-                            ImageEncoding::AVIF => ImageFormat::Avif,
-                            ImageEncoding::JPEG => ImageFormat::Jpeg,
-                            ImageEncoding::PNG => ImageFormat::Png,
-                        };
-
-                        Transcoder.transcode(
-                            &bytes,
-                            template.format.extension().to_string(),
-                            format,
-                            Some(vec![Operations::Resize(PixelSize::new(
-                                template.size[0],
-                                template.size[1],
-                            ))]),
-                        )
-                    } else {
-                        match target_format {
-                            ImageEncoding::AVIF => Ok(bytes),
-                            ImageEncoding::JPEG => Transcoder.transcode(
-                                &bytes,
-                                "avif".to_owned(),
-                                image_processing::ImageFormat::Jpeg,
-                                None,
-                            ),
-                            ImageEncoding::PNG => Transcoder.transcode(
-                                &bytes,
-                                "avif".to_owned(),
-                                image_processing::ImageFormat::Png,
-                                None,
-                            ),
-                        }
-                    };
-
-                    Ok(encoded_image_bytes?)
+                    Ok(bytes)
                 }
                 Err(e) => {
                     tracing::error!("Failed reading Image file: {:?} : {}", &full_path, e);
@@ -286,11 +642,11 @@ fn remove_template_pattern(image: &str, template: &TemplateSettings) -> String {
     match template.location {
         TemplateType::Prefix => {
             let pattern = format!("{}_", &template.name);
-            return image.strip_prefix(&pattern).unwrap().to_string();
+            image.strip_prefix(&pattern).unwrap().to_string()
         }
         TemplateType::Suffix => {
             let pattern = format!("_{}", &template.name);
-            return image.strip_suffix(&pattern).unwrap().to_string();
+            image.strip_suffix(&pattern).unwrap().to_string()
         }
     }
 }
@@ -325,3 +681,313 @@ fn check_templates<'a>(
 
     Err(anyhow!("No templates matched"))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use axum::{body::Body, http::header::LOCATION};
+    use configuration::{
+        config::{EncoderSettings, SigningKey, SizeMode},
+        ImageEncoding,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn state(test: &str, settings: Settings) -> Arc<APIState<'static>> {
+        testing::state("main", test, settings)
+    }
+
+    async fn resize(
+        state: &Arc<APIState<'static>>,
+        width: u32,
+        height: u32,
+        query: Option<&str>,
+    ) -> Response {
+        serve_resized(
+            Path((width, height, "missing".to_owned(), "png".to_owned())),
+            Query(RequestOptions::default()),
+            RawQuery(query.map(str::to_owned)),
+            HeaderMap::new(),
+            State(state.clone()),
+        )
+        .await
+        .into_response()
+    }
+
+    #[tokio::test]
+    async fn test_giant_resize_refused_before_reading() {
+        let state = state("giant", Settings::default());
+
+        // the master doesn't exist, a 400 means it was never opened
+        assert_eq!(
+            resize(&state, 50000, 50000, None).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            resize(&state, u32::MAX, 1, None).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            resize(&state, 0, 100, None).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        assert_ne!(
+            resize(&state, 100, 100, None).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        testing::clean(&state);
+    }
+
+    #[tokio::test]
+    async fn test_sizes_not_allowed() {
+        let mut settings = Settings::default();
+        settings.image.sizes.mode = SizeMode::Allowed;
+        settings.image.sizes.allowed = vec![[320, 240]];
+        let reject = state("reject", settings);
+
+        assert_eq!(
+            resize(&reject, 321, 240, None).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_ne!(
+            resize(&reject, 320, 240, None).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let mut settings = Settings::default();
+        settings.image.sizes.mode = SizeMode::Allowed;
+        settings.image.sizes.allowed = vec![[320, 240]];
+        settings.image.sizes.mismatch = SizeMismatch::Redirect;
+        let redirect = state("redirect", settings);
+
+        let response = resize(&redirect, 300, 200, Some("quality=80")).await;
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            response.headers()[LOCATION],
+            "/320/240/missing/png?quality=80"
+        );
+
+        let mut settings = Settings::default();
+        settings.image.sizes.mode = SizeMode::Templates;
+        settings.image.sizes.mismatch = SizeMismatch::Redirect;
+        let templates = state("templates", settings);
+
+        assert_eq!(
+            resize(&templates, 320, 240, None).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        testing::clean(&reject);
+        testing::clean(&redirect);
+        testing::clean(&templates);
+    }
+
+    #[tokio::test]
+    async fn test_derived_sizes_allowed() {
+        let mut settings = Settings::default();
+        settings.image.sizes.mode = SizeMode::Allowed;
+        settings.image.sizes.allowed = vec![[4, 4], [10, 10]];
+        settings.image.client_hints.enabled = true;
+        let state = state("derived", settings);
+        let output_path = &state.configuration.image.output_path;
+        fs::copy("../resources/10x10.png", output_path.join("derived.png")).unwrap();
+
+        let serve = |options: RequestOptions, headers: HeaderMap| {
+            serve_resized(
+                Path((4, 4, "derived".to_owned(), "png".to_owned())),
+                Query(options),
+                RawQuery(None),
+                headers,
+                State(state.clone()),
+            )
+        };
+
+        // `?dpr=2` asks for 8x8, which isn't allowed, the nearest allowed size is served
+        let dpr = RequestOptions {
+            dpr: Some(2.0),
+            ..RequestOptions::default()
+        };
+        let response = serve(dpr, HeaderMap::new()).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[EFFECTIVE_SIZE_HEADER], "10x10");
+
+        // so does a hinted pixel ratio
+        let mut headers = HeaderMap::new();
+        headers.insert(hints::SEC_CH_DPR, HeaderValue::from_static("2"));
+        let response = serve(RequestOptions::default(), headers)
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[EFFECTIVE_SIZE_HEADER], "10x10");
+        assert_eq!(response.headers()[CONTENT_DPR], "2.5");
+
+        testing::clean(&state);
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_transforms_refused() {
+        let mut settings = Settings::default();
+        settings.signing.enabled = true;
+        settings.signing.keys = vec![SigningKey {
+            id: "current".to_owned(),
+            secret: "secret".to_owned(),
+        }];
+        let state = state("unsigned", settings);
+
+        let app = Router::new()
+            .route("/:width/:height/:image/:extension", get(serve_resized))
+            .route_layer(middleware::from_fn_with_state(
+                Arc::clone(&state),
+                signatures::verify,
+            ))
+            .with_state(Arc::clone(&state));
+
+        let status = |url: String| {
+            let request = axum::http::Request::get(url).body(Body::empty()).unwrap();
+            let app = app.clone();
+
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        let url = "/50000/50000/missing/png";
+        assert_eq!(status(url.to_owned()).await, StatusCode::FORBIDDEN);
+
+        // a signed request reaches the handler, which refuses the size
+        let signed = signatures::sign(url, &state.configuration.signing, None);
+        assert_eq!(status(signed.clone()).await, StatusCode::BAD_REQUEST);
+
+        let tampered = signed.replace("/50000/", "/100/");
+        assert_eq!(status(tampered).await, StatusCode::FORBIDDEN);
+
+        let expired = signatures::sign(url, &state.configuration.signing, Some(1));
+        assert_eq!(status(expired).await, StatusCode::FORBIDDEN);
+
+        testing::clean(&state);
+    }
+
+    #[tokio::test]
+    async fn test_pages_of_images_without_pdf() {
+        let state = state("pages", Settings::default());
+        let output_path = &state.configuration.image.output_path;
+        fs::copy("../resources/10x10.png", output_path.join("unpaged.png")).unwrap();
+
+        let serve = |options: RequestOptions| {
+            serve_image(
+                Path(("unpaged".to_owned(), "png".to_owned())),
+                Query(options),
+                HeaderMap::new(),
+                State(state.clone()),
+            )
+        };
+
+        let response = serve(RequestOptions::default()).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for options in [
+            RequestOptions {
+                dpi: Some(100),
+                ..RequestOptions::default()
+            },
+            RequestOptions {
+                page: Some(2),
+                ..RequestOptions::default()
+            },
+        ] {
+            let response = serve(options).await.into_response();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        testing::clean(&state);
+    }
+
+    #[tokio::test]
+    async fn test_dpr_out_of_range_refused() {
+        let state = state("dpr", Settings::default());
+        let output_path = &state.configuration.image.output_path;
+        fs::copy("../resources/10x10.png", output_path.join("scaled.png")).unwrap();
+
+        let serve = |dpr: f32| {
+            serve_image(
+                Path(("scaled".to_owned(), "png".to_owned())),
+                Query(RequestOptions {
+                    dpr: Some(dpr),
+                    ..RequestOptions::default()
+                }),
+                HeaderMap::new(),
+                State(state.clone()),
+            )
+        };
+
+        for dpr in [0.5, 4.5, 100.0, f32::NAN] {
+            let response = serve(dpr).await.into_response();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "dpr {}", dpr);
+        }
+
+        let response = serve(2.2).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        testing::clean(&state);
+    }
+
+    #[test]
+    fn test_template_dpr_checked() {
+        let mut settings = Settings {
+            templates: vec![TemplateSettings {
+                location: TemplateType::Prefix,
+                name: "retina".to_owned(),
+                size: [100, 100],
+                format: ImageEncoding::PNG,
+                encoder: EncoderSettings::default(),
+                frame: None,
+                dpr: Some(2.0),
+            }],
+            ..Settings::default()
+        };
+        assert!(check_template_dprs(&settings).is_ok());
+
+        settings.templates[0].dpr = Some(10.0);
+        assert!(check_template_dprs(&settings).is_err());
+    }
+
+    #[test]
+    fn test_unsupported_formats_refused() {
+        let mut settings = Settings::default();
+        assert!(check_formats(&settings).is_ok());
+
+        settings.image.storage_format = ImageEncoding::JXL;
+        assert_eq!(
+            check_formats(&settings).is_ok(),
+            image_processing::codecs::jxl::is_supported()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_poisoned_index() {
+        let state = state("poisoned", Settings::default());
+
+        std::thread::scope(|scope| {
+            let poisoned = scope.spawn(|| {
+                let _index = state.index.lock().unwrap();
+                std::panic!("panicked while holding the index");
+            });
+            assert!(poisoned.join().is_err());
+        });
+
+        assert!(state.index().is_err());
+
+        let response = serve_similar(
+            Path("fox".to_owned()),
+            Query(SimilarParams { distance: None }),
+            State(state.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        testing::clean(&state);
+    }
+}
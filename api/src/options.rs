@@ -0,0 +1,149 @@
+use std::fmt;
+
+use configuration::config::{BackgroundColor, EncoderSettings, FrameSelection};
+use image_processing::transcoder::{PageRender, PixelSize};
+use serde::Deserialize;
+
+const MIN_DPR: f32 = 1.0;
+const MAX_DPR: f32 = 4.0;
+/// Pixel ratios are rounded to half steps, so a handful of derivatives serve every device.
+const DPR_STEP: f32 = 0.5;
+
+/// `page` or `dpi` requested for an image that wasn't ingested from a PDF, served as a 400.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoPagesError(pub String);
+
+impl fmt::Display for NoPagesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' has no PDF to render pages from", self.0)
+    }
+}
+
+impl std::error::Error for NoPagesError {}
+
+/// Pixel ratio outside of 1 to 4, served as a 400.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DprError(pub f32);
+
+impl fmt::Display for DprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pixel ratio {} is not between {} and {}",
+            self.0, MIN_DPR, MAX_DPR
+        )
+    }
+}
+
+impl std::error::Error for DprError {}
+
+/// Options passed in the query string of image requests, e.g. `/fox/jpg?max_kb=50`.
+#[derive(Debug, Deserialize, Default)]
+pub struct RequestOptions {
+    /// Encoder quality, see [`EncoderSettings::quality`] for what it means for PNG.
+    pub quality: Option<u8>,
+    /// Size budget in kilobytes, the quality found for it is reused for the same derivative.
+    pub max_kb: Option<u32>,
+    /// Background for transparent images served in a format without alpha, `#` is optional.
+    pub bg: Option<BackgroundColor>,
+    /// Frame index or `poster`, serves a still image from an animated one.
+    pub frame: Option<FrameSelection>,
+    /// Page of a PDF to render, one based.
+    pub page: Option<u32>,
+    /// Resolution PDF pages are rendered at.
+    pub dpi: Option<u32>,
+    /// Pixel ratio the resize dimensions are multiplied by, between 1 and 4 in half steps.
+    pub dpr: Option<f32>,
+}
+
+impl RequestOptions {
+    /// Returns `settings` overridden by the options set on the request.
+    pub fn apply(&self, settings: &EncoderSettings) -> EncoderSettings {
+        let mut settings = settings.clone();
+
+        if let Some(quality) = self.quality {
+            settings.quality = Some(quality.clamp(1, 100));
+        }
+
+        if let Some(max_kb) = self.max_kb {
+            settings.max_kb = Some(max_kb);
+        }
+
+        if let Some(background) = self.bg {
+            settings.background = background;
+        }
+
+        settings
+    }
+
+    /// Tells if the request renders the source PDF instead of using the stored master.
+    pub fn renders_page(&self) -> bool {
+        self.page.is_some() || self.dpi.is_some()
+    }
+
+    /// PDF page to render, `default_dpi` is used when the request sets only `page`.
+    pub fn page_render(&self, default_dpi: u32) -> Option<PageRender> {
+        self.renders_page()
+            .then(|| PageRender::new(self.page.unwrap_or(1), self.dpi.unwrap_or(default_dpi)))
+    }
+
+    /// Pixel ratio of the request, or else of the `template` serving it, rounded to the nearest
+    /// half step.
+    pub fn dpr(&self, template: Option<f32>) -> Result<Option<f32>, DprError> {
+        let Some(dpr) = self.dpr.or(template) else {
+            return Ok(None);
+        };
+
+        // NaN is outside of every range
+        if !(MIN_DPR..=MAX_DPR).contains(&dpr) {
+            return Err(DprError(dpr));
+        }
+
+        Ok(Some((dpr / DPR_STEP).round() * DPR_STEP))
+    }
+}
+
+/// Lowers `dpr` so `size` multiplied by it doesn't upscale a master of `source` size. The
+/// requested size itself is always honored.
+pub fn effective_dpr(size: &PixelSize, dpr: f32, source: Option<(u32, u32)>) -> f32 {
+    let Some((width, height)) = source else {
+        return dpr;
+    };
+
+    dpr.min(width as f32 / *size.width() as f32)
+        .min(height as f32 / *size.height() as f32)
+        .max(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dpr() {
+        let options = RequestOptions {
+            dpr: Some(2.5),
+            ..Default::default()
+        };
+
+        assert_eq!(options.dpr(Some(2.0)), Ok(Some(2.5)));
+        assert_eq!(RequestOptions::default().dpr(Some(4.0)), Ok(Some(MAX_DPR)));
+        assert_eq!(RequestOptions::default().dpr(Some(1.3)), Ok(Some(1.5)));
+        assert_eq!(RequestOptions::default().dpr(Some(2.2)), Ok(Some(2.0)));
+        assert_eq!(RequestOptions::default().dpr(None), Ok(None));
+
+        assert_eq!(RequestOptions::default().dpr(Some(8.0)), Err(DprError(8.0)));
+        assert_eq!(RequestOptions::default().dpr(Some(0.5)), Err(DprError(0.5)));
+        assert!(RequestOptions::default().dpr(Some(f32::NAN)).is_err());
+    }
+
+    #[test]
+    fn test_effective_dpr() {
+        let size = PixelSize::new(300, 200);
+
+        assert_eq!(effective_dpr(&size, 2.0, Some((1000, 1000))), 2.0);
+        assert_eq!(effective_dpr(&size, 2.0, Some((450, 1000))), 1.5);
+        assert_eq!(effective_dpr(&size, 2.0, Some((100, 100))), 1.0);
+        assert_eq!(effective_dpr(&size, 2.0, None), 2.0);
+    }
+}
@@ -0,0 +1,156 @@
+//! Color palettes of images. Extracting one decodes and quantizes the whole master, so each
+//! palette is cached in the output path until the master changes.
+
+use std::{io::ErrorKind, path::Path as FsPath, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::IntoResponse,
+};
+use image_processing::color;
+use serde::Deserialize;
+use storage::disk::{DiskStorage, File};
+use tracing::{error, info};
+
+use crate::{ingest::safe_name, read_master, APIState};
+
+const DEFAULT_PALETTE_SIZE: usize = 5;
+const MAX_PALETTE_SIZE: usize = 16;
+/// Folder of the output path where palettes are cached, one sub folder per image with a JSON
+/// file per number of colors.
+const PALETTE_CACHE_FOLDER: &str = "palettes";
+
+#[derive(Debug, Deserialize)]
+pub struct PaletteParams {
+    colors: Option<usize>,
+}
+
+#[tracing::instrument]
+pub async fn serve_palette(
+    Path(image): Path<String>,
+    Query(params): Query<PaletteParams>,
+    State(state): State<Arc<APIState<'_>>>,
+) -> axum::response::Result<impl IntoResponse> {
+    let size = params
+        .colors
+        .unwrap_or(DEFAULT_PALETTE_SIZE)
+        .clamp(1, MAX_PALETTE_SIZE);
+
+    // names are used as a folder of the cache, other names are extracted each time
+    let cached = safe_name(&image);
+    let folder = state
+        .configuration
+        .image
+        .output_path
+        .join(PALETTE_CACHE_FOLDER)
+        .join(&image);
+    let name = size.to_string();
+
+    if cached {
+        if let Ok(json) = std::fs::read(folder.join(format!("{}.json", name))) {
+            return Ok(([(CONTENT_TYPE, "application/json")], json));
+        }
+    }
+
+    let bytes = match read_master(&image, state.configuration).await {
+        Ok(master) => master.bytes,
+        Err(e) => {
+            error!("Failed to read image {:?}: {}", image, e);
+            return Err(StatusCode::NOT_FOUND.into());
+        }
+    };
+
+    let extracted = tokio::task::spawn_blocking(move || -> anyhow::Result<Vec<u8>> {
+        let palette = color::palette_from_bytes(&bytes, size)?;
+        Ok(serde_json::to_vec(&palette)?)
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.into()));
+
+    let json = extracted.map_err(|e| {
+        error!("Failed to extract palette from {:?}: {}", image, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if cached {
+        let stored = DiskStorage::from_path(&folder)
+            .and_then(|storage| storage.add_new_file(File::new(&name, "json"), &json));
+
+        if let Err(e) = stored {
+            error!("Failed to cache palette of {:?}: {}", image, e);
+        }
+    }
+
+    Ok(([(CONTENT_TYPE, "application/json")], json))
+}
+
+/// Drops the cached palettes of `image`, called when its master is replaced.
+pub fn clear_cache(output_path: &FsPath, image: &str) -> std::io::Result<()> {
+    if !safe_name(image) {
+        return Ok(());
+    }
+
+    match std::fs::remove_dir_all(output_path.join(PALETTE_CACHE_FOLDER).join(image)) {
+        Ok(()) => {
+            info!("Cleared cached palettes of {:?}", image);
+            Ok(())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use axum::{body::Body, extract::Request, routing::get, Router};
+    use configuration::config::Settings;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::testing::{self, resource};
+
+    #[tokio::test]
+    async fn test_palette_cached() {
+        let state = testing::state("palette", "cached", Settings::default());
+        let output_path = &state.configuration.image.output_path;
+        fs::write(output_path.join("fox.jpg"), resource("100x100.jpg")).unwrap();
+
+        let app = Router::new()
+            .route("/:image/palette", get(serve_palette))
+            .with_state(Arc::clone(&state));
+        let palette = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = Request::get(uri).body(Body::empty()).unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+
+                (status, body.to_vec())
+            }
+        };
+
+        let (status, extracted) = palette("/fox/palette?colors=3").await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_slice(&extracted).unwrap();
+        assert!(json["colors"].as_array().is_some_and(|c| c.len() <= 3));
+
+        let cache = output_path.join("palettes/fox/3.json");
+        assert_eq!(fs::read(&cache).unwrap(), extracted);
+
+        // no master to read, the palette comes from the cache
+        fs::remove_file(output_path.join("fox.jpg")).unwrap();
+        assert_eq!(palette("/fox/palette?colors=3").await, (status, extracted));
+        assert_eq!(palette("/fox/palette").await.0, StatusCode::NOT_FOUND);
+
+        clear_cache(output_path, "fox").unwrap();
+        assert!(!cache.exists());
+
+        testing::clean(&state);
+    }
+}
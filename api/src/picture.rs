@@ -0,0 +1,593 @@
+//! Responsive image markup: `srcset` candidates for each format, a `<picture>` element using them
+//! and a tiny blurred placeholder (LQIP) to show while the real image loads.
+
+use std::{io::ErrorKind, path::Path as FsPath, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, RawQuery, State},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use configuration::{
+    config::{EncoderSettings, Settings, TemplateType},
+    ImageEncoding,
+};
+use image_processing::{
+    dimensions, image_format,
+    transcoder::{Encoder, Operations, PixelSize, Transcoder},
+};
+use serde::{Deserialize, Serialize};
+use storage::disk::{DiskStorage, File};
+use tracing::{error, info};
+
+use crate::{
+    base_url,
+    format::fallback_format,
+    ingest::safe_name,
+    limits,
+    options::{effective_dpr, RequestOptions},
+    read_master, signatures, APIState, Master,
+};
+
+/// Widths used when none are requested and no template is configured.
+const DEFAULT_WIDTHS: [u32; 5] = [320, 640, 960, 1280, 1920];
+/// Formats offered as `<source>` elements, most efficient first.
+const SOURCE_FORMATS: [ImageEncoding; 2] = [ImageEncoding::AVIF, ImageEncoding::WEBP];
+/// Width of the placeholder, it is stretched and blurred by the browser anyway.
+const LQIP_WIDTH: u32 = 16;
+const LQIP_QUALITY: u8 = 30;
+/// Folder of the output path where placeholders are cached, one JPEG per image.
+const LQIP_CACHE_FOLDER: &str = "lqip";
+
+#[derive(Debug, Deserialize)]
+pub struct PictureParams {
+    /// Comma separated widths, e.g. `320,640,1280`. The configured templates are used otherwise.
+    widths: Option<String>,
+    /// `sizes` attribute, how wide the image is displayed.
+    sizes: Option<String>,
+    alt: Option<String>,
+    /// `html` for the `<picture>` element, JSON otherwise.
+    output: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Candidate {
+    url: String,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PictureSource {
+    #[serde(rename = "type")]
+    content_type: String,
+    srcset: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Picture {
+    /// `<source>` elements, in the order browsers should try them.
+    sources: Vec<PictureSource>,
+    /// Largest fallback image, for the `src` of the `<img>`.
+    src: String,
+    srcset: String,
+    sizes: String,
+    /// Intrinsic size of `src`, lets browsers reserve the space before it loads.
+    width: u32,
+    height: u32,
+    /// Data URI of a tiny version of the image.
+    lqip: String,
+}
+
+#[tracing::instrument]
+pub async fn serve_picture(
+    Path(image): Path<String>,
+    Query(params): Query<PictureParams>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    State(state): State<Arc<APIState<'_>>>,
+) -> axum::response::Result<Response> {
+    let config = state.configuration;
+
+    let widths = match params.widths.as_deref() {
+        Some(widths) => Some(parse_widths(widths).ok_or(StatusCode::BAD_REQUEST)?),
+        None => None,
+    };
+
+    let indexed = match state.index() {
+        Ok(index) => index.get(&image).and_then(|e| e.dimensions),
+        Err(e) => {
+            error!("Failed to read the index: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        }
+    };
+
+    // the master is only read when the index doesn't know its size or the placeholder isn't cached
+    let (master, (width, height)) = match indexed {
+        Some([width, height]) => (None, (width, height)),
+        None => {
+            let master = read(&image, config).await?;
+            let size = dimensions(&master.bytes).map_err(|e| {
+                error!("Failed to read the size of {:?}: {}", image, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+
+            (Some(master), size)
+        }
+    };
+    let base = base_url(&headers);
+
+    let mut candidates = match widths {
+        Some(widths) => resized_candidates(&image, &widths, width, height, config),
+        None if !config.templates.is_empty() => template_candidates(&image, width, height, config),
+        None => resized_candidates(&image, &DEFAULT_WIDTHS, width, height, config),
+    };
+
+    // resizing may be limited to templates
+    if candidates.is_empty() && !config.templates.is_empty() {
+        candidates = template_candidates(&image, width, height, config);
+    }
+
+    // signed URLs expire with the request listing them
+    let expires = query.as_deref().and_then(signing::expires);
+    for (_, candidate) in &mut candidates {
+        candidate.url = signatures::sign(&candidate.url, &config.signing, expires);
+    }
+
+    let lqip = placeholder(&image, master, width, height, config).await?;
+
+    let sizes = params.sizes.unwrap_or_else(|| "100vw".to_owned());
+    let fallback = fallback_format(&config.image.formats);
+    let Some(picture) = Picture::new(candidates, &base, sizes, lqip, fallback) else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    if params.output.as_deref() == Some("html") {
+        let html = picture.html(params.alt.as_deref().unwrap_or_default());
+
+        return Ok(([(CONTENT_TYPE, "text/html; charset=utf-8")], html).into_response());
+    }
+
+    Ok(Json(picture).into_response())
+}
+
+fn parse_widths(widths: &str) -> Option<Vec<u32>> {
+    let widths: Vec<u32> = widths
+        .split(',')
+        .map(|w| w.trim().parse().ok().filter(|w| *w > 0))
+        .collect::<Option<_>>()?;
+
+    Some(widths)
+}
+
+/// Candidates served by `/:width/:height/:image/:extension` in every allowed format. Widths
+/// larger than the image are dropped, upscaling only makes the download bigger, and sizes are
+/// moved to the nearest allowed one.
+fn resized_candidates(
+    image: &str,
+    widths: &[u32],
+    width: u32,
+    height: u32,
+    config: &Settings,
+) -> Vec<(ImageEncoding, Candidate)> {
+    let mut widths: Vec<u32> = widths.iter().copied().filter(|w| *w <= width).collect();
+    if widths.is_empty() {
+        widths.push(width);
+    }
+
+    let mut sizes: Vec<(u32, u32)> = widths
+        .into_iter()
+        .filter_map(|w| {
+            let h = (height as f64 * w as f64 / width as f64).round().max(1.0) as u32;
+            let allowed = limits::nearest_allowed(&PixelSize::new(w, h), &config.image.sizes)?;
+
+            Some((*allowed.width(), *allowed.height()))
+        })
+        .collect();
+
+    sizes.sort_unstable();
+    sizes.dedup();
+
+    config
+        .image
+        .formats
+        .iter()
+        .flat_map(|format| {
+            sizes.iter().map(move |(w, h)| {
+                let candidate = Candidate {
+                    url: format!(
+                        "/{}/{}/{}/{}",
+                        w,
+                        h,
+                        image,
+                        format.extension().trim_start_matches('.')
+                    ),
+                    width: *w,
+                    height: *h,
+                };
+
+                (*format, candidate)
+            })
+        })
+        .collect()
+}
+
+/// Candidates served by the configured templates, each in its own format. The pixel ratio of a
+/// template is lowered as it is when served, so an image of `width` x `height` isn't upscaled.
+fn template_candidates(
+    image: &str,
+    width: u32,
+    height: u32,
+    config: &Settings,
+) -> Vec<(ImageEncoding, Candidate)> {
+    config
+        .templates
+        .iter()
+        .map(|template| {
+            let name = match template.location {
+                TemplateType::Prefix => format!("{}_{}", template.name, image),
+                TemplateType::Suffix => format!("{}_{}", image, template.name),
+            };
+
+            let size = PixelSize::new(template.size[0], template.size[1]);
+            let size = match RequestOptions::default().dpr(template.dpr) {
+                Ok(Some(dpr)) => size.scale(effective_dpr(&size, dpr, Some((width, height)))),
+                _ => size,
+            };
+
+            let candidate = Candidate {
+                url: format!("/{}", name),
+                width: *size.width(),
+                height: *size.height(),
+            };
+
+            (template.format, candidate)
+        })
+        .collect()
+}
+
+async fn read(image: &str, config: &Settings) -> Result<Master, StatusCode> {
+    read_master(image, config).await.map_err(|e| {
+        error!("Failed to read image {:?}: {}", image, e);
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// Placeholder of `image` as a data URI, from the cache or rendered from `master`, which is read
+/// when not given.
+async fn placeholder(
+    image: &str,
+    master: Option<Master>,
+    width: u32,
+    height: u32,
+    config: &Settings,
+) -> Result<String, StatusCode> {
+    let folder = config.image.output_path.join(LQIP_CACHE_FOLDER);
+    let storage = DiskStorage::from_path(&folder).map_err(|e| {
+        error!("Failed to open placeholder cache {:?}: {}", folder, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // names are used as file names of the cache, other names are rendered each time
+    let cached = safe_name(image);
+
+    if cached {
+        if let Ok(bytes) = storage.read_file(File::new(image, "jpg")) {
+            return Ok(data_uri(&bytes));
+        }
+    }
+
+    let master = match master {
+        Some(master) => master,
+        None => read(image, config).await?,
+    };
+
+    let rendered = tokio::task::spawn_blocking(move || lqip(&master, width, height))
+        .await
+        .unwrap_or_else(|e| Err(e.into()));
+
+    let bytes = rendered.map_err(|e| {
+        error!("Failed to render placeholder of {:?}: {}", image, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if cached {
+        if let Err(e) = storage.add_new_file(File::new(image, "jpg"), &bytes) {
+            error!("Failed to cache placeholder of {:?}: {}", image, e);
+        }
+    }
+
+    Ok(data_uri(&bytes))
+}
+
+fn data_uri(jpeg: &[u8]) -> String {
+    format!("data:image/jpeg;base64,{}", STANDARD.encode(jpeg))
+}
+
+/// Drops the cached placeholder of `image`, called when its master is replaced.
+pub fn clear_cache(output_path: &FsPath, image: &str) -> std::io::Result<()> {
+    if !safe_name(image) {
+        return Ok(());
+    }
+
+    let path = output_path
+        .join(LQIP_CACHE_FOLDER)
+        .join(format!("{}.jpg", image));
+
+    match std::fs::remove_file(path) {
+        Ok(()) => {
+            info!("Cleared cached placeholder of {:?}", image);
+            Ok(())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Tiny JPEG of the image, a few hundred bytes that fit in the markup once in a data URI.
+fn lqip(master: &Master, width: u32, height: u32) -> anyhow::Result<Vec<u8>> {
+    let lqip_height = (height * LQIP_WIDTH).div_ceil(width.max(1)).max(1);
+    let settings = EncoderSettings {
+        quality: Some(LQIP_QUALITY),
+        ..EncoderSettings::default()
+    };
+
+    let transcoded = Transcoder.encode(
+        &master.bytes,
+        master.extension.clone(),
+        image_format(&ImageEncoding::JPEG),
+        Some(vec![Operations::Resize(PixelSize::new(
+            LQIP_WIDTH.min(width),
+            lqip_height.min(height),
+        ))]),
+        &settings,
+    )?;
+
+    Ok(transcoded.bytes)
+}
+
+impl Picture {
+    /// Groups `candidates` by format. The `<img>` uses `fallback` when available and the other
+    /// formats become sources. `None` when there is nothing to show.
+    fn new(
+        candidates: Vec<(ImageEncoding, Candidate)>,
+        base: &str,
+        sizes: String,
+        lqip: String,
+        fallback: ImageEncoding,
+    ) -> Option<Self> {
+        let of = |format: ImageEncoding| -> Vec<Candidate> {
+            candidates
+                .iter()
+                .filter(|(f, _)| *f == format)
+                .map(|(_, c)| c.clone())
+                .collect()
+        };
+
+        let fallback = if of(fallback).is_empty() {
+            candidates.last()?.0
+        } else {
+            fallback
+        };
+
+        let sources = SOURCE_FORMATS
+            .into_iter()
+            .filter(|f| *f != fallback)
+            .map(|f| (f, of(f)))
+            .filter(|(_, c)| !c.is_empty())
+            .map(|(f, c)| PictureSource {
+                content_type: f.content_type().to_owned(),
+                srcset: srcset(&c, base),
+            })
+            .collect();
+
+        let images = of(fallback);
+        let largest = images.iter().max_by_key(|c| c.width)?;
+
+        Some(Picture {
+            sources,
+            src: format!("{}{}", base, largest.url),
+            srcset: srcset(&images, base),
+            sizes,
+            width: largest.width,
+            height: largest.height,
+            lqip,
+        })
+    }
+
+    /// Ready to paste `<picture>` element.
+    fn html(&self, alt: &str) -> String {
+        let mut html = String::from("<picture>\n");
+
+        for source in &self.sources {
+            html.push_str(&format!(
+                "  <source type=\"{}\" srcset=\"{}\" sizes=\"{}\">\n",
+                escape_html(&source.content_type),
+                escape_html(&source.srcset),
+                escape_html(&self.sizes)
+            ));
+        }
+
+        html.push_str(&format!(
+            "  <img src=\"{}\" srcset=\"{}\" sizes=\"{}\" width=\"{}\" height=\"{}\" alt=\"{}\" \
+             loading=\"lazy\" decoding=\"async\" \
+             style=\"background-size: cover; background-image: url('{}')\">\n",
+            escape_html(&self.src),
+            escape_html(&self.srcset),
+            escape_html(&self.sizes),
+            self.width,
+            self.height,
+            escape_html(alt),
+            self.lqip
+        ));
+        html.push_str("</picture>\n");
+
+        html
+    }
+}
+
+/// `srcset` attribute with width descriptors.
+fn srcset(candidates: &[Candidate], base: &str) -> String {
+    candidates
+        .iter()
+        .map(|c| format!("{}{} {}w", base, c.url, c.width))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use configuration::config::{SizeMode, TemplateSettings};
+
+    use super::*;
+    use crate::testing;
+
+    fn settings(formats: Vec<ImageEncoding>) -> Settings {
+        let mut settings = Settings::default();
+        settings.image.formats = formats;
+
+        settings
+    }
+
+    #[test]
+    fn test_parse_widths() {
+        assert_eq!(parse_widths("320, 640,1280"), Some(vec![320, 640, 1280]));
+        assert_eq!(parse_widths("320,0"), None);
+        assert_eq!(parse_widths("320,big"), None);
+    }
+
+    #[test]
+    fn test_picture_from_widths() {
+        let config = settings(vec![
+            ImageEncoding::JPEG,
+            ImageEncoding::AVIF,
+            ImageEncoding::WEBP,
+        ]);
+        let candidates = resized_candidates("fox", &[640, 320, 4000], 1000, 500, &config);
+
+        let picture = Picture::new(
+            candidates,
+            "http://cdn",
+            "50vw".to_owned(),
+            "data:,".to_owned(),
+            ImageEncoding::JPEG,
+        )
+        .unwrap();
+
+        assert_eq!(picture.sources.len(), 2);
+        assert_eq!(picture.sources[0].content_type, "image/avif");
+        assert_eq!(
+            picture.sources[1].srcset,
+            "http://cdn/320/160/fox/webp 320w, http://cdn/640/320/fox/webp 640w"
+        );
+        assert_eq!(picture.src, "http://cdn/640/320/fox/jpg");
+        assert_eq!((picture.width, picture.height), (640, 320));
+
+        let html = picture.html("a \"quick\" fox");
+        assert!(html.starts_with("<picture>\n  <source type=\"image/avif\""));
+        assert!(html.contains("alt=\"a &quot;quick&quot; fox\""));
+        assert!(html.contains("sizes=\"50vw\" width=\"640\" height=\"320\""));
+    }
+
+    #[test]
+    fn test_candidates_use_allowed_sizes() {
+        let mut config = settings(vec![ImageEncoding::JPEG]);
+        config.image.sizes.mode = SizeMode::Allowed;
+        config.image.sizes.allowed = vec![[300, 150], [600, 300]];
+
+        let candidates = resized_candidates("fox", &[640, 620, 320], 1000, 500, &config);
+        let sizes: Vec<(u32, u32)> = candidates
+            .iter()
+            .map(|(_, c)| (c.width, c.height))
+            .collect();
+        assert_eq!(sizes, vec![(300, 150), (600, 300)]);
+
+        config.image.sizes.mode = SizeMode::Templates;
+        assert!(resized_candidates("fox", &[640], 1000, 500, &config).is_empty());
+    }
+
+    #[test]
+    fn test_picture_from_templates() {
+        let mut config = settings(vec![ImageEncoding::PNG, ImageEncoding::WEBP]);
+        config.templates = vec![
+            TemplateSettings {
+                location: TemplateType::Prefix,
+                name: "small".to_owned(),
+                size: [200, 100],
+                format: ImageEncoding::WEBP,
+                encoder: EncoderSettings::default(),
+                frame: None,
+                dpr: None,
+            },
+            TemplateSettings {
+                location: TemplateType::Suffix,
+                name: "large".to_owned(),
+                size: [400, 200],
+                format: ImageEncoding::PNG,
+                encoder: EncoderSettings::default(),
+                frame: None,
+                dpr: Some(2.0),
+            },
+        ];
+
+        let picture = Picture::new(
+            template_candidates("fox", 1000, 500, &config),
+            "",
+            "100vw".to_owned(),
+            "data:,".to_owned(),
+            ImageEncoding::JPEG,
+        )
+        .unwrap();
+
+        assert_eq!(picture.src, "/fox_large");
+        assert_eq!((picture.width, picture.height), (800, 400));
+        assert_eq!(picture.sources[0].srcset, "/small_fox 200w");
+
+        // the pixel ratio stops at the size of the image, as when the template is served
+        let candidates = template_candidates("fox", 600, 300, &config);
+        assert_eq!((candidates[1].1.width, candidates[1].1.height), (600, 300));
+        let candidates = template_candidates("fox", 100, 50, &config);
+        assert_eq!((candidates[1].1.width, candidates[1].1.height), (400, 200));
+    }
+
+    #[tokio::test]
+    async fn test_placeholder_cached() -> anyhow::Result<()> {
+        let output = testing::folder("picture", "placeholder");
+        let mut config = settings(vec![ImageEncoding::JPEG]);
+        config.image.output_path = output.clone();
+        let master = Master {
+            bytes: std::fs::read("../resources/100x100.jpg")?,
+            extension: "jpg".to_owned(),
+        };
+
+        let rendered = placeholder("fox", Some(master), 100, 100, &config)
+            .await
+            .expect("placeholder is rendered");
+        assert!(rendered.starts_with("data:image/jpeg;base64,"));
+        assert!(output.join(LQIP_CACHE_FOLDER).join("fox.jpg").is_file());
+
+        // no master to read, the placeholder comes from the cache
+        let cached = placeholder("fox", None, 100, 100, &config)
+            .await
+            .expect("placeholder is cached");
+        assert_eq!(cached, rendered);
+
+        clear_cache(&output, "fox")?;
+        assert!(!output.join(LQIP_CACHE_FOLDER).join("fox.jpg").exists());
+
+        std::fs::remove_dir_all(output)?;
+
+        Ok(())
+    }
+}
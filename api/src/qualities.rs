@@ -0,0 +1,129 @@
+//! Qualities found for `max_kb` requests. Finding the quality fitting a budget encodes the image
+//! several times, a derivative requested again is encoded once with the quality found before.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use configuration::config::EncoderSettings;
+use image_processing::{
+    transcoder::{Encoder, Operations, Transcoded, Transcoder},
+    OutputFormat,
+};
+
+/// Images whose qualities are kept, the cache starts over past it.
+const MAX_IMAGES: usize = 10_000;
+
+/// Quality chosen for each derivative of an image, dropped when the image changes.
+#[derive(Debug, Default)]
+pub struct QualityCache {
+    images: Mutex<HashMap<String, HashMap<String, u8>>>,
+}
+
+impl QualityCache {
+    fn get(&self, image: &str, derivative: &str) -> Option<u8> {
+        let images = self.images.lock().ok()?;
+
+        images.get(image)?.get(derivative).copied()
+    }
+
+    fn insert(&self, image: &str, derivative: String, quality: u8) {
+        let Ok(mut images) = self.images.lock() else {
+            return;
+        };
+
+        if images.len() >= MAX_IMAGES && !images.contains_key(image) {
+            images.clear();
+        }
+
+        images
+            .entry(image.to_owned())
+            .or_default()
+            .insert(derivative, quality);
+    }
+
+    /// Forgets the qualities of `image`, once its master is replaced or removed.
+    pub fn forget(&self, image: &str) {
+        if let Ok(mut images) = self.images.lock() {
+            images.remove(image);
+        }
+    }
+
+    /// Encodes `bytes`, the master of `image`, like [`Transcoder::encode`]. When `settings` has a
+    /// `max_kb` budget, a quality found for the same derivative before is reused.
+    pub fn encode(
+        &self,
+        image: &str,
+        bytes: &[u8],
+        extension: String,
+        target: OutputFormat,
+        ops: Vec<Operations>,
+        settings: &EncoderSettings,
+    ) -> anyhow::Result<Transcoded> {
+        if settings.max_kb.is_none() {
+            return Transcoder.encode(bytes, extension, target, Some(ops), settings);
+        }
+
+        let derivative = format!("{:?} {:?} {:?}", target, ops, settings);
+
+        if let Some(quality) = self.get(image, &derivative) {
+            let settings = EncoderSettings {
+                quality: Some(quality),
+                max_kb: None,
+                ..settings.clone()
+            };
+
+            return Transcoder.encode(bytes, extension, target, Some(ops), &settings);
+        }
+
+        let transcoded = Transcoder.encode(bytes, extension, target, Some(ops), settings)?;
+
+        if let Some(quality) = transcoded.quality {
+            self.insert(image, derivative, quality);
+        }
+
+        Ok(transcoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qualities_by_image() {
+        let cache = QualityCache::default();
+        cache.insert("fox", "Jpeg 40".to_owned(), 70);
+        cache.insert("fox", "Jpeg 20".to_owned(), 45);
+        cache.insert("owl", "Jpeg 40".to_owned(), 80);
+
+        assert_eq!(cache.get("fox", "Jpeg 40"), Some(70));
+        assert_eq!(cache.get("fox", "Jpeg 20"), Some(45));
+        assert_eq!(cache.get("fox", "Png 40"), None);
+
+        cache.forget("fox");
+
+        assert_eq!(cache.get("fox", "Jpeg 40"), None);
+        assert_eq!(cache.get("owl", "Jpeg 40"), Some(80));
+    }
+
+    #[test]
+    fn test_reused_quality_fits() -> anyhow::Result<()> {
+        let cache = QualityCache::default();
+        let bytes = std::fs::read("../resources/100x100.jpg")?;
+        let settings = EncoderSettings {
+            max_kb: Some(2),
+            ..EncoderSettings::default()
+        };
+        let jpeg = OutputFormat::Image(image_processing::ImageFormat::Jpeg);
+
+        let searched = cache.encode("fox", &bytes, "jpg".to_owned(), jpeg, vec![], &settings)?;
+        let quality = searched.quality.expect("JPEG has a quality");
+        let derivative = format!("{:?} [] {:?}", jpeg, settings);
+        assert_eq!(cache.get("fox", &derivative), Some(quality));
+
+        let reused = cache.encode("fox", &bytes, "jpg".to_owned(), jpeg, vec![], &settings)?;
+        assert_eq!(reused.quality, Some(quality));
+        assert_eq!(reused.bytes, searched.bytes);
+
+        Ok(())
+    }
+}
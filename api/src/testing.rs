@@ -0,0 +1,44 @@
+//! Fixtures shared by the tests: an output path per test and a state over it.
+
+use std::{env, fs, path::PathBuf, sync::Arc};
+
+use configuration::config::Settings;
+use image_processing::transcoder::Transcoder;
+use storage::index::ImageIndex;
+
+use crate::APIState;
+
+/// Empty output path of `test` in `module`, so tests running in parallel don't share files.
+pub fn folder(module: &str, test: &str) -> PathBuf {
+    let folder = env::temp_dir().join(format!(
+        "wire-img-{}-{}-{}",
+        module,
+        test,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&folder);
+    fs::create_dir_all(&folder).unwrap();
+
+    folder
+}
+
+/// State over `settings` with the output path of `test` in `module`. The settings are leaked,
+/// handlers take a `'static` state.
+pub fn state(module: &str, test: &str, mut settings: Settings) -> Arc<APIState<'static>> {
+    settings.image.output_path = folder(module, test);
+    let settings = Box::leak(Box::new(settings));
+
+    let index = ImageIndex::open(&settings.image.output_path).unwrap();
+    Arc::new(APIState::new(settings, Transcoder, index))
+}
+
+/// Removes the output path of `state`.
+pub fn clean(state: &APIState<'_>) {
+    let _ = fs::remove_dir_all(&state.configuration.image.output_path);
+}
+
+/// Content of `name` in the resources folder.
+pub fn resource(name: &str) -> Vec<u8> {
+    let s = env::var("CARGO_MANIFEST_DIR").unwrap();
+    fs::read(PathBuf::from(s).join("../resources").join(name)).unwrap()
+}
@@ -0,0 +1,266 @@
+use std::{io::ErrorKind, path::Path as FsPath, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::IntoResponse,
+};
+use configuration::{config::Settings, ImageEncoding};
+use image_processing::{dimensions, image_format, tiles::Pyramid, transcoder::encode_decoded};
+use serde::Deserialize;
+use storage::disk::{DiskStorage, File};
+use tracing::{error, info};
+
+use crate::{format::fallback_format, ingest::safe_name, read_master, APIState, Master};
+
+/// Folder of the output path where rendered tiles are cached, one sub folder per image and level.
+const TILE_CACHE_FOLDER: &str = "tiles";
+
+#[derive(Debug, Deserialize)]
+pub struct DziParams {
+    format: Option<String>,
+}
+
+/// Deep Zoom descriptor of an image, for viewers like OpenSeadragon.
+#[tracing::instrument]
+pub async fn serve_dzi(
+    Path(image): Path<String>,
+    Query(params): Query<DziParams>,
+    State(state): State<Arc<APIState<'_>>>,
+) -> axum::response::Result<impl IntoResponse> {
+    let config = state.configuration;
+
+    let format = match params.format.as_deref() {
+        Some(extension) => ImageEncoding::from_extension(extension),
+        None => Some(default_tile_format(config)),
+    };
+
+    let Some(format) = format.filter(|f| config.image.formats.contains(f)) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    let pyramid = pyramid(&image, config).await?;
+    let url = format!("/{}/tiles/", image);
+
+    Ok((
+        [(CONTENT_TYPE, "application/xml")],
+        pyramid.dzi(format.extension().trim_start_matches('.'), &url),
+    ))
+}
+
+/// Tile `x_y.ext` of a zoom level, numbered like Deep Zoom.
+#[tracing::instrument]
+pub async fn serve_tile(
+    Path((image, level, tile)): Path<(String, u32, String)>,
+    State(state): State<Arc<APIState<'_>>>,
+) -> axum::response::Result<impl IntoResponse> {
+    let config = state.configuration;
+
+    let Some((x, y, format)) = parse_tile(&tile) else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+
+    if !config.image.formats.contains(&format) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let bytes = tile_bytes(&image, level, x, y, format, config).await?;
+
+    Ok(([(CONTENT_TYPE, format.content_type().to_owned())], bytes))
+}
+
+/// Preferred tile format: JPEG when allowed, tiles of photos and scans are large.
+pub fn default_tile_format(config: &Settings) -> ImageEncoding {
+    fallback_format(&config.image.formats)
+}
+
+pub async fn pyramid(image: &str, config: &Settings) -> Result<Pyramid, StatusCode> {
+    pyramid_of(&master(image, config).await?.bytes)
+}
+
+/// Pyramid of an already read master.
+pub fn pyramid_of(bytes: &[u8]) -> Result<Pyramid, StatusCode> {
+    match dimensions(bytes) {
+        Ok((width, height)) => Ok(Pyramid::new(width, height)),
+        Err(e) => {
+            error!("Failed to read image dimensions: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub async fn master(image: &str, config: &Settings) -> Result<Master, StatusCode> {
+    read_master(image, config).await.map_err(|e| {
+        error!("Failed to read image {:?}: {}", image, e);
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// Encoded tile, rendered from the master on the first request and then read from the cache.
+pub async fn tile_bytes(
+    image: &str,
+    level: u32,
+    x: u32,
+    y: u32,
+    format: ImageEncoding,
+    config: &Settings,
+) -> Result<Vec<u8>, StatusCode> {
+    // names are used as a folder of the cache, `a%2F..%2F..` would escape it
+    if !safe_name(image) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let folder = config
+        .image
+        .output_path
+        .join(TILE_CACHE_FOLDER)
+        .join(image)
+        .join(level.to_string());
+    let name = format!("{}_{}", x, y);
+    let extension = format.extension().trim_start_matches('.');
+
+    let storage = DiskStorage::from_path(&folder).map_err(|e| {
+        error!("Failed to open tile cache {:?}: {}", folder, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if let Ok(cached) = storage.read_file(File::new(&name, extension)) {
+        return Ok(cached);
+    }
+
+    let bytes = master(image, config).await?.bytes;
+    let encoder = config.image.encoder.clone();
+
+    // decoding a whole master would hold up the other requests of the runtime thread
+    let render = move || -> anyhow::Result<Option<Vec<u8>>> {
+        let (width, height) = dimensions(&bytes)?;
+        let pyramid = Pyramid::new(width, height);
+
+        if pyramid.tile_rect(level, x, y).is_none() {
+            return Ok(None);
+        }
+
+        let decoded = image_processing::decode(&bytes)?;
+        let tile = pyramid.render_tile(&decoded, level, x, y)?;
+        let encoded = encode_decoded(tile, image_format(&format), &encoder)?;
+
+        Ok(Some(encoded.bytes))
+    };
+
+    let rendered = tokio::task::spawn_blocking(render)
+        .await
+        .unwrap_or_else(|e| Err(e.into()));
+
+    let tile = match rendered {
+        Ok(Some(tile)) => tile,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!(
+                "Failed to render tile {}/{}_{} of {:?}: {}",
+                level, x, y, image, e
+            );
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    if let Err(e) = storage.add_new_file(File::new(&name, extension), &tile) {
+        error!(
+            "Failed to cache tile {}/{} of {:?}: {}",
+            level, name, image, e
+        );
+    }
+
+    Ok(tile)
+}
+
+/// Drops the cached tiles of `image`, called when its master is replaced.
+pub fn clear_cache(output_path: &FsPath, image: &str) -> std::io::Result<()> {
+    // tiles are never cached for other names, and they could point outside of the cache
+    if !safe_name(image) {
+        return Ok(());
+    }
+
+    match std::fs::remove_dir_all(output_path.join(TILE_CACHE_FOLDER).join(image)) {
+        Ok(()) => {
+            info!("Cleared cached tiles of {:?}", image);
+            Ok(())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses a tile file name, `<column>_<row>.<extension>`.
+fn parse_tile(tile: &str) -> Option<(u32, u32, ImageEncoding)> {
+    let (position, extension) = tile.rsplit_once('.')?;
+    let (x, y) = position.split_once('_')?;
+
+    Some((
+        x.parse().ok()?,
+        y.parse().ok()?,
+        ImageEncoding::from_extension(extension)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testing;
+
+    fn settings(test: &str) -> Settings {
+        let mut settings = Settings::default();
+        settings.image.output_path = testing::folder("tiles", test);
+        settings
+    }
+
+    #[tokio::test]
+    async fn test_tile_rendered_and_cached() {
+        let config = settings("cached");
+        let output_path = &config.image.output_path;
+        fs::copy("../resources/10x10.png", output_path.join("tiny.png")).unwrap();
+
+        let tile = tile_bytes("tiny", 0, 0, 0, ImageEncoding::PNG, &config)
+            .await
+            .unwrap();
+        assert_eq!(dimensions(&tile).unwrap(), (1, 1));
+        assert!(output_path.join("tiles/tiny/0/0_0.png").exists());
+
+        assert_eq!(
+            tile_bytes("tiny", 0, 1, 0, ImageEncoding::PNG, &config).await,
+            Err(StatusCode::NOT_FOUND)
+        );
+
+        fs::remove_dir_all(output_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_names_stay_in_the_cache() {
+        let config = settings("escape");
+        let output_path = &config.image.output_path;
+        fs::create_dir_all(output_path.join("kept/0")).unwrap();
+
+        // `a%2F..%2F..%2Fkept` once the path is decoded
+        let escaping = "a/../../kept";
+        assert_eq!(
+            tile_bytes(escaping, 0, 0, 0, ImageEncoding::PNG, &config).await,
+            Err(StatusCode::NOT_FOUND)
+        );
+
+        clear_cache(output_path, escaping).unwrap();
+        clear_cache(output_path, "..").unwrap();
+        assert!(output_path.join("kept/0").exists());
+
+        fs::remove_dir_all(output_path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_tile() {
+        assert_eq!(parse_tile("3_10.jpg"), Some((3, 10, ImageEncoding::JPEG)));
+        assert_eq!(parse_tile("0_0.webp"), Some((0, 0, ImageEncoding::WEBP)));
+        assert_eq!(parse_tile("0_0.tiff"), None);
+        assert_eq!(parse_tile("0-0.png"), None);
+        assert_eq!(parse_tile("a_0.png"), None);
+    }
+}
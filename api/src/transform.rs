@@ -0,0 +1,358 @@
+//! Encoding of an image for a request: resizing within the configured sizes and limits, pixel
+//! ratios, format negotiation and encoding, shared by the transform routes and `/fetch`.
+
+use std::sync::Arc;
+
+use configuration::{
+    config::{EncoderSettings, TemplateSettings, UpscalePolicy},
+    ImageEncoding,
+};
+use image_processing::{
+    alpha, animation, detect_encoding, dimensions, image_format, svg,
+    transcoder::{Operations, PixelSize, Transcoded},
+};
+
+use crate::{
+    format::{negotiate, ImageTraits, RequestedFormat},
+    hints::ClientHints,
+    limits::{self, SizeOrigin},
+    options::{effective_dpr, RequestOptions},
+    APIState, Master,
+};
+
+/// Image encoded for a request.
+pub struct Processed {
+    pub encoding: ImageEncoding,
+    pub transcoded: Transcoded,
+    /// Size the image was resized to.
+    pub size: Option<PixelSize>,
+    /// Physical pixels per CSS pixel, when the size was multiplied by a pixel ratio.
+    pub content_dpr: Option<f32>,
+    /// Dominant color of the master, recorded when it was ingested.
+    pub dominant_color: Option<String>,
+}
+
+impl Processed {
+    fn unresized(encoding: ImageEncoding, transcoded: Transcoded) -> Self {
+        Processed {
+            encoding,
+            transcoded,
+            size: None,
+            content_dpr: None,
+            dominant_color: None,
+        }
+    }
+}
+
+/// What a request asks of an image.
+#[derive(Debug)]
+pub struct Transform {
+    pub format: RequestedFormat,
+    /// `Accept` header of the request.
+    pub accept: Option<String>,
+    /// Size in the URL.
+    pub size: Option<PixelSize>,
+    pub options: RequestOptions,
+    pub hints: ClientHints,
+}
+
+/// Encodes `master`, the master of `image_name` or an original fetched for it, as `request` and
+/// the `template` matching the name ask. Decoding and encoding run on a blocking thread, so they
+/// don't hold the async runtime.
+pub async fn transform(
+    image_name: String,
+    master: Master,
+    template: Option<&'static TemplateSettings>,
+    request: Transform,
+    state: Arc<APIState<'static>>,
+) -> anyhow::Result<Processed> {
+    tokio::task::spawn_blocking(move || {
+        let mut processed = render(&image_name, master, template, &request, &state)?;
+
+        // the encoded image isn't decoded again for a header, the color of the master is close
+        // enough
+        if state.configuration.image.dominant_color_header {
+            processed.dominant_color = state
+                .index()?
+                .get(&image_name)
+                .and_then(|e| e.dominant_color.clone());
+        }
+
+        Ok(processed)
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.into()))
+}
+
+fn render(
+    image_name: &str,
+    master: Master,
+    template: Option<&TemplateSettings>,
+    request: &Transform,
+    state: &APIState<'_>,
+) -> anyhow::Result<Processed> {
+    let Transform {
+        format: requested_format,
+        ref accept,
+        size: new_size,
+        ref options,
+        ref hints,
+    } = *request;
+
+    let Master { bytes, extension } = master;
+    let config = state.configuration;
+    let mut op = Vec::new();
+
+    if let Some(frame) = options.frame {
+        op.push(Operations::Frame(frame));
+    }
+
+    if let Some(render) = options.page_render(config.image.pdf_dpi) {
+        op.push(Operations::Page(render));
+    }
+
+    // checked even when unused, an invalid ratio is refused the same on every route
+    let dpr = options.dpr(template.and_then(|t| t.dpr))?;
+
+    if let Some(template) = template {
+        let requested = PixelSize::new(template.size[0], template.size[1]);
+        let (size, content_dpr) = match dpr {
+            Some(dpr) => {
+                let source = source_size(image_name, &bytes, options, state)?;
+                let dpr = effective_dpr(&requested, dpr, source);
+                (requested.scale(dpr), Some(dpr))
+            }
+            None => (requested, None),
+        };
+        // templates are always allowed, only the limits apply to them
+        let size = constrain(
+            size,
+            SizeOrigin::Configured,
+            image_name,
+            &bytes,
+            options,
+            state,
+        )?
+        .unwrap_or(size);
+
+        let mut template_op = vec![Operations::Resize(size)];
+
+        if let Some(frame) = options.frame.or(template.frame) {
+            template_op.push(Operations::Frame(frame));
+        }
+
+        if let Some(render) = options.page_render(config.image.pdf_dpi) {
+            template_op.push(Operations::Page(render));
+        }
+
+        let transcoded = state.qualities.encode(
+            image_name,
+            &bytes,
+            extension,
+            image_format(&template.format),
+            template_op,
+            &hints.apply(
+                options.apply(&template.encoder),
+                options.quality,
+                &config.image.client_hints,
+            ),
+        )?;
+
+        return Ok(Processed {
+            encoding: template.format,
+            transcoded,
+            size: Some(size),
+            content_dpr,
+            dominant_color: None,
+        });
+    }
+
+    let widths = &config.image.client_hints.widths;
+    let mut content_dpr = None;
+    let mut origin = SizeOrigin::Derived;
+
+    // a size in the URL must be allowed, before a pixel ratio changes it
+    if let Some(requested) = new_size {
+        limits::allowed(requested, SizeOrigin::Requested, &config.image.sizes)?;
+    }
+
+    // an explicit pixel ratio wins over the hinted one, so URLs stay stable
+    let size = if let Some(requested) = new_size {
+        if let Some(dpr) = dpr {
+            let source = source_size(image_name, &bytes, options, state)?;
+            let dpr = effective_dpr(&requested, dpr, source);
+            content_dpr = Some(dpr);
+
+            Some(requested.scale(dpr))
+        } else if let Some(hinted) = hints.scale(&requested, widths) {
+            content_dpr = Some(hinted.content_dpr);
+
+            Some(hinted.size)
+        } else {
+            origin = SizeOrigin::Requested;
+            Some(requested)
+        }
+    } else if hints.has_width() {
+        // without a size in the URL, the hinted display width avoids sending a huge master
+        source_size(image_name, &bytes, options, state)?.and_then(|(width, height)| {
+            hints.fit(width, height, widths).map(|hinted| {
+                content_dpr = Some(hinted.content_dpr);
+                hinted.size
+            })
+        })
+    } else {
+        None
+    };
+
+    let derived = size;
+    let size = match size {
+        Some(size) => constrain(size, origin, image_name, &bytes, options, state)?,
+        None => None,
+    };
+
+    // rounding to an allowed size or fitting the limits changes the pixel ratio
+    content_dpr = match (content_dpr, derived, size) {
+        (Some(dpr), Some(from), Some(to)) => Some(dpr * *to.width() as f32 / *from.width() as f32),
+        _ => None,
+    };
+
+    if let Some(size) = size {
+        op.insert(0, Operations::Resize(size));
+    }
+
+    let target_format = match requested_format {
+        RequestedFormat::Fixed(encoding) => encoding,
+        RequestedFormat::Auto => {
+            let traits = ImageTraits {
+                transparent: config.image.auto_transparency
+                    && is_transparent(image_name, &bytes, options, state)?,
+                animated: options.frame.is_none() && animation::is_animated(&bytes)?,
+            };
+
+            negotiate(accept.as_deref(), &config.image.formats, traits)
+        }
+    };
+
+    let settings = hints.apply(
+        options.apply(&config.image.encoder),
+        options.quality,
+        &config.image.client_hints,
+    );
+
+    // JPEGs recompressed to JPEG XL at ingest are served as the original file
+    if op.is_empty()
+        && target_format == ImageEncoding::JPEG
+        && settings == EncoderSettings::default()
+        && image_processing::codecs::jxl::is_jxl(&bytes)
+    {
+        if let Some(jpeg) = image_processing::codecs::jxl::reconstruct_jpeg(&bytes)? {
+            return Ok(Processed::unresized(
+                target_format,
+                Transcoded {
+                    bytes: jpeg,
+                    quality: None,
+                },
+            ));
+        }
+    }
+
+    // animated masters are kept in their original format, so check the bytes and not only the
+    // configured storage format
+    if op.is_empty()
+        && target_format == config.image.storage_format
+        && settings == EncoderSettings::default()
+        && detect_encoding(&bytes) == Some(target_format)
+    {
+        return Ok(Processed::unresized(
+            target_format,
+            Transcoded {
+                bytes,
+                quality: None,
+            },
+        ));
+    }
+
+    let transcoded = state.qualities.encode(
+        image_name,
+        &bytes,
+        extension,
+        image_format(&target_format),
+        op,
+        &settings,
+    )?;
+
+    Ok(Processed {
+        encoding: target_format,
+        transcoded,
+        size,
+        content_dpr,
+        dominant_color: None,
+    })
+}
+
+/// Size of the master when resizing it past that loses detail. Vectors and PDF pages are
+/// rendered at the requested size, so they have none. The size recorded in the index is
+/// preferred, reading it from an AVIF master decodes the whole image.
+fn source_size(
+    name: &str,
+    bytes: &[u8],
+    options: &RequestOptions,
+    state: &APIState<'_>,
+) -> anyhow::Result<Option<(u32, u32)>> {
+    if options.renders_page() || svg::is_svg(bytes) {
+        return Ok(None);
+    }
+
+    let indexed = state.index()?.get(name).and_then(|e| e.dimensions);
+
+    match indexed {
+        Some([width, height]) => Ok(Some((width, height))),
+        None => Ok(Some(dimensions(bytes)?)),
+    }
+}
+
+/// Tells if the served image has transparent pixels. The master's is recorded in the index at
+/// ingest, the bytes are only decoded for PDF pages and images indexed before it was.
+fn is_transparent(
+    name: &str,
+    bytes: &[u8],
+    options: &RequestOptions,
+    state: &APIState<'_>,
+) -> anyhow::Result<bool> {
+    let indexed = if options.renders_page() {
+        None
+    } else {
+        state.index()?.get(name).and_then(|e| e.transparent)
+    };
+
+    match indexed {
+        Some(transparent) => Ok(transparent),
+        None => alpha::has_transparency_bytes(bytes),
+    }
+}
+
+/// Size a resize is done at: the allowed sizes applied as `origin` asks, then the size limits
+/// once multiplied by a pixel ratio and the upscaling policy. `None` when the image isn't resized.
+fn constrain(
+    size: PixelSize,
+    origin: SizeOrigin,
+    name: &str,
+    bytes: &[u8],
+    options: &RequestOptions,
+    state: &APIState<'_>,
+) -> anyhow::Result<Option<PixelSize>> {
+    let config = &state.configuration.image;
+    let Some(size) = limits::allowed(size, origin, &config.sizes)? else {
+        return Ok(None);
+    };
+    let size = limits::fit(size, config);
+
+    if config.allow_upscale == UpscalePolicy::Allowed {
+        return Ok(Some(size));
+    }
+
+    match source_size(name, bytes, options, state)? {
+        Some(source) => Ok(Some(limits::upscale(size, source, config.allow_upscale)?)),
+        None => Ok(Some(size)),
+    }
+}
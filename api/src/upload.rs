@@ -0,0 +1,555 @@
+//! Uploads through the API, for clients that can't write to the watched input folder. The body is
+//! either the image itself or a multipart form with the image in a file field.
+
+use std::{io, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Multipart, Path, Query, Request, State},
+    http::{
+        header::{CONTENT_TYPE, LOCATION},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use image_processing::{limits::LimitError, ImageFormat};
+use serde::Deserialize;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::{
+    ingest::{self, valid_name, DuplicateError, ExistsError},
+    library::ImageMetadata,
+    APIState,
+};
+
+/// Room for the multipart boundaries and headers on top of `max_input_bytes`.
+pub const MULTIPART_OVERHEAD: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct UploadParams {
+    /// Id to store the image under, generated when missing.
+    name: Option<String>,
+}
+
+/// Image read from the request body.
+#[derive(Debug, Default)]
+struct Upload {
+    name: Option<String>,
+    /// Used when the format can't be detected from the content.
+    extension: String,
+    content: Bytes,
+}
+
+/// Stores a new master, converted to the storage format like the files dropped in `input_path`.
+/// Answers with a 201 and the id, URL and metadata of the image.
+#[tracing::instrument(skip(request))]
+pub async fn upload_image(
+    Query(params): Query<UploadParams>,
+    State(state): State<Arc<APIState<'static>>>,
+    request: Request,
+) -> axum::response::Result<Response> {
+    let headers = request.headers().clone();
+    let mut upload = read_upload(request).await?;
+
+    let name = match params.name.or(upload.name.take()) {
+        Some(name) if valid_name(&name) => name,
+        Some(name) => {
+            warn!("Refused upload with invalid name {:?}", name);
+            return Err((StatusCode::BAD_REQUEST, "invalid image name").into());
+        }
+        None => Uuid::now_v7().as_simple().to_string(),
+    };
+
+    // answered before the upload is decoded, the ingest checks again as it stores the master
+    if exists(&name, &state).map_err(|e| {
+        error!("Failed to read the index: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })? {
+        return Err(conflict().into());
+    }
+
+    Ok(store(name, upload, false, StatusCode::CREATED, &headers, state).await?)
+}
+
+/// Replaces the master of `image`, or creates it, and drops everything derived from the previous
+/// one. Answers like [`upload_image`], with a 200 when a master was replaced.
+#[tracing::instrument(skip(request))]
+pub async fn replace_image(
+    Path(image): Path<String>,
+    State(state): State<Arc<APIState<'static>>>,
+    request: Request,
+) -> axum::response::Result<Response> {
+    // ids of dropped files may not be valid names, their masters can still be replaced
+    if !ingest::known_name(&state, &image).map_err(|e| {
+        error!("Failed to read the index: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })? {
+        return Err((StatusCode::BAD_REQUEST, "invalid image name").into());
+    }
+
+    let headers = request.headers().clone();
+    let upload = read_upload(request).await?;
+
+    let replaced = exists(&image, &state).map_err(|e| {
+        error!("Failed to read the index: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let status = if replaced {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+
+    Ok(store(image, upload, true, status, &headers, state).await?)
+}
+
+async fn read_upload(request: Request) -> Result<Upload, Response> {
+    let upload = if is_multipart(request.headers()) {
+        let multipart = Multipart::from_request(request, &())
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        read_multipart(multipart).await?
+    } else {
+        Upload {
+            name: None,
+            extension: mime_extension(request.headers()).unwrap_or_default(),
+            content: Bytes::from_request(request, &())
+                .await
+                .map_err(IntoResponse::into_response)?,
+        }
+    };
+
+    if upload.content.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "no image in the request").into_response());
+    }
+
+    Ok(upload)
+}
+
+/// Ingests `upload` off the runtime, it is decoded, transcoded and hashed. Unless `replace`, it
+/// fails when `name` is taken.
+async fn store(
+    name: String,
+    upload: Upload,
+    replace: bool,
+    status: StatusCode,
+    headers: &HeaderMap,
+    state: Arc<APIState<'static>>,
+) -> Result<Response, Response> {
+    let ingested = {
+        let state = Arc::clone(&state);
+        let name = name.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let content = upload.content.into();
+
+            if replace {
+                ingest::ingest(&state, &name, &upload.extension, content)
+            } else {
+                ingest::ingest_new(&state, &name, &upload.extension, content)
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.into()))
+    };
+
+    let entry = ingested.map_err(|e| {
+        warn!("Upload '{}' was not ingested: {}", name, e);
+
+        match ingest_status(&e) {
+            StatusCode::CONFLICT if e.is::<ExistsError>() => conflict().into_response(),
+            status => status.into_response(),
+        }
+    })?;
+
+    let image = ImageMetadata::new(entry, headers, &state);
+
+    Ok((status, [(LOCATION, image.url.clone())], Json(image)).into_response())
+}
+
+fn conflict() -> (StatusCode, &'static str) {
+    (
+        StatusCode::CONFLICT,
+        "an image with this name already exists",
+    )
+}
+
+fn is_multipart(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("multipart/form-data"))
+}
+
+/// Reads the image from the first file field, or the field named `file` or `image`, and the id
+/// from the `name` field.
+async fn read_multipart(mut multipart: Multipart) -> Result<Upload, Response> {
+    let status = |e: axum::extract::multipart::MultipartError| (e.status(), e.body_text());
+    let mut upload = Upload::default();
+    let mut found = false;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| status(e).into_response())?
+    {
+        let field_name = field.name().unwrap_or_default().to_owned();
+
+        if field_name == "name" {
+            upload.name = Some(field.text().await.map_err(|e| status(e).into_response())?);
+        } else if !found
+            && (field.file_name().is_some() || matches!(&*field_name, "file" | "image"))
+        {
+            upload.extension = field
+                .file_name()
+                .and_then(|f| f.rsplit_once('.'))
+                .map(|(_, e)| e.to_lowercase())
+                .or_else(|| field.content_type().and_then(extension_of))
+                .unwrap_or_default();
+            upload.content = field.bytes().await.map_err(|e| status(e).into_response())?;
+            found = true;
+        }
+    }
+
+    Ok(upload)
+}
+
+fn mime_extension(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(extension_of)
+}
+
+fn extension_of(mime: &str) -> Option<String> {
+    let mime = mime.split(';').next()?.trim();
+
+    ImageFormat::from_mime_type(mime)
+        .and_then(|f| f.extensions_str().first())
+        .map(|e| e.to_string())
+}
+
+fn exists(name: &str, state: &APIState<'_>) -> anyhow::Result<bool> {
+    Ok(state.index()?.get(name).is_some()
+        || ingest::master_extension(&state.configuration.image.output_path, name).is_some())
+}
+
+/// Status for an upload that could not be ingested: oversized and duplicate images are told
+/// apart from the ones that can't be decoded.
+fn ingest_status(e: &anyhow::Error) -> StatusCode {
+    if e.is::<LimitError>() {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else if e.is::<DuplicateError>() || e.is::<ExistsError>() {
+        StatusCode::CONFLICT
+    } else if e.is::<io::Error>() {
+        error!("Failed to store upload: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use axum::{
+        body::Body,
+        extract::DefaultBodyLimit,
+        http::Method,
+        routing::{post, put},
+        Router,
+    };
+    use configuration::config::{DuplicateAction, Settings};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::testing::{self, resource};
+
+    fn state(test: &str, settings: Settings) -> Arc<APIState<'static>> {
+        testing::state("upload", test, settings)
+    }
+
+    async fn upload(
+        state: &Arc<APIState<'static>>,
+        uri: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> (StatusCode, serde_json::Value) {
+        send(state, Method::POST, uri, content_type, body).await
+    }
+
+    async fn send(
+        state: &Arc<APIState<'static>>,
+        method: Method,
+        uri: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> (StatusCode, serde_json::Value) {
+        let limit = state.configuration.image.decode.max_input_bytes as usize;
+        let app = Router::new()
+            .route("/images", post(upload_image))
+            .route("/images/:image", put(replace_image))
+            .layer(DefaultBodyLimit::max(limit + MULTIPART_OVERHEAD))
+            .with_state(Arc::clone(state));
+
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, content_type)
+            .header("host", "img.test")
+            .body(Body::from(body))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (
+            status,
+            serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_raw_upload() {
+        let state = state("raw", Settings::default());
+
+        let (status, body) = upload(
+            &state,
+            "/images?name=fox",
+            "image/jpeg",
+            resource("100x100.jpg"),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["id"], "fox");
+        assert_eq!(body["url"], "http://img.test/fox");
+        assert_eq!(body["format"], "avif");
+        assert_eq!(body["width"], 100);
+        assert_eq!(body["height"], 100);
+        assert!(state
+            .configuration
+            .image
+            .output_path
+            .join("fox.avif")
+            .exists());
+        assert!(state.index.lock().unwrap().get("fox").is_some());
+
+        // ids are never replaced by an upload
+        let (status, _) = upload(
+            &state,
+            "/images?name=fox",
+            "image/png",
+            resource("10x10.png"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, _) = upload(
+            &state,
+            "/images?name=../fox",
+            "image/png",
+            resource("10x10.png"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = upload(&state, "/images", "image/png", b"not an image".to_vec()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_multipart_upload() {
+        let state = state("multipart", Settings::default());
+
+        let mut body = Vec::new();
+        body.extend_from_slice(
+            b"--boundary\r\n\
+            Content-Disposition: form-data; name=\"name\"\r\n\r\n\
+            tiny\r\n\
+            --boundary\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"tiny.png\"\r\n\
+            Content-Type: image/png\r\n\r\n",
+        );
+        body.extend_from_slice(&resource("10x10.png"));
+        body.extend_from_slice(b"\r\n--boundary--\r\n");
+
+        let (status, body) = upload(
+            &state,
+            "/images",
+            "multipart/form-data; boundary=boundary",
+            body,
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["id"], "tiny");
+        assert_eq!(body["width"], 20);
+
+        // without a name the id is generated
+        let (status, body) = upload(&state, "/images", "image/jpeg", resource("100x100.jpg")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["id"].as_str().unwrap().len(), 32);
+    }
+
+    #[tokio::test]
+    async fn test_upload_limits() {
+        let mut settings = Settings::default();
+        settings.image.decode.max_input_bytes = 512;
+        settings.image.duplicates.action = DuplicateAction::Reject;
+        let state = state("limits", settings);
+
+        let image = resource("100x100.jpg");
+        assert!(image.len() > 512);
+
+        let (status, _) = upload(&state, "/images", "image/jpeg", image).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let (status, _) =
+            upload(&state, "/images?name=a", "image/png", resource("10x10.png")).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, _) =
+            upload(&state, "/images?name=b", "image/png", resource("10x10.png")).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_replace() {
+        let state = state("replace", Settings::default());
+        let output_path = &state.configuration.image.output_path;
+
+        let (status, _) = send(
+            &state,
+            Method::PUT,
+            "/images/fox",
+            "image/png",
+            resource("10x10.png"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        fs::create_dir_all(output_path.join("tiles/fox/0")).unwrap();
+        fs::write(output_path.join("fox.pdf"), b"previous source").unwrap();
+
+        let (status, body) = send(
+            &state,
+            Method::PUT,
+            "/images/fox",
+            "image/jpeg",
+            resource("100x100.jpg"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["width"], 100);
+
+        // nothing derived from the previous master is served anymore
+        assert!(!output_path.join("tiles/fox").exists());
+        assert!(!output_path.join("fox.pdf").exists());
+        assert_eq!(
+            state.index.lock().unwrap().get("fox").unwrap().dimensions,
+            Some([100, 100])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stored_in_its_format() {
+        let mut settings = Settings::default();
+        settings.image.storage_format = configuration::ImageEncoding::PNG;
+        let state = state("format", settings);
+        let output_path = &state.configuration.image.output_path;
+
+        // a master left in another format by a previous storage format
+        fs::write(output_path.join("fox.avif"), b"previous master").unwrap();
+
+        let (status, body) = send(
+            &state,
+            Method::PUT,
+            "/images/fox",
+            "image/jpeg",
+            resource("100x100.jpg"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["format"], "png");
+        assert!(output_path.join("fox.png").exists());
+        assert!(!output_path.join("fox.avif").exists());
+
+        let master = crate::read_master("fox", state.configuration)
+            .await
+            .unwrap();
+        assert_eq!(master.extension, "png");
+        assert_eq!(
+            image_processing::dimensions(&master.bytes).unwrap(),
+            (100, 100)
+        );
+
+        assert!(ingest::remove(&state, "fox").unwrap());
+        assert!(!output_path.join("fox.png").exists());
+        assert!(crate::read_master("fox", state.configuration)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload_needs_admin_token() {
+        let mut settings = Settings::default();
+        settings.image.storage_format = configuration::ImageEncoding::PNG;
+        settings.admin.tokens = vec!["admin-token".to_owned()];
+        let state = state("admin", settings);
+
+        // the routes as the server mounts them
+        let status = |token: Option<&str>| {
+            let app = crate::library_routes(&state).with_state(Arc::clone(&state));
+            let mut request = Request::post("/images?name=fox").header(CONTENT_TYPE, "image/jpeg");
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            let request = request.body(Body::from(resource("100x100.jpg"))).unwrap();
+
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("guess")).await, StatusCode::UNAUTHORIZED);
+        assert!(!state
+            .configuration
+            .image
+            .output_path
+            .join("fox.png")
+            .exists());
+
+        assert_eq!(status(Some("admin-token")).await, StatusCode::CREATED);
+        assert_eq!(status(Some("admin-token")).await, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_new_ids_not_replaced() {
+        let mut settings = Settings::default();
+        settings.image.storage_format = configuration::ImageEncoding::PNG;
+        let state = state("exists", settings);
+        let output_path = &state.configuration.image.output_path;
+
+        let fox = resource("100x100.jpg");
+        ingest::ingest_new(&state, "fox", "jpg", fox.clone()).unwrap();
+        let error = ingest::ingest_new(&state, "fox", "jpg", fox.clone()).unwrap_err();
+        assert!(error.is::<ExistsError>());
+
+        // a master written by something else than an upload, not yet indexed
+        fs::write(output_path.join("owl.png"), b"stored elsewhere").unwrap();
+        let error = ingest::ingest_new(&state, "owl", "jpg", fox).unwrap_err();
+        assert!(error.is::<ExistsError>());
+        assert_eq!(
+            fs::read(output_path.join("owl.png")).unwrap(),
+            b"stored elsewhere"
+        );
+    }
+}
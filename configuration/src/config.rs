@@ -1,7 +1,8 @@
 #![allow(dead_code)]
 
-use std::{net::Ipv4Addr, path::PathBuf};
+use std::{net::Ipv4Addr, path::PathBuf, str::FromStr};
 
+use anyhow::anyhow;
 use serde::Deserialize;
 
 use crate::ImageEncoding;
@@ -11,6 +12,87 @@ pub struct Settings {
     pub server: ServerSettings,
     pub image: ImageSettings,
     pub templates: Vec<TemplateSettings>,
+    #[serde(default)]
+    pub signing: SigningSettings,
+    #[serde(default)]
+    pub admin: AdminSettings,
+    #[serde(default)]
+    pub fetch: FetchSettings,
+}
+
+/// Listing, uploads, replacements and deletions through the API. They are refused while no token
+/// is set.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct AdminSettings {
+    /// Bearer tokens accepted, several can be listed while one is rotated.
+    pub tokens: Vec<String>,
+}
+
+/// Signed URLs. When enabled, requests transforming images must be signed with one of the keys.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct SigningSettings {
+    pub enabled: bool,
+    /// The first key signs new URLs. Rotated keys stay listed while URLs signed with them are in
+    /// use.
+    pub keys: Vec<SigningKey>,
+}
+
+/// Transforms of images fetched from origin URLs, `/fetch/<options>/<url>`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct FetchSettings {
+    pub enabled: bool,
+    /// Hosts images are fetched from, `*.example.com` also allows its subdomains. Redirects must
+    /// stay on them too.
+    pub allowed_domains: Vec<String>,
+    /// Seconds a fetch can take, connecting included.
+    pub timeout: u64,
+    /// Largest original fetched, `image.decode.max_input_bytes` applies too.
+    pub max_bytes: u64,
+    pub max_redirects: usize,
+    /// Allows origins on loopback, private, link-local and other non-public addresses, which are
+    /// refused so requests can't reach internal services.
+    pub allow_private_addresses: bool,
+    /// Stores fetched originals as masters, so each URL is fetched once.
+    pub persist: bool,
+}
+
+impl Default for FetchSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allowed_domains: Vec::new(),
+            timeout: 10,
+            max_bytes: 16 * 1024 * 1024,
+            max_redirects: 3,
+            allow_private_addresses: false,
+            persist: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SigningKey {
+    /// Sent in signed URLs, so it should be URL safe.
+    pub id: String,
+    pub secret: String,
+}
+
+impl SigningSettings {
+    /// Secret of the key `id`.
+    pub fn secret(&self, id: &str) -> Option<&[u8]> {
+        self.keys
+            .iter()
+            .find(|k| k.id == id)
+            .map(|k| k.secret.as_bytes())
+    }
+
+    /// Key signing new URLs.
+    pub fn current(&self) -> Option<&SigningKey> {
+        self.keys.first()
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -34,6 +116,64 @@ pub struct ImageSettings {
     pub storage_format: ImageEncoding,
     pub input_path: PathBuf,
     pub output_path: PathBuf,
+    /// Adds the `X-Dominant-Color` header to image responses.
+    #[serde(default)]
+    pub dominant_color_header: bool,
+    #[serde(default)]
+    pub duplicates: DuplicateSettings,
+    /// Encoder options used when a request does not match any template.
+    #[serde(default)]
+    pub encoder: EncoderSettings,
+    /// When the client asks for the `auto` format, serve WebP or PNG instead of a format without
+    /// alpha if the image has transparent pixels.
+    #[serde(default = "default_true")]
+    pub auto_transparency: bool,
+    /// Page stored when a multi-page TIFF is ingested, zero based.
+    #[serde(default)]
+    pub tiff_page: u32,
+    /// With the `jxl` storage format, JPEG uploads are recompressed losslessly instead of being
+    /// re-encoded from their pixels, so the original file can be served back bit for bit.
+    #[serde(default = "default_true")]
+    pub jpeg_recompression: bool,
+    /// Resolution PDF pages are rendered at, when ingested or when a request doesn't set `dpi`.
+    #[serde(default = "default_pdf_dpi")]
+    pub pdf_dpi: u32,
+    #[serde(default)]
+    pub client_hints: ClientHintSettings,
+    /// Largest width served, larger requests are refused before the image is read.
+    #[serde(default = "default_max_side")]
+    pub max_width: u32,
+    #[serde(default = "default_max_side")]
+    pub max_height: u32,
+    /// Largest area served, in pixels.
+    #[serde(default = "default_max_pixels")]
+    pub max_pixels: u64,
+    /// What to do when a request is larger than the stored image.
+    #[serde(default)]
+    pub allow_upscale: UpscalePolicy,
+    #[serde(default)]
+    pub sizes: SizeSettings,
+    #[serde(default)]
+    pub decode: DecodeSettings,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Resolution PDF pages are rendered at when nothing else is configured or requested.
+pub const DEFAULT_PDF_DPI: u32 = 150;
+
+fn default_pdf_dpi() -> u32 {
+    DEFAULT_PDF_DPI
+}
+
+fn default_max_side() -> u32 {
+    8192
+}
+
+fn default_max_pixels() -> u64 {
+    40_000_000
 }
 
 impl Default for ImageSettings {
@@ -43,16 +183,298 @@ impl Default for ImageSettings {
             storage_format: ImageEncoding::AVIF,
             input_path: "/var/lib/wire-img/in".into(),
             output_path: "/var/lib/wire-img/out".into(),
+            dominant_color_header: false,
+            duplicates: DuplicateSettings::default(),
+            encoder: EncoderSettings::default(),
+            auto_transparency: true,
+            tiff_page: 0,
+            jpeg_recompression: true,
+            pdf_dpi: default_pdf_dpi(),
+            client_hints: ClientHintSettings::default(),
+            max_width: default_max_side(),
+            max_height: default_max_side(),
+            max_pixels: default_max_pixels(),
+            allow_upscale: UpscalePolicy::default(),
+            sizes: SizeSettings::default(),
+            decode: DecodeSettings::default(),
         }
     }
 }
 
+/// Responsive images from the `Sec-CH-DPR`, `Sec-CH-Width`, `Sec-CH-Viewport-Width` and
+/// `Save-Data` request headers.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ClientHintSettings {
+    /// Off unless configured, hinted responses vary on the hints and split shared caches.
+    pub enabled: bool,
+    /// Widths hinted sizes are rounded up to, so a few variants serve every device.
+    pub widths: Vec<u32>,
+    /// Quality used when the client asks to save data, unless the request sets one.
+    pub save_data_quality: u8,
+}
+
+impl Default for ClientHintSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            widths: vec![320, 480, 640, 768, 1024, 1280, 1536, 1920, 2560],
+            save_data_quality: 50,
+        }
+    }
+}
+
+/// Limits on untrusted input, checked before decoding so a small file declaring a huge image
+/// can't exhaust the memory.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct DecodeSettings {
+    /// Largest file read, in bytes.
+    pub max_input_bytes: u64,
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Largest allocation of a decoder, in bytes, including the decoded pixels.
+    pub max_alloc: u64,
+}
+
+impl Default for DecodeSettings {
+    fn default() -> Self {
+        Self {
+            max_input_bytes: 64 * 1024 * 1024,
+            max_width: 16384,
+            max_height: 16384,
+            max_alloc: 512 * 1024 * 1024,
+        }
+    }
+}
+
+/// Sizes images are resized to, so arbitrary sizes can't fill the caches with derivatives. Sizes
+/// in URLs, IIIF sizes and `/fetch` sizes must be allowed, sizes scaled by a pixel ratio or client
+/// hints are rounded to the nearest allowed one. Templates and tiles are always served.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct SizeSettings {
+    pub mode: SizeMode,
+    /// Sizes served with the `allowed` mode.
+    pub allowed: Vec<[u32; 2]>,
+    /// With the `allowed` mode, sizes whose sides are both multiples of the step are served too.
+    pub step: Option<u32>,
+    /// What to do with a size that isn't allowed.
+    pub mismatch: SizeMismatch,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+pub enum SizeMode {
+    /// Any size within the limits.
+    #[default]
+    #[serde(alias = "any")]
+    Any,
+    /// Only the `allowed` sizes and the multiples of `step`.
+    #[serde(alias = "allowed")]
+    Allowed,
+    /// No free-form sizes, images are resized by templates only.
+    #[serde(alias = "templates")]
+    Templates,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+pub enum SizeMismatch {
+    /// Answer with a 400.
+    #[default]
+    #[serde(alias = "reject")]
+    Reject,
+    /// Redirect to the nearest allowed size.
+    #[serde(alias = "redirect")]
+    Redirect,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DuplicateSettings {
+    /// Maximum Hamming distance between perceptual hashes for two images to be considered
+    /// near-duplicates.
+    pub threshold: u32,
+    pub action: DuplicateAction,
+}
+
+impl Default for DuplicateSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            action: DuplicateAction::Flag,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct BackgroundColor(pub u8, pub u8, pub u8);
+
+impl Default for BackgroundColor {
+    fn default() -> Self {
+        BackgroundColor(255, 255, 255)
+    }
+}
+
+impl FromStr for BackgroundColor {
+    type Err = anyhow::Error;
+
+    /// Parses `#rrggbb` or `#rgb`, the `#` is optional so colors fit in URLs.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+
+        let expanded: String = match hex.len() {
+            3 => hex.chars().flat_map(|c| [c, c]).collect(),
+            6 => hex.to_string(),
+            _ => return Err(anyhow!("invalid color: {:?}", s)),
+        };
+
+        let channel = |i: usize| {
+            u8::from_str_radix(&expanded[i..i + 2], 16)
+                .map_err(|_| anyhow!("invalid color: {:?}", s))
+        };
+
+        Ok(BackgroundColor(channel(0)?, channel(2)?, channel(4)?))
+    }
+}
+
+impl TryFrom<String> for BackgroundColor {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Frame kept when serving an animated image as a still one.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum FrameSelection {
+    /// Zero based frame index.
+    Index(u32),
+    /// The most detailed frame, which skips blank or faded intro frames.
+    Poster,
+}
+
+impl FromStr for FrameSelection {
+    type Err = anyhow::Error;
+
+    /// Parses a frame index or `poster`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "poster" => Ok(FrameSelection::Poster),
+            _ => s
+                .parse()
+                .map(FrameSelection::Index)
+                .map_err(|_| anyhow!("invalid frame: {:?}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for FrameSelection {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct JpegSettings {
+    /// Encodes in several scans so browsers can show a coarse version while downloading.
+    pub progressive: bool,
+    pub subsampling: ChromaSubsampling,
+    /// Builds Huffman tables for the image instead of using the standard ones.
+    pub optimize_huffman: bool,
+    /// Uses the quantization tables from mozjpeg instead of the ones from the JPEG spec.
+    pub tuned_quantization: bool,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+pub enum ChromaSubsampling {
+    /// Full resolution color, sharper edges on text and graphics.
+    #[serde(rename = "4:4:4", alias = "444")]
+    Yuv444,
+    /// Color at half resolution on both axes, smaller files for photos.
+    #[default]
+    #[serde(rename = "4:2:0", alias = "420")]
+    Yuv420,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+pub enum UpscalePolicy {
+    /// Refuse requests larger than the stored image.
+    #[serde(alias = "never")]
+    Never,
+    /// Resize past the stored image, within the maximum dimensions.
+    #[default]
+    #[serde(alias = "allowed")]
+    Allowed,
+    /// Serve the largest size the stored image has, keeping the requested aspect ratio.
+    #[serde(alias = "clamp")]
+    Clamp,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub enum DuplicateAction {
+    /// Store the image and record which master it duplicates.
+    #[serde(alias = "flag")]
+    Flag,
+    /// Refuse to store the image.
+    #[serde(alias = "reject")]
+    Reject,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct TemplateSettings {
     pub location: TemplateType,
     pub name: String,
     pub size: [u32; 2],
     pub format: ImageEncoding,
+    #[serde(default)]
+    pub encoder: EncoderSettings,
+    /// Serves a single frame of animated images, e.g. for thumbnails.
+    #[serde(default)]
+    pub frame: Option<FrameSelection>,
+    /// Pixel ratio the size is multiplied by, e.g. 2 for high density screens.
+    #[serde(default)]
+    pub dpr: Option<f32>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct EncoderSettings {
+    /// Encoder quality (1-100). PNG has no quality of its own: below 100 the image is reduced to
+    /// a palette of `quality * 256 / 100` colors, at least 2, so quality 50 keeps 128 colors.
+    pub quality: Option<u8>,
+    /// Size budget in kilobytes, the quality is lowered until the output fits. For PNG this
+    /// shrinks the palette. Formats without a quality are encoded once and may not fit.
+    pub max_kb: Option<u32>,
+    /// Color transparent pixels are blended with when the output format has no alpha.
+    pub background: BackgroundColor,
+    pub png: PngSettings,
+    pub jpeg: JpegSettings,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct PngSettings {
+    /// Reduces the image to a palette of at most 256 colors.
+    pub quantize: bool,
+    /// Minimum quality (0-100) the palette has to reach, otherwise the image is kept truecolor.
+    pub min_quality: u8,
+    /// Lossless optimization level (0-6) applied after encoding. Disabled when not set.
+    pub optimize: Option<u8>,
+}
+
+impl Default for PngSettings {
+    fn default() -> Self {
+        Self {
+            quantize: false,
+            min_quality: 70,
+            optimize: None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
@@ -68,7 +490,10 @@ mod tests {
     use std::{net::Ipv4Addr, path::PathBuf, str::FromStr};
 
     use crate::{
-        config::{Settings, TemplateType},
+        config::{
+            BackgroundColor, ChromaSubsampling, DecodeSettings, DuplicateAction, EncoderSettings,
+            FrameSelection, Settings, SizeMismatch, SizeMode, TemplateType, UpscalePolicy,
+        },
         ImageEncoding,
     };
 
@@ -119,6 +544,25 @@ mod tests {
             image_settings.output_path,
             PathBuf::from_str("/tmp/watch-out")?
         );
+        assert!(!image_settings.dominant_color_header);
+        assert_eq!(image_settings.duplicates.threshold, 5);
+        assert_eq!(image_settings.duplicates.action, DuplicateAction::Flag);
+        assert_eq!(image_settings.encoder, EncoderSettings::default());
+        assert!(image_settings.auto_transparency);
+        assert_eq!(image_settings.tiff_page, 0);
+        assert!(image_settings.jpeg_recompression);
+        assert_eq!(image_settings.pdf_dpi, 150);
+        assert!(!image_settings.client_hints.enabled);
+        assert_eq!(image_settings.max_width, 8192);
+        assert_eq!(image_settings.max_pixels, 40_000_000);
+        assert_eq!(image_settings.allow_upscale, UpscalePolicy::Allowed);
+        assert_eq!(image_settings.sizes.mode, SizeMode::Any);
+        assert_eq!(image_settings.decode, DecodeSettings::default());
+        assert!(!result.signing.enabled);
+        assert!(result.admin.tokens.is_empty());
+        assert!(!result.fetch.enabled);
+        assert_eq!(result.fetch.max_redirects, 3);
+        assert_eq!(image_settings.client_hints.save_data_quality, 50);
 
         // templates settings
 
@@ -207,4 +651,184 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_image_optional_settings() -> anyhow::Result<()> {
+        let valid_toml: &str = r#"
+            [server]
+            port = 8080
+            host = "192.168.1.1"
+
+            [image]
+            formats = ["png"]
+            storage_format = "png"
+            input_path = "/tmp/watch-in"
+            output_path = "/tmp/watch-out"
+            dominant_color_header = true
+            tiff_page = 1
+            pdf_dpi = 300
+            max_width = 4000
+            max_pixels = 1000000
+            allow_upscale = "clamp"
+
+            [image.duplicates]
+            threshold = 8
+            action = "reject"
+
+            [image.client_hints]
+            enabled = true
+            widths = [400, 800]
+
+            [image.decode]
+            max_input_bytes = 1048576
+            max_width = 4000
+
+            [image.sizes]
+            mode = "allowed"
+            allowed = [[320, 240], [640, 480]]
+            step = 100
+            mismatch = "redirect"
+
+            [image.encoder.png]
+            optimize = 2
+
+            [signing]
+            enabled = true
+
+            [[signing.keys]]
+            id = "2025"
+            secret = "new"
+
+            [[signing.keys]]
+            id = "2024"
+            secret = "old"
+
+            [admin]
+            tokens = ["admin-token"]
+
+            [fetch]
+            enabled = true
+            allowed_domains = ["images.example.com", "*.cdn.example.com"]
+            timeout = 5
+
+            [[templates]]
+            location = "prefix"
+            name = "icon"
+            size = [32, 32]
+            format = "png"
+            frame = "poster"
+            dpr = 2
+
+            [templates.encoder]
+            max_kb = 50
+
+            [templates.encoder.png]
+            quantize = true
+            min_quality = 60
+
+            [[templates]]
+            location = "suffix"
+            name = "photo"
+            size = [1280, 720]
+            format = "jpeg"
+
+            [templates.encoder]
+            background = "000"
+
+            [templates.encoder.jpeg]
+            progressive = true
+            subsampling = "4:4:4"
+            optimize_huffman = true
+        "#;
+        let result = toml::from_str::<Settings>(valid_toml)?;
+
+        assert!(result.image.dominant_color_header);
+        assert_eq!(result.image.tiff_page, 1);
+        assert_eq!(result.image.pdf_dpi, 300);
+        assert_eq!(result.image.max_width, 4000);
+        assert_eq!(result.image.max_height, 8192);
+        assert_eq!(result.image.max_pixels, 1_000_000);
+        assert_eq!(result.image.allow_upscale, UpscalePolicy::Clamp);
+        assert_eq!(result.image.sizes.mode, SizeMode::Allowed);
+        assert_eq!(result.image.sizes.allowed, vec![[320, 240], [640, 480]]);
+        assert_eq!(result.image.sizes.step, Some(100));
+        assert_eq!(result.image.sizes.mismatch, SizeMismatch::Redirect);
+        assert_eq!(result.image.decode.max_input_bytes, 1_048_576);
+        assert_eq!(result.image.decode.max_width, 4000);
+        assert_eq!(result.image.decode.max_height, 16384);
+        assert!(result.signing.enabled);
+        assert_eq!(
+            result.signing.current().map(|k| k.id.as_str()),
+            Some("2025")
+        );
+        assert_eq!(result.signing.secret("2024"), Some(&b"old"[..]));
+        assert_eq!(result.signing.secret("2023"), None);
+        assert_eq!(result.admin.tokens, vec!["admin-token"]);
+        assert!(result.fetch.enabled);
+        assert_eq!(result.fetch.allowed_domains.len(), 2);
+        assert_eq!(result.fetch.timeout, 5);
+        assert_eq!(result.fetch.max_bytes, 16 * 1024 * 1024);
+        assert_eq!(result.image.duplicates.threshold, 8);
+        assert_eq!(result.image.duplicates.action, DuplicateAction::Reject);
+        assert_eq!(result.image.client_hints.widths, vec![400, 800]);
+        assert!(result.image.client_hints.enabled);
+
+        let png = &result.image.encoder.png;
+        assert!(!png.quantize);
+        assert_eq!(png.optimize, Some(2));
+
+        assert_eq!(
+            result.templates.first().unwrap().frame,
+            Some(FrameSelection::Poster)
+        );
+        assert_eq!(result.templates.last().unwrap().frame, None);
+        assert_eq!(result.templates.first().unwrap().dpr, Some(2.0));
+        assert_eq!(result.templates.last().unwrap().dpr, None);
+
+        let encoder = &result.templates.first().unwrap().encoder;
+        assert_eq!(encoder.max_kb, Some(50));
+        assert_eq!(encoder.quality, None);
+
+        let png = &encoder.png;
+        assert!(png.quantize);
+        assert_eq!(png.min_quality, 60);
+        assert_eq!(png.optimize, None);
+
+        let encoder = &result.templates.last().unwrap().encoder;
+        assert_eq!(encoder.background, BackgroundColor(0, 0, 0));
+
+        let jpeg = &encoder.jpeg;
+        assert!(jpeg.progressive);
+        assert!(jpeg.optimize_huffman);
+        assert!(!jpeg.tuned_quantization);
+        assert_eq!(jpeg.subsampling, ChromaSubsampling::Yuv444);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_background_color_from_str() -> anyhow::Result<()> {
+        assert_eq!(
+            "#ff8000".parse::<BackgroundColor>()?,
+            BackgroundColor(255, 128, 0)
+        );
+        assert_eq!(
+            "0f0".parse::<BackgroundColor>()?,
+            BackgroundColor(0, 255, 0)
+        );
+        assert!("#ff80".parse::<BackgroundColor>().is_err());
+        assert!("zzzzzz".parse::<BackgroundColor>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_frame_selection_from_str() -> anyhow::Result<()> {
+        assert_eq!("poster".parse::<FrameSelection>()?, FrameSelection::Poster);
+        assert_eq!("3".parse::<FrameSelection>()?, FrameSelection::Index(3));
+        assert!("-1".parse::<FrameSelection>().is_err());
+        assert!("first".parse::<FrameSelection>().is_err());
+
+        Ok(())
+    }
 }
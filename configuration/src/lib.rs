@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 
 pub mod config;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Default, Clone, Copy)]
 pub enum ImageEncoding {
     #[serde(alias = "avif")]
     #[default]
@@ -11,14 +11,35 @@ pub enum ImageEncoding {
     JPEG,
     #[serde(alias = "png")]
     PNG,
+    #[serde(alias = "webp")]
+    WEBP,
+    #[serde(alias = "gif")]
+    GIF,
+    #[serde(alias = "jxl")]
+    JXL,
 }
 
 impl ImageEncoding {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "png" => Some(ImageEncoding::PNG),
+            "jpg" | "jpeg" => Some(ImageEncoding::JPEG),
+            "avif" => Some(ImageEncoding::AVIF),
+            "webp" => Some(ImageEncoding::WEBP),
+            "gif" => Some(ImageEncoding::GIF),
+                    "jxl" => Some(ImageEncoding::JXL),
+            _ => None,
+        }
+    }
+
     pub fn content_type(&self) -> &str {
         match self {
             ImageEncoding::AVIF => "image/avif",
             ImageEncoding::JPEG => "image/jpeg",
             ImageEncoding::PNG => "image/png",
+            ImageEncoding::WEBP => "image/webp",
+            ImageEncoding::GIF => "image/gif",
+                    ImageEncoding::JXL => "image/jxl",
         }
     }
     pub fn extension(&self) -> &str {
@@ -26,6 +47,9 @@ impl ImageEncoding {
             ImageEncoding::AVIF => ".avif",
             ImageEncoding::JPEG => ".jpg",
             ImageEncoding::PNG => ".png",
+            ImageEncoding::WEBP => ".webp",
+            ImageEncoding::GIF => ".gif",
+                    ImageEncoding::JXL => ".jxl",
         }
     }
 }
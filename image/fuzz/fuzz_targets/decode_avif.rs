@@ -0,0 +1,9 @@
+#![no_main]
+
+use image_processing::{limits, ImageFormat};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(init: image_processing_fuzz::init(), |data: &[u8]| {
+    let _ = image_processing::codecs::avif::dimensions(data);
+    let _ = limits::load(data, ImageFormat::Avif, &limits::current());
+});
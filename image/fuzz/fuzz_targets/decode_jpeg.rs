@@ -0,0 +1,8 @@
+#![no_main]
+
+use image_processing::{limits, ImageFormat};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(init: image_processing_fuzz::init(), |data: &[u8]| {
+    let _ = limits::load(data, ImageFormat::Jpeg, &limits::current());
+});
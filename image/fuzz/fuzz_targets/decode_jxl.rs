@@ -0,0 +1,8 @@
+#![no_main]
+
+use image_processing::codecs;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(init: image_processing_fuzz::init(), |data: &[u8]| {
+    let _ = codecs::jxl::decode(data);
+});
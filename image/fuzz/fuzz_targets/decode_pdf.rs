@@ -0,0 +1,9 @@
+#![no_main]
+
+use image_processing::pdf;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(init: image_processing_fuzz::init(), |data: &[u8]| {
+    let _ = pdf::page_count(data);
+    let _ = pdf::render_page(data, 1, 72);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use image_processing::{animation, limits, ImageFormat};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(init: image_processing_fuzz::init(), |data: &[u8]| {
+    let _ = limits::load(data, ImageFormat::Png, &limits::current());
+    let _ = animation::decode(data, ImageFormat::Png);
+});
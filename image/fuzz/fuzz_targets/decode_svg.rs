@@ -0,0 +1,9 @@
+#![no_main]
+
+use image_processing::svg;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(init: image_processing_fuzz::init(), |data: &[u8]| {
+    let _ = svg::decode(data);
+    let _ = svg::sanitize(data);
+});
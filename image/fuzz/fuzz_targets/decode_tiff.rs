@@ -0,0 +1,10 @@
+#![no_main]
+
+use image_processing::{limits, pages, ImageFormat};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(init: image_processing_fuzz::init(), |data: &[u8]| {
+    let _ = limits::load(data, ImageFormat::Tiff, &limits::current());
+    let _ = pages::tiff_page_count(data);
+    let _ = pages::decode_tiff_page(data, 1);
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use image_processing::{
+    transcoder::{Encoder, Transcoder},
+    ImageFormat,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(init: image_processing_fuzz::init(), |data: &[u8]| {
+    let _ = image_processing::dimensions(data);
+    let _ = Transcoder.transcode(data, String::new(), ImageFormat::Png.into(), None);
+});
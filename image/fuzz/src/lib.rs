@@ -0,0 +1,15 @@
+//! Setup shared by the fuzz targets.
+
+use configuration::config::DecodeSettings;
+use image_processing::limits;
+
+/// Tighter limits than the defaults, so the fuzzer spends its time in the decoders and not
+/// allocating the largest images they accept.
+pub fn init() {
+    limits::init(DecodeSettings {
+        max_input_bytes: 1024 * 1024,
+        max_width: 4096,
+        max_height: 4096,
+        max_alloc: 64 * 1024 * 1024,
+    });
+}
@@ -0,0 +1,123 @@
+use configuration::config::BackgroundColor;
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb, RgbImage};
+
+use crate::depth;
+
+/// Alpha values at or above `255 - ALPHA_TOLERANCE` count as opaque. Some encoders leave a few
+/// almost opaque pixels behind which are not worth switching formats for.
+const ALPHA_TOLERANCE: u8 = 4;
+
+/// Tells if `image` has pixels that are visibly transparent.
+pub fn has_transparency(image: &DynamicImage) -> bool {
+    if !image.color().has_alpha() {
+        return false;
+    }
+
+    image
+        .pixels()
+        .any(|(_, _, p)| p.0[3] < u8::MAX - ALPHA_TOLERANCE)
+}
+
+/// Decodes `image` and tells if it has visibly transparent pixels.
+pub fn has_transparency_bytes(image: &[u8]) -> anyhow::Result<bool> {
+    let image = crate::decode(image)?;
+
+    Ok(has_transparency(&image))
+}
+
+/// Alpha-composites `image` over `background`, for formats that can't store transparency.
+/// High bit depth images stay at 16 bits per channel.
+#[tracing::instrument(skip(image))]
+pub fn flatten(image: &DynamicImage, background: &BackgroundColor) -> DynamicImage {
+    if depth::is_high_depth(image) {
+        return flatten_16(image, background);
+    }
+
+    let rgba = image.to_rgba8();
+    let bg = [background.0, background.1, background.2];
+
+    let flat = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let p = rgba.get_pixel(x, y).0;
+        let alpha = p[3] as u32;
+
+        let blend =
+            |c: usize| ((p[c] as u32 * alpha + bg[c] as u32 * (255 - alpha) + 127) / 255) as u8;
+
+        Rgb([blend(0), blend(1), blend(2)])
+    });
+
+    DynamicImage::ImageRgb8(flat)
+}
+
+fn flatten_16(image: &DynamicImage, background: &BackgroundColor) -> DynamicImage {
+    let rgba = image.to_rgba16();
+    let bg = [background.0, background.1, background.2].map(|c| c as u64 * 257);
+    let max = u16::MAX as u64;
+
+    let flat = ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let p = rgba.get_pixel(x, y).0;
+        let alpha = p[3] as u64;
+
+        let blend =
+            |c: usize| ((p[c] as u64 * alpha + bg[c] * (max - alpha) + max / 2) / max) as u16;
+
+        Rgb([blend(0), blend(1), blend(2)])
+    });
+
+    DynamicImage::ImageRgb16(flat)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn half_transparent() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 4, |x, _| {
+            if x < 2 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        }))
+    }
+
+    #[test]
+    fn test_has_transparency() {
+        assert!(has_transparency(&half_transparent()));
+
+        let opaque = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([1, 2, 3, 253])));
+        assert!(!has_transparency(&opaque));
+
+        let rgb = DynamicImage::ImageRgb8(RgbImage::new(4, 4));
+        assert!(!has_transparency(&rgb));
+    }
+
+    #[test]
+    fn test_flatten() {
+        let flat = flatten(&half_transparent(), &BackgroundColor(0, 0, 255)).to_rgb8();
+
+        assert_eq!(flat.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(flat.get_pixel(3, 0), &Rgb([0, 0, 255]));
+
+        let semi = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 128])));
+        let flat = flatten(&semi, &BackgroundColor(255, 255, 255)).to_rgb8();
+
+        assert_eq!(flat.get_pixel(0, 0), &Rgb([127, 127, 127]));
+    }
+
+    #[test]
+    fn test_flatten_keeps_high_depth() {
+        let semi = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(
+            1,
+            1,
+            Rgba([1000u16, 2000, 3000, u16::MAX / 2]),
+        ));
+
+        let flat = flatten(&semi, &BackgroundColor(0, 0, 0));
+
+        assert_eq!(flat.color(), image::ColorType::Rgb16);
+        assert_eq!(flat.to_rgb16().get_pixel(0, 0), &Rgb([500, 1000, 1500]));
+    }
+}
@@ -0,0 +1,348 @@
+use std::io::Cursor;
+
+use anyhow::bail;
+use configuration::config::FrameSelection;
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        png::PngDecoder,
+        webp::WebPDecoder,
+    },
+    guess_format, AnimationDecoder, DynamicImage, Frame, Frames, ImageDecoder, ImageFormat,
+};
+
+use crate::limits;
+
+/// Side of the thumbnail used to score frames when picking a poster.
+const POSTER_SAMPLE_SIZE: u32 = 32;
+
+/// Tells if `target` can store several frames.
+pub fn supports_animation(target: ImageFormat) -> bool {
+    matches!(
+        target,
+        ImageFormat::Gif | ImageFormat::Png | ImageFormat::WebP
+    )
+}
+
+/// Tells if `image` is a GIF, APNG or WebP with more than one frame.
+pub fn is_animated(image: &[u8]) -> anyhow::Result<bool> {
+    let Ok(format) = guess_format(image) else {
+        return Ok(false);
+    };
+
+    match frames(image, format)? {
+        Some(frames) => Ok(frames.take(2).count() > 1),
+        None => Ok(false),
+    }
+}
+
+/// Decodes every frame of an animated image, composited to the full canvas. Returns `None` for
+/// formats without animation and for single frame images.
+#[tracing::instrument(skip(image))]
+pub fn decode(image: &[u8], format: ImageFormat) -> anyhow::Result<Option<Vec<Frame>>> {
+    let Some(frames) = frames(image, format)? else {
+        return Ok(None);
+    };
+
+    // each frame is within the limits, but not necessarily all of them
+    let limits = limits::current();
+    let mut decoded = Vec::new();
+    let mut allocated = 0;
+
+    for frame in frames {
+        let frame = frame?;
+
+        allocated += frame.buffer().len() as u64;
+        limits::check_allocation(allocated, &limits)?;
+
+        decoded.push(frame);
+    }
+
+    Ok(Some(decoded).filter(|f| f.len() > 1))
+}
+
+fn frames(image: &[u8], format: ImageFormat) -> anyhow::Result<Option<Frames<'_>>> {
+    let cursor = Cursor::new(image);
+    let limits = limits::image_limits(&limits::current());
+
+    let frames = match format {
+        ImageFormat::Gif => {
+            let mut decoder = GifDecoder::new(cursor)?;
+            decoder.set_limits(limits)?;
+
+            decoder.into_frames()
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::with_limits(cursor, limits)?;
+
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+
+            decoder.apng()?.into_frames()
+        }
+        ImageFormat::WebP => {
+            let mut decoder = WebPDecoder::new(cursor)?;
+            decoder.set_limits(limits)?;
+
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(frames))
+}
+
+/// Picks the frame described by `selection`.
+pub fn select(frames: Vec<Frame>, selection: FrameSelection) -> anyhow::Result<DynamicImage> {
+    let index = match selection {
+        FrameSelection::Index(i) => i as usize,
+        FrameSelection::Poster => poster(&frames),
+    };
+
+    let count = frames.len();
+
+    match frames.into_iter().nth(index) {
+        Some(frame) => Ok(DynamicImage::ImageRgba8(frame.into_buffer())),
+        None => bail!("frame {} out of range, image has {} frames", index, count),
+    }
+}
+
+/// Index of the frame with the most detail, measured as the luma variance of a small thumbnail.
+/// Animations often start with a blank or faded frame which makes a poor still.
+pub fn poster(frames: &[Frame]) -> usize {
+    let score = |frame: &Frame| {
+        let luma = DynamicImage::ImageRgba8(frame.buffer().clone())
+            .thumbnail(POSTER_SAMPLE_SIZE, POSTER_SAMPLE_SIZE)
+            .to_luma8();
+
+        let count = luma.len().max(1) as f64;
+        let mean = luma.iter().map(|v| *v as f64).sum::<f64>() / count;
+
+        luma.iter().map(|v| (*v as f64 - mean).powi(2)).sum::<f64>() / count
+    };
+
+    frames
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (i, score(f)))
+        .fold((0, f64::MIN), |best, current| {
+            if current.1 > best.1 {
+                current
+            } else {
+                best
+            }
+        })
+        .0
+}
+
+/// Encodes `frames` as an animation looping forever.
+#[tracing::instrument(skip(frames))]
+pub fn encode(frames: Vec<Frame>, target: ImageFormat) -> anyhow::Result<Vec<u8>> {
+    match target {
+        ImageFormat::Gif => encode_gif(frames),
+        ImageFormat::Png => encode_apng(&frames),
+        ImageFormat::WebP => encode_webp(&frames),
+        _ => bail!("{:?} can't store animations", target),
+    }
+}
+
+fn encode_gif(frames: Vec<Frame>) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+
+    {
+        let mut encoder = GifEncoder::new(&mut bytes);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames)?;
+    }
+
+    Ok(bytes)
+}
+
+fn encode_apng(frames: &[Frame]) -> anyhow::Result<Vec<u8>> {
+    let (width, height) = canvas_size(frames)?;
+    let mut bytes = Vec::new();
+
+    let mut encoder = png::Encoder::new(&mut bytes, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0)?;
+
+    let mut writer = encoder.write_header()?;
+
+    for frame in frames {
+        writer.set_frame_delay(delay_ms(frame).min(u16::MAX as u32) as u16, 1000)?;
+        writer.write_image_data(frame.buffer().as_raw())?;
+    }
+
+    writer.finish()?;
+
+    Ok(bytes)
+}
+
+/// The image-rs WebP encoder only writes still images, so every frame is encoded on its own and
+/// its bitstream is wrapped in an `ANMF` chunk of an extended WebP container.
+fn encode_webp(frames: &[Frame]) -> anyhow::Result<Vec<u8>> {
+    let (width, height) = canvas_size(frames)?;
+
+    let transparent = frames
+        .iter()
+        .any(|f| f.buffer().pixels().any(|p| p.0[3] != u8::MAX));
+
+    let mut body = b"WEBP".to_vec();
+
+    // VP8X: animation flag, plus alpha when any frame is transparent
+    let mut vp8x = vec![if transparent { 0x12 } else { 0x02 }, 0, 0, 0];
+    vp8x.extend(u24(width - 1));
+    vp8x.extend(u24(height - 1));
+    write_chunk(&mut body, b"VP8X", &vp8x);
+
+    // ANIM: transparent background, loop forever
+    write_chunk(&mut body, b"ANIM", &[0, 0, 0, 0, 0, 0]);
+
+    for frame in frames {
+        let mut still = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(frame.buffer().clone()).write_to(&mut still, ImageFormat::WebP)?;
+
+        let mut anmf = Vec::new();
+        anmf.extend(u24(0));
+        anmf.extend(u24(0));
+        anmf.extend(u24(frame.buffer().width() - 1));
+        anmf.extend(u24(frame.buffer().height() - 1));
+        anmf.extend(u24(delay_ms(frame)));
+        // frames are full canvases, overwrite instead of blending
+        anmf.push(0x02);
+        anmf.extend(bitstream_chunks(still.get_ref())?);
+
+        write_chunk(&mut body, b"ANMF", &anmf);
+    }
+
+    let mut bytes = b"RIFF".to_vec();
+    bytes.extend((body.len() as u32).to_le_bytes());
+    bytes.extend(body);
+
+    Ok(bytes)
+}
+
+/// Returns the `ALPH`, `VP8 ` and `VP8L` chunks of a still WebP file, as they are.
+fn bitstream_chunks(webp: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut chunks = Vec::new();
+    let mut position = 12;
+
+    while position + 8 <= webp.len() {
+        let fourcc = &webp[position..position + 4];
+        let size = u32::from_le_bytes(webp[position + 4..position + 8].try_into()?) as usize;
+        let end = position + 8 + size + size % 2;
+
+        if end > webp.len() {
+            bail!("truncated WebP chunk {:?}", String::from_utf8_lossy(fourcc));
+        }
+
+        if matches!(fourcc, b"ALPH" | b"VP8 " | b"VP8L") {
+            chunks.extend_from_slice(&webp[position..end]);
+        }
+
+        position = end;
+    }
+
+    Ok(chunks)
+}
+
+fn write_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend((payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+
+    if payload.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn u24(value: u32) -> [u8; 3] {
+    let [a, b, c, _] = value.to_le_bytes();
+    [a, b, c]
+}
+
+fn delay_ms(frame: &Frame) -> u32 {
+    let (numerator, denominator) = frame.delay().numer_denom_ms();
+
+    numerator / denominator.max(1)
+}
+
+fn canvas_size(frames: &[Frame]) -> anyhow::Result<(u32, u32)> {
+    match frames.first() {
+        Some(frame) => Ok(frame.buffer().dimensions()),
+        None => bail!("animation has no frames"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Delay, Rgba, RgbaImage};
+
+    use super::*;
+
+    /// Three frames: a blank one, a checkerboard and a flat red one.
+    fn frames() -> Vec<Frame> {
+        let delay = Delay::from_numer_denom_ms(100, 1);
+
+        vec![
+            RgbaImage::from_pixel(16, 12, Rgba([0, 0, 0, 255])),
+            RgbaImage::from_fn(16, 12, |x, y| {
+                if (x + y) % 2 == 0 {
+                    Rgba([255, 255, 255, 255])
+                } else {
+                    Rgba([0, 0, 0, 255])
+                }
+            }),
+            RgbaImage::from_pixel(16, 12, Rgba([255, 0, 0, 255])),
+        ]
+        .into_iter()
+        .map(|buffer| Frame::from_parts(buffer, 0, 0, delay))
+        .collect()
+    }
+
+    #[test]
+    fn test_round_trip() -> anyhow::Result<()> {
+        for format in [ImageFormat::Gif, ImageFormat::Png, ImageFormat::WebP] {
+            let bytes = encode(frames(), format)?;
+
+            assert!(is_animated(&bytes)?, "{:?} is not animated", format);
+
+            let decoded = decode(&bytes, format)?.unwrap();
+
+            assert_eq!(decoded.len(), 3, "{:?}", format);
+            assert_eq!(decoded[2].buffer().dimensions(), (16, 12));
+            assert_eq!(decoded[2].buffer().get_pixel(4, 4), &Rgba([255, 0, 0, 255]));
+            assert_eq!(delay_ms(&decoded[1]), 100, "{:?}", format);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_still_images_are_not_animated() -> anyhow::Result<()> {
+        let mut png = Cursor::new(Vec::new());
+        RgbaImage::new(4, 4).write_to(&mut png, ImageFormat::Png)?;
+
+        assert!(!is_animated(png.get_ref())?);
+        assert!(decode(png.get_ref(), ImageFormat::Png)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_select() -> anyhow::Result<()> {
+        let red = select(frames(), FrameSelection::Index(2))?.to_rgba8();
+        assert_eq!(red.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+
+        assert_eq!(poster(&frames()), 1);
+        assert!(select(frames(), FrameSelection::Index(3)).is_err());
+
+        Ok(())
+    }
+}
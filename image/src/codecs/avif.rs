@@ -0,0 +1,195 @@
+use std::io::Cursor;
+
+use image::{codecs::avif::AvifEncoder, DynamicImage};
+use ravif::{BitDepth, MatrixCoefficients, PixelRange};
+
+use crate::depth;
+
+/// Speed used by the AVIF encoder, same default as image-rs.
+const AVIF_SPEED: u8 = 4;
+/// Quality used when none is requested, same as the image-rs encoder.
+const DEFAULT_QUALITY: u8 = 80;
+
+/// Luma coefficients for red, green and blue, the same matrix ravif uses for 8 bits input.
+const BT601: [f32; 3] = [0.2990, 0.5870, 0.1140];
+const TEN_BIT_MAX: f32 = 1023.0;
+
+/// Largest size declared by the `ispe` properties of an AVIF file, read without decoding it.
+/// image-rs runs the whole decode to learn it. `None` when a box on the way overruns its
+/// parent, the parser image-rs uses allocates the size such boxes declare.
+pub fn dimensions(image: &[u8]) -> Option<(u32, u32)> {
+    // `meta` is a full box, its children follow the version and flags
+    let meta = find_box(&boxes(image)?, b"meta")?.get(4..)?;
+    let iprp = find_box(&boxes(meta)?, b"iprp")?;
+    let ipco = boxes(find_box(&boxes(iprp)?, b"ipco")?)?;
+
+    ipco.iter()
+        .filter(|(kind, _)| kind == b"ispe")
+        .filter_map(|(_, content)| {
+            let width = u32::from_be_bytes(content.get(4..8)?.try_into().ok()?);
+            let height = u32::from_be_bytes(content.get(8..12)?.try_into().ok()?);
+
+            Some((width, height))
+        })
+        .reduce(|a, b| (a.0.max(b.0), a.1.max(b.1)))
+}
+
+fn find_box<'a>(boxes: &[([u8; 4], &'a [u8])], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes
+        .iter()
+        .find(|(k, _)| k == kind)
+        .map(|(_, content)| *content)
+}
+
+/// Boxes of an ISO BMFF container, as their type and content. `None` unless they fill it
+/// exactly.
+fn boxes(mut data: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    let mut boxes = Vec::new();
+
+    while !data.is_empty() {
+        let size = u32::from_be_bytes(data.get(0..4)?.try_into().ok()?) as u64;
+        let kind: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+
+        let (header, size) = match size {
+            0 => (8, data.len() as u64),
+            1 => (16, u64::from_be_bytes(data.get(8..16)?.try_into().ok()?)),
+            size => (8, size),
+        };
+
+        if size < header as u64 || size > data.len() as u64 {
+            return None;
+        }
+
+        boxes.push((kind, &data[header..size as usize]));
+        data = &data[size as usize..];
+    }
+
+    Some(boxes)
+}
+
+/// Encodes `image` as AVIF. 8 bits images go through the image-rs encoder, high bit depth ones
+/// are written as 10 bits so gradients keep their precision.
+#[tracing::instrument(skip(image))]
+pub fn encode(image: &DynamicImage, quality: Option<u8>) -> anyhow::Result<Vec<u8>> {
+    let quality = quality.unwrap_or(DEFAULT_QUALITY).clamp(1, 100);
+
+    if !depth::is_high_depth(image) {
+        let mut cursor = Cursor::new(Vec::new());
+        image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+            &mut cursor,
+            AVIF_SPEED,
+            quality,
+        ))?;
+
+        return Ok(cursor.into_inner());
+    }
+
+    encode_ten_bit(image, quality)
+}
+
+fn encode_ten_bit(image: &DynamicImage, quality: u8) -> anyhow::Result<Vec<u8>> {
+    let rgba = image.to_rgba16();
+    let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+
+    let encoder = ravif::Encoder::new()
+        .with_quality(quality as f32)
+        .with_alpha_quality(quality as f32)
+        .with_speed(AVIF_SPEED)
+        .with_bit_depth(BitDepth::Ten);
+
+    let planes = rgba.pixels().map(|p| ycbcr(p.0[0], p.0[1], p.0[2]));
+
+    let alpha = image
+        .color()
+        .has_alpha()
+        .then(|| rgba.pixels().map(|p| to_ten_bit(p.0[3])))
+        .filter(|_| rgba.pixels().any(|p| p.0[3] != u16::MAX));
+
+    let encoded = encoder.encode_raw_planes_10_bit(
+        width,
+        height,
+        planes,
+        alpha,
+        PixelRange::Full,
+        MatrixCoefficients::BT601,
+    )?;
+
+    Ok(encoded.avif_file)
+}
+
+fn to_ten_bit(value: u16) -> u16 {
+    (value as f32 * TEN_BIT_MAX / u16::MAX as f32).round() as u16
+}
+
+/// Full range 10 bits Y, Cb and Cr from 16 bits RGB.
+fn ycbcr(r: u16, g: u16, b: u16) -> [u16; 3] {
+    let scale = TEN_BIT_MAX / u16::MAX as f32;
+    let (r, g, b) = (r as f32 * scale, g as f32 * scale, b as f32 * scale);
+
+    let y = BT601[0] * r + BT601[1] * g + BT601[2] * b;
+    let cb = (b - y) * 0.5 / (1.0 - BT601[2]) + 512.0;
+    let cr = (r - y) * 0.5 / (1.0 - BT601[0]) + 512.0;
+
+    [y, cb, cr].map(|v| v.round().clamp(0.0, TEN_BIT_MAX) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, ImageFormat, Rgb};
+
+    use super::*;
+
+    fn sky() -> DynamicImage {
+        DynamicImage::ImageRgb16(ImageBuffer::from_fn(64, 64, |x, y| {
+            Rgb([20000 + x as u16 * 40, 30000 + y as u16 * 40, 50000])
+        }))
+    }
+
+    #[test]
+    fn test_high_depth_keeps_ten_bits() -> anyhow::Result<()> {
+        let bytes = encode(&sky(), Some(90))?;
+        let decoded = image::load_from_memory_with_format(&bytes, ImageFormat::Avif)?;
+
+        assert!(depth::is_high_depth(&decoded));
+        assert_eq!(decoded.width(), 64);
+
+        // same colors as the 8 bits encoder, the decoder shifts both the same way
+        let eight_bit = encode(&DynamicImage::ImageRgb8(sky().to_rgb8()), Some(90))?;
+        let expected = image::load_from_memory(&eight_bit)?.to_rgb16();
+        let decoded = decoded.to_rgb16();
+
+        for c in 0..3 {
+            let a = expected.get_pixel(10, 10).0[c] as i32;
+            let b = decoded.get_pixel(10, 10).0[c] as i32;
+            assert!((a - b).abs() < 2 * 257, "channel {}: {} vs {}", c, a, b);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ycbcr() {
+        assert_eq!(ycbcr(0, 0, 0), [0, 512, 512]);
+        assert_eq!(ycbcr(u16::MAX, u16::MAX, u16::MAX), [1023, 512, 512]);
+    }
+
+    #[test]
+    fn test_dimensions_from_header() -> anyhow::Result<()> {
+        let bytes = encode(&sky(), Some(60))?;
+        assert_eq!(dimensions(&bytes), Some((64, 64)));
+
+        let eight_bit = encode(&DynamicImage::ImageRgb8(sky().to_rgb8()), Some(60))?;
+        assert_eq!(dimensions(&eight_bit), Some((64, 64)));
+
+        assert_eq!(dimensions(&bytes[..bytes.len().min(24)]), None);
+        assert_eq!(dimensions(b"not an avif"), None);
+
+        // a property declaring more bytes than the file has
+        let ispe = bytes.windows(4).position(|w| w == b"ispe").unwrap() - 4;
+        let mut overrun = bytes.clone();
+        overrun[ispe..ispe + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(dimensions(&overrun), None);
+
+        Ok(())
+    }
+}
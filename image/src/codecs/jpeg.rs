@@ -0,0 +1,115 @@
+use crate::depth;
+use configuration::config::{ChromaSubsampling, JpegSettings};
+use image::DynamicImage;
+use jpeg_encoder::{ColorType, QuantizationTableType, SamplingFactor};
+
+/// Quality used when none is requested, same as the image-rs encoder.
+const DEFAULT_QUALITY: u8 = 75;
+
+#[tracing::instrument(skip(image))]
+pub fn encode(
+    image: &DynamicImage,
+    settings: &JpegSettings,
+    quality: Option<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    let width = u16::try_from(image.width())?;
+    let height = u16::try_from(image.height())?;
+
+    let mut bytes = Vec::new();
+
+    let mut encoder =
+        jpeg_encoder::Encoder::new(&mut bytes, quality.unwrap_or(DEFAULT_QUALITY).clamp(1, 100));
+
+    encoder.set_progressive(settings.progressive);
+    encoder.set_optimized_huffman_tables(settings.optimize_huffman);
+    encoder.set_sampling_factor(match settings.subsampling {
+        ChromaSubsampling::Yuv444 => SamplingFactor::R_4_4_4,
+        ChromaSubsampling::Yuv420 => SamplingFactor::R_4_2_0,
+    });
+
+    if settings.tuned_quantization {
+        // same tables mozjpeg uses by default
+        encoder.set_quantization_tables(
+            QuantizationTableType::ImageMagick,
+            QuantizationTableType::ImageMagick,
+        );
+    }
+
+    encoder.encode(
+        depth::to_rgb8(image).as_raw(),
+        width,
+        height,
+        ColorType::Rgb,
+    )?;
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageFormat, Rgb, RgbImage};
+
+    use super::*;
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(128, 96, |x, y| {
+            Rgb([(x * 2) as u8, (y * 2) as u8, ((x + y) % 256) as u8])
+        }))
+    }
+
+    /// Start of frame marker for baseline (0xC0) or progressive (0xC2) JPEGs.
+    fn start_of_frame(bytes: &[u8]) -> Option<u8> {
+        bytes
+            .windows(2)
+            .find(|w| w[0] == 0xFF && (w[1] == 0xC0 || w[1] == 0xC2))
+            .map(|w| w[1])
+    }
+
+    #[test]
+    fn test_encode_baseline() -> anyhow::Result<()> {
+        let bytes = encode(&gradient(), &JpegSettings::default(), None)?;
+
+        assert_eq!(start_of_frame(&bytes), Some(0xC0));
+
+        let decoded = image::load_from_memory_with_format(&bytes, ImageFormat::Jpeg)?;
+        assert_eq!(decoded.width(), 128);
+        assert_eq!(decoded.height(), 96);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_progressive() -> anyhow::Result<()> {
+        let settings = JpegSettings {
+            progressive: true,
+            optimize_huffman: true,
+            subsampling: ChromaSubsampling::Yuv444,
+            tuned_quantization: true,
+        };
+
+        let bytes = encode(&gradient(), &settings, Some(80))?;
+
+        assert_eq!(start_of_frame(&bytes), Some(0xC2));
+
+        let decoded = image::load_from_memory_with_format(&bytes, ImageFormat::Jpeg)?;
+        assert_eq!(decoded.width(), 128);
+        assert_eq!(decoded.height(), 96);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimized_huffman_is_smaller() -> anyhow::Result<()> {
+        let optimized = JpegSettings {
+            optimize_huffman: true,
+            ..Default::default()
+        };
+
+        let default = encode(&gradient(), &JpegSettings::default(), Some(80))?;
+        let smaller = encode(&gradient(), &optimized, Some(80))?;
+
+        assert!(smaller.len() < default.len());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+//! JPEG XL through libjxl, built from source with the `jpegxl` feature. image-rs has no JPEG XL
+//! support, so images are converted from and to `DynamicImage` here. Without the feature JPEG XL
+//! files are still recognized, but decoding and encoding them fails.
+
+use anyhow::anyhow;
+use image::DynamicImage;
+#[cfg(feature = "jpegxl")]
+use jpegxl_rs::{
+    decode::Data, decoder_builder, encode::EncoderFrame, encoder_builder, image::ToDynamic,
+};
+
+/// Start of a bare JPEG XL codestream.
+const CODESTREAM_SIGNATURE: [u8; 2] = [0xFF, 0x0A];
+/// Start of a JPEG XL file using the ISO BMFF container, needed to keep JPEG reconstruction data.
+const CONTAINER_SIGNATURE: [u8; 12] = [
+    0, 0, 0, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+];
+
+/// Tells if `image` is a JPEG XL file.
+pub fn is_jxl(image: &[u8]) -> bool {
+    image.starts_with(&CODESTREAM_SIGNATURE) || image.starts_with(&CONTAINER_SIGNATURE)
+}
+
+/// Tells if JPEG XL files can be decoded and encoded, the `jpegxl` feature is enabled.
+pub const fn is_supported() -> bool {
+    cfg!(feature = "jpegxl")
+}
+
+#[cfg(feature = "jpegxl")]
+#[tracing::instrument(skip(image))]
+pub fn decode(image: &[u8]) -> anyhow::Result<DynamicImage> {
+    crate::limits::check_input(image, &crate::limits::current())?;
+
+    let decoder = decoder_builder().build()?;
+
+    decoder
+        .decode_to_image(image)?
+        .ok_or_else(|| anyhow!("JPEG XL image has no RGB(A) or grayscale equivalent"))
+}
+
+/// Encodes `image` with the same quality scale as cjxl. Quality 100 is lossless, `None` uses the
+/// libjxl default (visually lossless). 16 bits images keep their depth.
+#[cfg(feature = "jpegxl")]
+#[tracing::instrument(skip(image))]
+pub fn encode(image: &DynamicImage, quality: Option<u8>) -> anyhow::Result<Vec<u8>> {
+    let has_alpha = image.color().has_alpha();
+
+    let mut encoder = encoder_builder()
+        .has_alpha(has_alpha)
+        .lossless(quality == Some(100))
+        .quality(quality.map(distance).unwrap_or(1.0))
+        .build()?;
+
+    let (width, height) = (image.width(), image.height());
+
+    // libjxl takes the pixels with their own depth, so 16 bits sources are not truncated
+    let bytes = match (crate::depth::is_high_depth(image), has_alpha) {
+        (true, true) => encoder.encode_frame(
+            &EncoderFrame::new(image.to_rgba16().as_raw()).num_channels(4),
+            width,
+            height,
+        )?,
+        (true, false) => encoder.encode(image.to_rgb16().as_raw(), width, height)?,
+        (false, true) => encoder.encode_frame(
+            &EncoderFrame::new(image.to_rgba8().as_raw()).num_channels(4),
+            width,
+            height,
+        )?,
+        (false, false) => encoder.encode(image.to_rgb8().as_raw(), width, height)?,
+    };
+
+    Ok(bytes)
+}
+
+/// Losslessly recompresses a JPEG file, usually ~20% smaller. The original file can be
+/// rebuilt bit for bit with [`reconstruct_jpeg`].
+#[cfg(feature = "jpegxl")]
+#[tracing::instrument(skip(jpeg))]
+pub fn recompress_jpeg(jpeg: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = encoder_builder()
+        .use_container(true)
+        .uses_original_profile(true)
+        .build()?;
+
+    Ok(encoder.encode_jpeg(jpeg)?)
+}
+
+/// Rebuilds the original JPEG of a file made by [`recompress_jpeg`]. Returns `None` when the
+/// file has no JPEG reconstruction data.
+#[cfg(feature = "jpegxl")]
+#[tracing::instrument(skip(image))]
+pub fn reconstruct_jpeg(image: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let decoder = decoder_builder().build()?;
+
+    match decoder.reconstruct(image)? {
+        (_, Data::Jpeg(jpeg)) => Ok(Some(jpeg)),
+        (_, Data::Pixels(_)) => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "jpegxl"))]
+pub fn decode(_image: &[u8]) -> anyhow::Result<DynamicImage> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "jpegxl"))]
+pub fn encode(_image: &DynamicImage, _quality: Option<u8>) -> anyhow::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "jpegxl"))]
+pub fn recompress_jpeg(_jpeg: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "jpegxl"))]
+pub fn reconstruct_jpeg(_image: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "jpegxl"))]
+fn unsupported() -> anyhow::Error {
+    anyhow!("JPEG XL needs the jpegxl feature")
+}
+
+/// Butteraugli distance for a JPEG-like quality, same mapping as cjxl.
+#[cfg(feature = "jpegxl")]
+fn distance(quality: u8) -> f32 {
+    let quality = quality as f32;
+
+    if quality >= 30.0 {
+        0.1 + (100.0 - quality) * 0.09
+    } else {
+        53.0 / 3000.0 * quality * quality - 23.0 / 20.0 * quality + 25.0
+    }
+}
+
+#[cfg(all(test, feature = "jpegxl"))]
+mod tests {
+    use std::{env, fs, path::Path};
+
+    use image::{ImageBuffer, Rgb};
+
+    use super::*;
+
+    fn get_jpeg_image() -> Vec<u8> {
+        let s = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = Path::new(&s).join("../resources/100x100.jpg");
+
+        fs::read(p).unwrap()
+    }
+
+    #[test]
+    fn test_jpeg_recompression_is_bit_exact() -> anyhow::Result<()> {
+        let jpeg = get_jpeg_image();
+        let jxl = recompress_jpeg(&jpeg)?;
+
+        assert!(is_jxl(&jxl));
+        assert!(jxl.len() < jpeg.len());
+        assert_eq!(reconstruct_jpeg(&jxl)?, Some(jpeg));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> anyhow::Result<()> {
+        let sky = DynamicImage::ImageRgb16(ImageBuffer::from_fn(32, 32, |x, _| {
+            Rgb([x as u16 * 1000, 20000, 40000])
+        }));
+
+        let lossless = decode(&encode(&sky, Some(100))?)?;
+        assert_eq!(lossless.to_rgb16(), sky.to_rgb16());
+
+        let lossy = encode(&sky, Some(50))?;
+        assert_eq!(reconstruct_jpeg(&lossy)?, None);
+        assert_eq!(decode(&lossy)?.width(), 32);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_distance() {
+        assert!((distance(90) - 1.0).abs() < 0.01);
+        assert!(distance(10) > distance(50));
+        assert!((distance(100) - 0.1).abs() < 0.01);
+    }
+}
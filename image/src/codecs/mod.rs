@@ -0,0 +1,6 @@
+//! Encoders used instead of the image-rs defaults when extra options are configured.
+
+pub mod avif;
+pub mod jpeg;
+pub mod jxl;
+pub mod png;
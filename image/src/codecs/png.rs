@@ -0,0 +1,263 @@
+use std::{collections::HashMap, io::Cursor};
+
+use color_quant::NeuQuant;
+use configuration::config::PngSettings;
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use tracing::debug;
+
+use crate::depth;
+
+/// Sampling factor for NeuQuant, 1 is the slowest and most accurate and 30 the fastest.
+const SAMPLE_FACTOR: i32 = 10;
+const MAX_COLORS: usize = 256;
+
+/// PSNR (in dB) mapped to quality 0 and 100 respectively.
+const MIN_PSNR: f64 = 20.0;
+const MAX_PSNR: f64 = 50.0;
+
+/// Encodes `image` as PNG. An explicit `quality` below 100 always quantizes, using a palette
+/// size proportional to the quality and ignoring `min_quality`.
+#[tracing::instrument(skip(image))]
+pub fn encode(
+    image: &DynamicImage,
+    settings: &PngSettings,
+    quality: Option<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    let quantized = match quality {
+        Some(q) if q < 100 => {
+            let colors = (q as usize * MAX_COLORS / 100).max(2);
+            quantize(&depth::to_rgba8(image), colors, 0)?
+        }
+        Some(_) => None,
+        None if settings.quantize => {
+            quantize(&depth::to_rgba8(image), MAX_COLORS, settings.min_quality)?
+        }
+        None => None,
+    };
+
+    let mut encoded = match quantized {
+        Some(bytes) => bytes,
+        None => {
+            let mut cursor = Cursor::new(Vec::new());
+            image.write_to(&mut cursor, ImageFormat::Png)?;
+            cursor.into_inner()
+        }
+    };
+
+    if let Some(level) = settings.optimize {
+        let options = oxipng::Options::from_preset(level.min(6));
+        encoded = oxipng::optimize_from_memory(&encoded, &options)?;
+    }
+
+    Ok(encoded)
+}
+
+/// Encodes `image` as an indexed PNG. Returns `None` when the palette can't reach `min_quality`.
+fn quantize(image: &RgbaImage, colors: usize, min_quality: u8) -> anyhow::Result<Option<Vec<u8>>> {
+    let (palette, indices) = match exact_palette(image, colors) {
+        Some(exact) => exact,
+        None => {
+            let quant = NeuQuant::new(SAMPLE_FACTOR, colors, image.as_raw());
+            let indices: Vec<u8> = image.pixels().map(|p| quant.index_of(&p.0) as u8).collect();
+            let palette = quant
+                .color_map_rgba()
+                .chunks_exact(4)
+                .map(|c| [c[0], c[1], c[2], c[3]])
+                .collect();
+
+            (palette, indices)
+        }
+    };
+
+    let quality = quality(image, &palette, &indices);
+
+    if quality < min_quality {
+        debug!(
+            "palette quality {} is below {}, keeping truecolor",
+            quality, min_quality
+        );
+        return Ok(None);
+    }
+
+    let mut bytes = Vec::new();
+
+    let mut encoder = png::Encoder::new(&mut bytes, image.width(), image.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(
+        palette
+            .iter()
+            .flat_map(|c| [c[0], c[1], c[2]])
+            .collect::<Vec<_>>(),
+    );
+
+    if palette.iter().any(|c| c[3] != u8::MAX) {
+        encoder.set_trns(palette.iter().map(|c| c[3]).collect::<Vec<_>>());
+    }
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&indices)?;
+    writer.finish()?;
+
+    Ok(Some(bytes))
+}
+
+/// Builds a palette with the exact colors of `image` if it doesn't have more than `colors` of them.
+fn exact_palette(image: &RgbaImage, colors: usize) -> Option<(Vec<[u8; 4]>, Vec<u8>)> {
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut positions: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut indices = Vec::with_capacity((image.width() * image.height()) as usize);
+
+    for pixel in image.pixels() {
+        let index = match positions.get(&pixel.0) {
+            Some(i) => *i,
+            None => {
+                if palette.len() == colors {
+                    return None;
+                }
+
+                let i = palette.len() as u8;
+                palette.push(pixel.0);
+                positions.insert(pixel.0, i);
+                i
+            }
+        };
+
+        indices.push(index);
+    }
+
+    Some((palette, indices))
+}
+
+/// Maps the PSNR between the original pixels and their palette colors to a 0-100 quality.
+fn quality(image: &RgbaImage, palette: &[[u8; 4]], indices: &[u8]) -> u8 {
+    let squared_error: f64 = image
+        .pixels()
+        .zip(indices)
+        .flat_map(|(p, i)| {
+            let c = palette[*i as usize];
+            (0..4).map(move |ch| (p.0[ch] as f64 - c[ch] as f64).powi(2))
+        })
+        .sum();
+
+    let mse = squared_error / (indices.len().max(1) * 4) as f64;
+
+    if mse == 0.0 {
+        return 100;
+    }
+
+    let psnr = 10.0 * (255.0f64.powi(2) / mse).log10();
+
+    ((psnr - MIN_PSNR) / (MAX_PSNR - MIN_PSNR) * 100.0).clamp(0.0, 100.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8, 255])
+        }))
+    }
+
+    fn icon() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| {
+            if (x / 8 + y / 8) % 2 == 0 {
+                Rgba([200, 30, 30, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        }))
+    }
+
+    #[test]
+    fn test_quantize_exact_palette_is_lossless() -> anyhow::Result<()> {
+        let settings = PngSettings {
+            quantize: true,
+            min_quality: 100,
+            optimize: None,
+        };
+
+        let original = icon();
+        let bytes = encode(&original, &settings, None)?;
+        let decoded = image::load_from_memory_with_format(&bytes, ImageFormat::Png)?;
+
+        assert_eq!(decoded.to_rgba8(), original.to_rgba8());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantize_is_smaller() -> anyhow::Result<()> {
+        let truecolor = encode(&gradient(), &PngSettings::default(), None)?;
+        let quantized = encode(
+            &gradient(),
+            &PngSettings {
+                quantize: true,
+                min_quality: 0,
+                optimize: None,
+            },
+            None,
+        )?;
+
+        assert!(quantized.len() < truecolor.len());
+
+        let decoded = image::load_from_memory_with_format(&quantized, ImageFormat::Png)?;
+        assert_eq!(decoded.width(), 64);
+        assert_eq!(decoded.height(), 64);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantize_below_min_quality_keeps_truecolor() -> anyhow::Result<()> {
+        let settings = PngSettings {
+            quantize: true,
+            min_quality: 100,
+            optimize: None,
+        };
+
+        let bytes = encode(&gradient(), &settings, None)?;
+        let decoded = image::load_from_memory_with_format(&bytes, ImageFormat::Png)?;
+
+        assert_eq!(decoded.to_rgba8(), gradient().to_rgba8());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimize_is_lossless() -> anyhow::Result<()> {
+        let settings = PngSettings {
+            quantize: false,
+            min_quality: 0,
+            optimize: Some(2),
+        };
+
+        let optimized = encode(&icon(), &settings, None)?;
+        let default = encode(&icon(), &PngSettings::default(), None)?;
+
+        assert!(optimized.len() <= default.len());
+
+        let decoded = image::load_from_memory_with_format(&optimized, ImageFormat::Png)?;
+        assert_eq!(decoded.to_rgba8(), icon().to_rgba8());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quality_reduces_palette() -> anyhow::Result<()> {
+        let bytes = encode(&gradient(), &PngSettings::default(), Some(2))?;
+        let decoded = image::load_from_memory_with_format(&bytes, ImageFormat::Png)?.to_rgba8();
+
+        let mut colors: Vec<_> = decoded.pixels().map(|p| p.0).collect();
+        colors.sort();
+        colors.dedup();
+
+        assert!(colors.len() <= 5);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+use image::{DynamicImage, GenericImageView, Rgba};
+use serde::{Serialize, Serializer};
+
+/// Largest side used when sampling pixels. Quantization does not need every pixel of the
+/// source, so images are downscaled before building the color boxes.
+const SAMPLE_SIZE: u32 = 64;
+
+/// Pixels with an alpha value below this threshold are ignored when sampling.
+const MIN_ALPHA: u8 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color(u8, u8, u8);
+
+impl Color {
+    pub fn new(red: u8, green: u8, blue: u8) -> Self {
+        Color(red, green, blue)
+    }
+
+    pub fn red(&self) -> &u8 {
+        &self.0
+    }
+
+    pub fn green(&self) -> &u8 {
+        &self.1
+    }
+
+    pub fn blue(&self) -> &u8 {
+        &self.2
+    }
+
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.hex())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Swatch {
+    pub color: Color,
+    /// Share of the sampled pixels represented by this color, between 0 and 1.
+    pub population: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Palette {
+    pub dominant: Color,
+    pub colors: Vec<Swatch>,
+}
+
+/// Decodes `image` and extracts a palette of at most `size` colors using median cut.
+pub fn palette_from_bytes(image: &[u8], size: usize) -> anyhow::Result<Palette> {
+    let image = crate::decode(image)?;
+
+    Ok(extract_palette(&image, size))
+}
+
+/// Decodes `image` and returns its dominant color.
+pub fn dominant_color(image: &[u8]) -> anyhow::Result<Color> {
+    Ok(palette_from_bytes(image, 1)?.dominant)
+}
+
+#[tracing::instrument(skip(image))]
+pub fn extract_palette(image: &DynamicImage, size: usize) -> Palette {
+    let pixels = sample_pixels(image);
+    let total = pixels.len().max(1) as f32;
+
+    let mut boxes = vec![ColorBox::new(pixels)];
+
+    while boxes.len() < size.max(1) {
+        let candidate = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.pixels.len() > 1)
+            .max_by_key(|(_, b)| b.widest_channel().1);
+
+        match candidate {
+            Some((idx, b)) if b.widest_channel().1 > 0 => {
+                let (left, right) = boxes.swap_remove(idx).split();
+                boxes.push(left);
+                boxes.push(right);
+            }
+            _ => break,
+        }
+    }
+
+    let mut colors: Vec<Swatch> = boxes
+        .iter()
+        .filter(|b| !b.pixels.is_empty())
+        .map(|b| Swatch {
+            color: b.average(),
+            population: b.pixels.len() as f32 / total,
+        })
+        .collect();
+
+    colors.sort_by(|a, b| b.population.total_cmp(&a.population));
+
+    let dominant = colors.first().map(|s| s.color).unwrap_or(Color(0, 0, 0));
+
+    Palette { dominant, colors }
+}
+
+fn sample_pixels(image: &DynamicImage) -> Vec<[u8; 3]> {
+    let sample = if image.width() > SAMPLE_SIZE || image.height() > SAMPLE_SIZE {
+        image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE)
+    } else {
+        image.clone()
+    };
+
+    let opaque: Vec<[u8; 3]> = sample
+        .pixels()
+        .filter(|(_, _, Rgba(p))| p[3] >= MIN_ALPHA)
+        .map(|(_, _, Rgba(p))| [p[0], p[1], p[2]])
+        .collect();
+
+    // a fully transparent image still has a color underneath, use it instead of nothing
+    if opaque.is_empty() {
+        return sample
+            .pixels()
+            .map(|(_, _, Rgba(p))| [p[0], p[1], p[2]])
+            .collect();
+    }
+
+    opaque
+}
+
+#[derive(Debug)]
+struct ColorBox {
+    pixels: Vec<[u8; 3]>,
+}
+
+impl ColorBox {
+    fn new(pixels: Vec<[u8; 3]>) -> Self {
+        Self { pixels }
+    }
+
+    /// Returns the channel index with the widest value range and that range.
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..3)
+            .map(|c| {
+                let min = self.pixels.iter().map(|p| p[c]).min().unwrap_or(0);
+                let max = self.pixels.iter().map(|p| p[c]).max().unwrap_or(0);
+                (c, max - min)
+            })
+            .max_by_key(|(_, range)| *range)
+            .unwrap_or((0, 0))
+    }
+
+    fn split(mut self) -> (ColorBox, ColorBox) {
+        let (channel, _) = self.widest_channel();
+        self.pixels.sort_unstable_by_key(|p| p[channel]);
+
+        // cut next to the median without separating pixels that share the same value, otherwise
+        // a large flat area ends up averaged with whatever sits on the other side of the cut
+        let median = self.pixels[self.pixels.len() / 2][channel];
+        let upper = self.pixels.partition_point(|p| p[channel] <= median);
+        let cut = if upper < self.pixels.len() {
+            upper
+        } else {
+            self.pixels.partition_point(|p| p[channel] < median)
+        };
+
+        let right = self.pixels.split_off(cut);
+
+        (ColorBox::new(self.pixels), ColorBox::new(right))
+    }
+
+    fn average(&self) -> Color {
+        let len = self.pixels.len().max(1) as u64;
+        let sum = self.pixels.iter().fold([0u64; 3], |acc, p| {
+            [
+                acc[0] + p[0] as u64,
+                acc[1] + p[1] as u64,
+                acc[2] + p[2] as u64,
+            ]
+        });
+
+        Color(
+            (sum[0] / len) as u8,
+            (sum[1] / len) as u8,
+            (sum[2] / len) as u8,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn two_color_image() -> DynamicImage {
+        // 3/4 red, 1/4 blue
+        let img = RgbaImage::from_fn(40, 40, |x, _| {
+            if x < 30 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 255, 255])
+            }
+        });
+
+        DynamicImage::ImageRgba8(img)
+    }
+
+    #[test]
+    fn test_dominant_color() {
+        let palette = extract_palette(&two_color_image(), 2);
+
+        assert_eq!(palette.dominant, Color::new(255, 0, 0));
+        assert_eq!(palette.colors.len(), 2);
+        assert_eq!(palette.colors[1].color, Color::new(0, 0, 255));
+        assert!((palette.colors[0].population - 0.75).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_palette_ignores_transparent_pixels() {
+        let img = RgbaImage::from_fn(10, 10, |x, _| {
+            if x < 8 {
+                Rgba([0, 255, 0, 0])
+            } else {
+                Rgba([10, 20, 30, 255])
+            }
+        });
+
+        let palette = extract_palette(&DynamicImage::ImageRgba8(img), 4);
+
+        assert_eq!(palette.dominant, Color::new(10, 20, 30));
+        assert_eq!(palette.colors.len(), 1);
+    }
+
+    #[test]
+    fn test_palette_from_bytes() -> anyhow::Result<()> {
+        let s = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let bytes = std::fs::read(std::path::Path::new(&s).join("../resources/100x100.jpg"))?;
+
+        let palette = palette_from_bytes(&bytes, 5)?;
+
+        assert!(!palette.colors.is_empty());
+        assert!(palette.colors.len() <= 5);
+        assert_eq!(palette.dominant, palette.colors[0].color);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_color_hex() {
+        assert_eq!(Color::new(255, 8, 171).hex(), "#ff08ab");
+    }
+}
@@ -0,0 +1,141 @@
+use image::{DynamicImage, ImageBuffer, Pixel, Rgb, RgbImage, Rgba, RgbaImage};
+
+/// 8x8 Bayer matrix used for ordered dithering. Unlike error diffusion every pixel is
+/// independent, so the same source always produces the same output and resized crops of an
+/// image get the same pattern.
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Tells if `image` stores more than 8 bits per channel (16 bits integer or 32 bits float).
+pub fn is_high_depth(image: &DynamicImage) -> bool {
+    let color = image.color();
+
+    color.bytes_per_pixel() > color.channel_count()
+}
+
+/// Converts `image` to 8 bits RGB. High bit depth sources are dithered so smooth gradients
+/// don't turn into visible bands.
+pub fn to_rgb8(image: &DynamicImage) -> RgbImage {
+    if !is_high_depth(image) {
+        return image.to_rgb8();
+    }
+
+    dither(&image.to_rgb16(), |c| Rgb([c[0], c[1], c[2]]))
+}
+
+/// Same as [`to_rgb8`], keeping the alpha channel. Alpha is rounded, not dithered.
+pub fn to_rgba8(image: &DynamicImage) -> RgbaImage {
+    if !is_high_depth(image) {
+        return image.to_rgba8();
+    }
+
+    dither(&image.to_rgba16(), |c| Rgba([c[0], c[1], c[2], c[3]]))
+}
+
+/// Converts `image` to the 8 bits variant of its color type, dithering high depth sources.
+pub fn to_8_bit(image: &DynamicImage) -> DynamicImage {
+    if !is_high_depth(image) {
+        return image.clone();
+    }
+
+    if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(to_rgba8(image))
+    } else {
+        DynamicImage::ImageRgb8(to_rgb8(image))
+    }
+}
+
+fn dither<P, Q>(
+    image: &ImageBuffer<P, Vec<u16>>,
+    pixel: impl Fn([u8; 4]) -> Q,
+) -> ImageBuffer<Q, Vec<u8>>
+where
+    P: Pixel<Subpixel = u16>,
+    Q: Pixel<Subpixel = u8>,
+{
+    let channels = P::CHANNEL_COUNT as usize;
+    let has_alpha = channels == 4;
+
+    ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+        let p = image.get_pixel(x, y).channels();
+        let threshold = (BAYER[(y % 8) as usize][(x % 8) as usize] as f32 + 0.5) / 64.0;
+
+        let mut out = [0u8; 4];
+
+        for (c, value) in p.iter().enumerate().take(channels) {
+            let scaled = *value as f32 * 255.0 / 65535.0;
+
+            out[c] = if has_alpha && c == 3 {
+                scaled.round() as u8
+            } else {
+                (scaled + threshold).floor().min(255.0) as u8
+            };
+        }
+
+        pixel(out)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb};
+
+    use super::*;
+
+    /// Horizontal ramp covering only four 8 bits steps over 256 pixels, like a clear sky.
+    fn sky() -> DynamicImage {
+        DynamicImage::ImageRgb16(ImageBuffer::from_fn(256, 8, |x, _| {
+            let v = 30000 + (x * 4 * 257 / 256) as u16;
+            Rgb([v, v, v])
+        }))
+    }
+
+    #[test]
+    fn test_is_high_depth() {
+        assert!(is_high_depth(&sky()));
+        assert!(!is_high_depth(&DynamicImage::ImageRgb8(RgbImage::new(
+            1, 1
+        ))));
+        assert!(is_high_depth(&DynamicImage::ImageRgba32F(
+            image::Rgba32FImage::new(1, 1)
+        )));
+    }
+
+    #[test]
+    fn test_dither_keeps_average_level() {
+        let source = sky().to_rgb16();
+        let dithered = to_rgb8(&sky());
+
+        for x in (0..256).step_by(32) {
+            // the average of a dithered 8x8 block matches the 16 bits one
+            let block = |value: &dyn Fn(u32, u32) -> f32| {
+                (0..8)
+                    .flat_map(|dy| (0..8).map(move |dx| (x + dx, dy)))
+                    .map(|(px, py)| value(px, py))
+                    .sum::<f32>()
+                    / 64.0
+            };
+
+            let expected = block(&|px, py| source.get_pixel(px, py).0[0] as f32 * 255.0 / 65535.0);
+            let block = block(&|px, py| dithered.get_pixel(px, py).0[0] as f32);
+
+            assert!((block - expected).abs() < 0.1, "{} vs {}", block, expected);
+        }
+    }
+
+    #[test]
+    fn test_eight_bit_is_untouched() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([1, 2, 3, 4])));
+
+        assert_eq!(to_rgba8(&image), image.to_rgba8());
+        assert_eq!(to_8_bit(&image), image);
+    }
+}
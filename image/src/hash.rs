@@ -0,0 +1,97 @@
+use image::{imageops::FilterType, DynamicImage};
+
+/// Side of the grid compared by the difference hash, one bit per cell.
+const HASH_SIZE: u32 = 8;
+
+/// Decodes `image` and computes its difference hash (dHash).
+pub fn perceptual_hash(image: &[u8]) -> anyhow::Result<u64> {
+    let image = crate::decode(image)?;
+
+    Ok(dhash(&image))
+}
+
+/// Computes a 64 bits difference hash. Each bit tells if a cell of a 9x8 grayscale version of the
+/// image is brighter than its right neighbour, which survives rescaling, re-encoding and small
+/// color adjustments.
+#[tracing::instrument(skip(image))]
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image
+        .resize_exact(HASH_SIZE + 1, HASH_SIZE, FilterType::Triangle)
+        .to_luma8();
+
+    let mut hash = 0u64;
+
+    for y in 0..HASH_SIZE {
+        for x in 0..HASH_SIZE {
+            let left = small.get_pixel(x, y).0[0];
+            let right = small.get_pixel(x + 1, y).0[0];
+
+            hash = (hash << 1) | (left > right) as u64;
+        }
+    }
+
+    hash
+}
+
+/// Number of differing bits between two hashes. Zero means identical, anything under ~10 is
+/// very likely the same picture.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io::Cursor, path::Path};
+
+    use image::ImageFormat;
+
+    use super::*;
+
+    fn get_avif_image() -> Vec<u8> {
+        let s = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = Path::new(&s).join("../resources/fox.avif");
+
+        fs::read(p).unwrap()
+    }
+
+    fn get_jpeg_image() -> Vec<u8> {
+        let s = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = Path::new(&s).join("../resources/another_big.jpeg");
+
+        fs::read(p).unwrap()
+    }
+
+    #[test]
+    fn test_hash_survives_resize_and_reencoding() -> anyhow::Result<()> {
+        let original = image::load_from_memory(&get_avif_image())?;
+
+        let mut resized = Cursor::new(Vec::new());
+        original
+            .resize_exact(301, 200, FilterType::CatmullRom)
+            .to_rgb8()
+            .write_to(&mut resized, ImageFormat::Jpeg)?;
+
+        let distance = hamming_distance(dhash(&original), perceptual_hash(resized.get_ref())?);
+
+        assert!(distance <= 4, "distance was {}", distance);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_different_images() -> anyhow::Result<()> {
+        let fox = perceptual_hash(&get_avif_image())?;
+        let other = perceptual_hash(&get_jpeg_image())?;
+
+        assert!(hamming_distance(fox, other) > 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hamming_distance() {
+        assert_eq!(hamming_distance(0, 0), 0);
+        assert_eq!(hamming_distance(0b1011, 0b0001), 2);
+        assert_eq!(hamming_distance(u64::MAX, 0), 64);
+    }
+}
@@ -1,2 +1,111 @@
+pub mod alpha;
+pub mod animation;
+pub mod codecs;
+pub mod color;
+pub mod depth;
+pub mod hash;
+pub mod limits;
+pub mod pages;
+#[cfg(feature = "pdf")]
+pub mod pdf;
+pub mod svg;
+pub mod tiles;
 pub mod transcoder;
-pub use image::ImageFormat;
+pub use image::{guess_format, ImageFormat};
+
+use std::io::Cursor;
+
+use configuration::ImageEncoding;
+use image::DynamicImage;
+
+/// Format written by the transcoder: one of image-rs or one encoded by a codec image-rs lacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Image(ImageFormat),
+    /// Needs the `jpegxl` feature, encoding fails without it.
+    JpegXl,
+}
+
+impl From<ImageFormat> for OutputFormat {
+    fn from(format: ImageFormat) -> Self {
+        OutputFormat::Image(format)
+    }
+}
+
+/// Format used to encode `encoding`.
+pub fn image_format(encoding: &ImageEncoding) -> OutputFormat {
+    match encoding {
+        ImageEncoding::AVIF => ImageFormat::Avif.into(),
+        ImageEncoding::JPEG => ImageFormat::Jpeg.into(),
+        ImageEncoding::PNG => ImageFormat::Png.into(),
+        ImageEncoding::WEBP => ImageFormat::WebP.into(),
+        ImageEncoding::GIF => ImageFormat::Gif.into(),
+        ImageEncoding::JXL => OutputFormat::JpegXl,
+    }
+}
+
+/// Tells if `encoding` can be encoded and decoded by this build. JPEG XL needs the `jpegxl`
+/// feature.
+pub fn is_supported(encoding: &ImageEncoding) -> bool {
+    match encoding {
+        ImageEncoding::JXL => codecs::jxl::is_supported(),
+        _ => true,
+    }
+}
+
+/// Encoding of `image`, detected from its content. `None` for formats that are not served.
+pub fn detect_encoding(image: &[u8]) -> Option<ImageEncoding> {
+    if codecs::jxl::is_jxl(image) {
+        return Some(ImageEncoding::JXL);
+    }
+
+    match guess_format(image).ok()? {
+        ImageFormat::Avif => Some(ImageEncoding::AVIF),
+        ImageFormat::Jpeg => Some(ImageEncoding::JPEG),
+        ImageFormat::Png => Some(ImageEncoding::PNG),
+        ImageFormat::WebP => Some(ImageEncoding::WEBP),
+        ImageFormat::Gif => Some(ImageEncoding::GIF),
+        _ => None,
+    }
+}
+
+/// Decodes `image`, detecting its format from the content, within the decode limits.
+pub fn decode(image: &[u8]) -> anyhow::Result<DynamicImage> {
+    let limits = limits::current();
+    limits::check_input(image, &limits)?;
+
+    if codecs::jxl::is_jxl(image) {
+        return codecs::jxl::decode(image);
+    }
+
+    if svg::is_svg(image) {
+        return svg::decode(image);
+    }
+
+    #[cfg(feature = "pdf")]
+    if pdf::is_pdf(image) {
+        return pdf::render_page(image, 1, configuration::config::DEFAULT_PDF_DPI);
+    }
+
+    let format = guess_format(image)?;
+
+    limits::load(image, format, &limits)
+}
+
+/// Width and height of `image`, read from the header when image-rs knows the format. AVIF
+/// headers are read here, image-rs decodes the whole image to learn its size.
+pub fn dimensions(image: &[u8]) -> anyhow::Result<(u32, u32)> {
+    match guess_format(image) {
+        Ok(ImageFormat::Avif) => {
+            codecs::avif::dimensions(image).ok_or_else(|| anyhow::anyhow!("AVIF image has no size"))
+        }
+        Ok(format) => {
+            Ok(image::ImageReader::with_format(Cursor::new(image), format).into_dimensions()?)
+        }
+        Err(_) => {
+            let image = decode(image)?;
+
+            Ok((image.width(), image.height()))
+        }
+    }
+}
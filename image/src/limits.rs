@@ -0,0 +1,215 @@
+//! Limits on untrusted input. A small file can declare a huge image, a decompression bomb, so
+//! sizes are checked before decoders allocate the pixels.
+
+use std::{fmt, io::Cursor, sync::OnceLock};
+
+use anyhow::anyhow;
+use configuration::config::DecodeSettings;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use tracing::warn;
+
+use crate::codecs;
+
+/// Size of a decoded pixel used to estimate allocations, 8 bits RGBA.
+const BYTES_PER_PIXEL: u64 = 4;
+
+static LIMITS: OnceLock<DecodeSettings> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    /// Size of the input, in bytes.
+    InputTooLarge(u64),
+    /// Declared width and height.
+    TooLarge(u32, u32),
+    /// Bytes needed to decode the image.
+    Allocation(u64),
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::InputTooLarge(bytes) => {
+                write!(f, "input of {} bytes is past the decode limits", bytes)
+            }
+            LimitError::TooLarge(width, height) => {
+                write!(f, "{}x{} image is past the decode limits", width, height)
+            }
+            LimitError::Allocation(bytes) => {
+                write!(f, "decoding needs {} bytes, past the decode limits", bytes)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+/// Sets the limits of every decode, once at startup. The defaults apply until then.
+pub fn init(settings: DecodeSettings) {
+    if LIMITS.set(settings).is_err() {
+        warn!("decode limits are already set, keeping them");
+    }
+}
+
+/// Limits set with [`init`], or the defaults.
+pub fn current() -> DecodeSettings {
+    LIMITS.get().copied().unwrap_or_default()
+}
+
+pub fn check_input(image: &[u8], limits: &DecodeSettings) -> Result<(), LimitError> {
+    if image.len() as u64 > limits.max_input_bytes {
+        return Err(LimitError::InputTooLarge(image.len() as u64));
+    }
+
+    Ok(())
+}
+
+pub fn check_dimensions(
+    width: u32,
+    height: u32,
+    limits: &DecodeSettings,
+) -> Result<(), LimitError> {
+    if width > limits.max_width || height > limits.max_height {
+        return Err(LimitError::TooLarge(width, height));
+    }
+
+    check_allocation(width as u64 * height as u64 * BYTES_PER_PIXEL, limits)
+}
+
+pub fn check_allocation(bytes: u64, limits: &DecodeSettings) -> Result<(), LimitError> {
+    if bytes > limits.max_alloc {
+        return Err(LimitError::Allocation(bytes));
+    }
+
+    Ok(())
+}
+
+/// image-rs limits matching `limits`.
+pub fn image_limits(limits: &DecodeSettings) -> Limits {
+    let mut image_limits = Limits::default();
+    image_limits.max_image_width = Some(limits.max_width);
+    image_limits.max_image_height = Some(limits.max_height);
+    image_limits.max_alloc = Some(limits.max_alloc);
+
+    image_limits
+}
+
+/// Decodes `image` in `format` with image-rs, within `limits`.
+pub fn load(
+    image: &[u8],
+    format: ImageFormat,
+    limits: &DecodeSettings,
+) -> anyhow::Result<DynamicImage> {
+    check_input(image, limits)?;
+
+    // image-rs decodes the whole AVIF image before checking its limits
+    if format == ImageFormat::Avif {
+        let (width, height) =
+            codecs::avif::dimensions(image).ok_or_else(|| anyhow!("AVIF image has no size"))?;
+
+        check_dimensions(width, height, limits)?;
+    }
+
+    let mut reader = ImageReader::with_format(Cursor::new(image), format);
+    reader.limits(image_limits(limits));
+
+    Ok(reader.decode()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path};
+
+    use image::ImageError;
+
+    use super::*;
+
+    fn resource(name: &str) -> Vec<u8> {
+        let s = env::var("CARGO_MANIFEST_DIR").unwrap();
+
+        fs::read(Path::new(&s).join("../resources").join(name)).unwrap()
+    }
+
+    /// PNG declaring `width` x `height` pixels without any data, a few dozen bytes.
+    fn bomb(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+
+        encoder.write_header().unwrap().finish().unwrap();
+
+        bytes
+    }
+
+    #[test]
+    fn test_checks() {
+        let limits = DecodeSettings::default();
+
+        assert_eq!(check_input(&[0; 16], &limits), Ok(()));
+        assert_eq!(check_dimensions(4000, 4000, &limits), Ok(()));
+        assert_eq!(
+            check_dimensions(20000, 10, &limits),
+            Err(LimitError::TooLarge(20000, 10))
+        );
+        // within both sides but not the memory
+        assert_eq!(
+            check_dimensions(16000, 16000, &limits),
+            Err(LimitError::Allocation(16000 * 16000 * 4))
+        );
+
+        let small = DecodeSettings {
+            max_input_bytes: 8,
+            ..limits
+        };
+        assert_eq!(
+            check_input(&[0; 16], &small),
+            Err(LimitError::InputTooLarge(16))
+        );
+    }
+
+    #[test]
+    fn test_load_within_limits() -> anyhow::Result<()> {
+        let limits = DecodeSettings::default();
+
+        let image = load(&resource("100x100.jpg"), ImageFormat::Jpeg, &limits)?;
+        assert_eq!((image.width(), image.height()), (100, 100));
+
+        let image = load(&resource("fox.avif"), ImageFormat::Avif, &limits)?;
+        assert_eq!((image.width(), image.height()), (1204, 800));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_refuses_bombs() {
+        let limits = DecodeSettings {
+            max_width: 1000,
+            max_height: 1000,
+            ..Default::default()
+        };
+
+        let bomb = bomb(100_000, 100_000);
+        assert!(bomb.len() < 100);
+
+        let error = load(&bomb, ImageFormat::Png, &limits).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ImageError>(),
+            Some(ImageError::Limits(_))
+        ));
+
+        let error = load(&resource("fox.avif"), ImageFormat::Avif, &limits).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LimitError>(),
+            Some(&LimitError::TooLarge(1204, 800))
+        );
+
+        let small = DecodeSettings {
+            max_input_bytes: 100,
+            ..limits
+        };
+        let error = load(&resource("100x100.jpg"), ImageFormat::Jpeg, &small).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LimitError>(),
+            Some(&LimitError::InputTooLarge(750))
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::io::Cursor;
+
+use anyhow::bail;
+use configuration::config::DecodeSettings;
+use image::{DynamicImage, GrayAlphaImage, GrayImage, ImageBuffer, RgbImage, RgbaImage};
+use tiff::{
+    decoder::{Decoder, DecodingResult, Limits},
+    ColorType,
+};
+
+use crate::limits;
+
+/// TIFF decoder reading `image` within the decode limits.
+fn decoder<'a>(
+    image: &'a [u8],
+    limits: &DecodeSettings,
+) -> anyhow::Result<Decoder<Cursor<&'a [u8]>>> {
+    limits::check_input(image, limits)?;
+
+    let mut tiff_limits = Limits::default();
+    tiff_limits.decoding_buffer_size = limits.max_alloc as usize;
+
+    Ok(Decoder::new(Cursor::new(image))?.with_limits(tiff_limits))
+}
+
+/// Number of pages (image file directories) in a TIFF file.
+pub fn tiff_page_count(image: &[u8]) -> anyhow::Result<usize> {
+    let mut decoder = decoder(image, &limits::current())?;
+    let mut count = 1;
+
+    while decoder.more_images() {
+        decoder.next_image()?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Decodes the zero based `page` of a multi-page TIFF, e.g. from a scanner. image-rs only reads
+/// the first one.
+#[tracing::instrument(skip(image))]
+pub fn decode_tiff_page(image: &[u8], page: usize) -> anyhow::Result<DynamicImage> {
+    let limits = limits::current();
+    let mut decoder = decoder(image, &limits)?;
+
+    if let Err(e) = decoder.seek_to_image(page) {
+        bail!(
+            "page {} out of range, TIFF has {} pages: {}",
+            page,
+            tiff_page_count(image)?,
+            e
+        );
+    }
+
+    let (width, height) = decoder.dimensions()?;
+    limits::check_dimensions(width, height, &limits)?;
+
+    let color = decoder.colortype()?;
+
+    let decoded = match (decoder.read_image()?, color) {
+        (DecodingResult::U8(data), ColorType::Gray(8)) => {
+            GrayImage::from_raw(width, height, data).map(DynamicImage::ImageLuma8)
+        }
+        (DecodingResult::U8(data), ColorType::GrayA(8)) => {
+            GrayAlphaImage::from_raw(width, height, data).map(DynamicImage::ImageLumaA8)
+        }
+        (DecodingResult::U8(data), ColorType::RGB(8)) => {
+            RgbImage::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        }
+        (DecodingResult::U8(data), ColorType::RGBA(8)) => {
+            RgbaImage::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        }
+        (DecodingResult::U16(data), ColorType::Gray(16)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma16)
+        }
+        (DecodingResult::U16(data), ColorType::GrayA(16)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA16)
+        }
+        (DecodingResult::U16(data), ColorType::RGB(16)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16)
+        }
+        (DecodingResult::U16(data), ColorType::RGBA(16)) => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16)
+        }
+        (_, color) => bail!("unsupported TIFF color type {:?} on page {}", color, page),
+    };
+
+    match decoded {
+        Some(image) => Ok(image),
+        None => bail!(
+            "TIFF page {} is smaller than its {}x{} size",
+            page,
+            width,
+            height
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    use super::*;
+
+    fn two_pages() -> anyhow::Result<Vec<u8>> {
+        let mut bytes = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut bytes)?;
+
+        encoder.write_image::<colortype::RGB8>(4, 2, &[10; 4 * 2 * 3])?;
+        encoder.write_image::<colortype::Gray16>(3, 3, &[1000; 9])?;
+
+        Ok(bytes.into_inner())
+    }
+
+    #[test]
+    fn test_tiff_pages() -> anyhow::Result<()> {
+        let tiff = two_pages()?;
+
+        assert_eq!(tiff_page_count(&tiff)?, 2);
+
+        let first = decode_tiff_page(&tiff, 0)?;
+        assert_eq!((first.width(), first.height()), (4, 2));
+        assert_eq!(first.to_rgb8().get_pixel(0, 0).0, [10, 10, 10]);
+
+        let second = decode_tiff_page(&tiff, 1)?;
+        assert_eq!(second.color(), image::ColorType::L16);
+        assert_eq!(second.width(), 3);
+
+        assert!(decode_tiff_page(&tiff, 2).is_err());
+
+        Ok(())
+    }
+}
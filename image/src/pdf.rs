@@ -0,0 +1,119 @@
+//! PDF pages rendered with hayro, a pure Rust rasterizer. The standard fonts are embedded in the
+//! crate, so nothing is fetched or loaded from the system.
+
+use anyhow::{anyhow, bail};
+use hayro::{
+    hayro_interpret::InterpreterSettings, hayro_syntax::Pdf, vello_cpu::color::palette::css::WHITE,
+    PixmapSettings, RenderCache, RenderSettings,
+};
+use image::{DynamicImage, Rgb, RgbImage};
+
+use crate::limits;
+
+/// Highest resolution rendered, an A4 page is already ~5000x7000 pixels at 600 DPI.
+const MAX_DPI: u32 = 600;
+/// PDF sizes are in points.
+const POINTS_PER_INCH: f32 = 72.0;
+
+/// Tells if `document` is a PDF file.
+pub fn is_pdf(document: &[u8]) -> bool {
+    document.starts_with(b"%PDF-")
+}
+
+fn open(document: &[u8]) -> anyhow::Result<Pdf> {
+    limits::check_input(document, &limits::current())?;
+
+    Pdf::new(document.to_vec()).map_err(|e| anyhow!("invalid PDF: {:?}", e))
+}
+
+pub fn page_count(document: &[u8]) -> anyhow::Result<usize> {
+    Ok(open(document)?.pages().len())
+}
+
+/// Renders the one based `page` of `document` on a white background at `dpi` dots per inch.
+#[tracing::instrument(skip(document))]
+pub fn render_page(document: &[u8], page: u32, dpi: u32) -> anyhow::Result<DynamicImage> {
+    let pdf = open(document)?;
+    let pages = pdf.pages();
+
+    let Some(selected) = (page as usize).checked_sub(1).and_then(|i| pages.get(i)) else {
+        bail!("page {} out of range, PDF has {} pages", page, pages.len());
+    };
+
+    let scale = dpi.clamp(1, MAX_DPI) as f32 / POINTS_PER_INCH;
+    let (width, height) = selected.render_dimensions();
+
+    if width * scale > u16::MAX as f32 || height * scale > u16::MAX as f32 {
+        bail!("page {} is too large to render at {} DPI", page, dpi);
+    }
+
+    limits::check_dimensions(
+        (width * scale).ceil() as u32,
+        (height * scale).ceil() as u32,
+        &limits::current(),
+    )?;
+
+    let pixmap = hayro::render(
+        selected,
+        &RenderCache::new(),
+        &InterpreterSettings::default(),
+        &RenderSettings::default(),
+        &PixmapSettings {
+            x_scale: scale,
+            y_scale: scale,
+            bg_color: WHITE,
+        },
+    );
+
+    // the background is opaque, so premultiplied colors are the actual ones
+    let (width, height) = (pixmap.width() as u32, pixmap.height() as u32);
+    let pixels = pixmap.data();
+    let image = RgbImage::from_fn(width, height, |x, y| {
+        let p = pixels[(y * width + x) as usize];
+        Rgb([p.r, p.g, p.b])
+    });
+
+    Ok(DynamicImage::ImageRgb8(image))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path};
+
+    use super::*;
+
+    fn get_ticket() -> Vec<u8> {
+        let s = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let p = Path::new(&s).join("../resources/904856519207-9673649909-ticket.pdf");
+
+        fs::read(p).unwrap()
+    }
+
+    #[test]
+    fn test_render_page() -> anyhow::Result<()> {
+        let ticket = get_ticket();
+
+        assert!(is_pdf(&ticket));
+        assert!(page_count(&ticket)? >= 1);
+
+        let low = render_page(&ticket, 1, 36)?;
+        let high = render_page(&ticket, 1, 72)?;
+
+        assert!(high.width().abs_diff(low.width() * 2) <= 1);
+        assert!(high.height().abs_diff(low.height() * 2) <= 1);
+
+        // a ticket has some ink on it
+        assert!(high.to_luma8().pixels().any(|p| p.0[0] < 128));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_out_of_range() {
+        let ticket = get_ticket();
+
+        assert!(render_page(&ticket, 0, 72).is_err());
+        assert!(render_page(&ticket, 1000, 72).is_err());
+        assert!(!is_pdf(b"\x89PNG\r\n"));
+    }
+}
@@ -0,0 +1,147 @@
+use std::sync::{Arc, OnceLock};
+
+use anyhow::anyhow;
+use image::{DynamicImage, Rgba, RgbaImage};
+use resvg::{
+    tiny_skia::{Pixmap, Transform},
+    usvg::{fontdb, ImageHrefResolver, Options, Tree, WriteOptions},
+};
+use tracing::warn;
+
+use crate::limits;
+
+/// How much of the file is searched for the `<svg` root, past the XML declaration and comments.
+const SNIFF_LENGTH: usize = 1024;
+
+/// Tells if `image` looks like an SVG document. image-rs doesn't know vector formats.
+pub fn is_svg(image: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&image[..image.len().min(SNIFF_LENGTH)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+
+    head.starts_with('<') && head.contains("<svg")
+}
+
+/// Parsing options that never leave the document: external images are dropped and only the
+/// system fonts are used. Embedded `data:` images are kept.
+fn options() -> Options<'static> {
+    static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+
+    let fontdb = FONTS.get_or_init(|| {
+        let mut fonts = fontdb::Database::new();
+        fonts.load_system_fonts();
+
+        Arc::new(fonts)
+    });
+
+    Options {
+        resources_dir: None,
+        image_href_resolver: ImageHrefResolver {
+            resolve_data: ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new(|href, _| {
+                warn!("external reference '{}' removed from SVG", href);
+                None
+            }),
+        },
+        fontdb: fontdb.clone(),
+        ..Options::default()
+    }
+}
+
+fn parse(image: &[u8]) -> anyhow::Result<Tree> {
+    limits::check_input(image, &limits::current())?;
+
+    Ok(Tree::from_data(image, &options())?)
+}
+
+/// Rewrites `image` as a plain SVG: scripts, event handlers and external references are
+/// dropped and text is converted to paths, so the stored file renders the same anywhere.
+#[tracing::instrument(skip(image))]
+pub fn sanitize(image: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Ok(parse(image)?
+        .to_string(&WriteOptions::default())
+        .into_bytes())
+}
+
+/// Renders `image` stretched to exactly `width` x `height`, like a raster resize would.
+#[tracing::instrument(skip(image))]
+pub fn rasterize(image: &[u8], width: u32, height: u32) -> anyhow::Result<DynamicImage> {
+    render(&parse(image)?, width, height)
+}
+
+/// Renders `image` at the size declared in the document.
+#[tracing::instrument(skip(image))]
+pub fn decode(image: &[u8]) -> anyhow::Result<DynamicImage> {
+    let tree = parse(image)?;
+    let size = tree.size().to_int_size();
+
+    render(&tree, size.width(), size.height())
+}
+
+fn render(tree: &Tree, width: u32, height: u32) -> anyhow::Result<DynamicImage> {
+    // the declared size of a document is only a number, it can be anything
+    limits::check_dimensions(width, height, &limits::current())?;
+
+    let mut pixmap = Pixmap::new(width, height)
+        .ok_or_else(|| anyhow!("can't render SVG at {}x{}", width, height))?;
+
+    let size = tree.size();
+    let transform =
+        Transform::from_scale(width as f32 / size.width(), height as f32 / size.height());
+
+    resvg::render(tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia stores premultiplied alpha
+    let pixels = pixmap.pixels();
+    let image = RgbaImage::from_fn(width, height, |x, y| {
+        let p = pixels[(y * width + x) as usize].demultiply();
+        Rgba([p.red(), p.green(), p.blue(), p.alpha()])
+    });
+
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGO: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"
+     width="20" height="10" viewBox="0 0 20 10" onload="alert(1)">
+  <script>alert(2)</script>
+  <rect x="0" y="0" width="10" height="10" fill="#ff0000"/>
+  <image x="10" y="0" width="10" height="10" xlink:href="https://example.com/tracker.png"/>
+</svg>"##;
+
+    #[test]
+    fn test_is_svg() {
+        assert!(is_svg(LOGO.as_bytes()));
+        assert!(is_svg(b"\xEF\xBB\xBF  <svg/>"));
+        assert!(!is_svg(b"\x89PNG\r\n"));
+        assert!(!is_svg(b"<html><body></body></html>"));
+    }
+
+    #[test]
+    fn test_sanitize() -> anyhow::Result<()> {
+        let clean = String::from_utf8(sanitize(LOGO.as_bytes())?)?;
+
+        assert!(!clean.contains("script"));
+        assert!(!clean.contains("onload"));
+        assert!(!clean.contains("example.com"));
+        assert!(is_svg(clean.as_bytes()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rasterize_at_requested_size() -> anyhow::Result<()> {
+        let natural = decode(LOGO.as_bytes())?;
+        assert_eq!((natural.width(), natural.height()), (20, 10));
+
+        let large = rasterize(LOGO.as_bytes(), 400, 100)?.to_rgba8();
+        assert_eq!(large.dimensions(), (400, 100));
+        assert_eq!(large.get_pixel(100, 50), &Rgba([255, 0, 0, 255]));
+        assert_eq!(large.get_pixel(300, 50).0[3], 0);
+
+        Ok(())
+    }
+}
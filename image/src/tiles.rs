@@ -0,0 +1,195 @@
+//! Deep zoom pyramids: each level halves the next one down to a single pixel and is cut in square
+//! tiles. Levels are numbered like Deep Zoom (DZI): level 0 is 1x1, the last one is the full image.
+
+use anyhow::bail;
+use image::DynamicImage;
+
+/// Side of the square tiles, the usual size for DZI and IIIF viewers.
+pub const TILE_SIZE: u32 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pyramid {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Pixel rectangle of a tile, in the coordinates of its level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Pyramid {
+    pub fn new(width: u32, height: u32) -> Self {
+        Pyramid { width, height }
+    }
+
+    /// Level holding the full resolution image.
+    pub fn max_level(&self) -> u32 {
+        let side = self.width.max(self.height).max(1);
+
+        u32::BITS - (side - 1).leading_zeros()
+    }
+
+    /// How many full resolution pixels one pixel of `level` covers on each axis.
+    pub fn scale(&self, level: u32) -> u32 {
+        1 << (self.max_level() - level.min(self.max_level()))
+    }
+
+    /// Downscale factors of every level, from the full image to the smallest.
+    pub fn scale_factors(&self) -> Vec<u32> {
+        (0..=self.max_level()).map(|l| 1 << l).collect()
+    }
+
+    pub fn level_size(&self, level: u32) -> (u32, u32) {
+        let scale = self.scale(level);
+
+        (self.width.div_ceil(scale), self.height.div_ceil(scale))
+    }
+
+    /// Rectangle of the tile at column `x` and row `y`, `None` when it is outside the level.
+    pub fn tile_rect(&self, level: u32, x: u32, y: u32) -> Option<TileRect> {
+        let (width, height) = self.level_size(level);
+        let (left, top) = (x.checked_mul(TILE_SIZE)?, y.checked_mul(TILE_SIZE)?);
+
+        if level > self.max_level() || left >= width || top >= height {
+            return None;
+        }
+
+        Some(TileRect {
+            x: left,
+            y: top,
+            width: TILE_SIZE.min(width - left),
+            height: TILE_SIZE.min(height - top),
+        })
+    }
+
+    /// Cuts a tile out of the full resolution `image`. Only the covered area is resized, so small
+    /// levels of large images stay cheap once the master is decoded.
+    #[tracing::instrument(skip(image))]
+    pub fn render_tile(
+        &self,
+        image: &DynamicImage,
+        level: u32,
+        x: u32,
+        y: u32,
+    ) -> anyhow::Result<DynamicImage> {
+        let Some(rect) = self.tile_rect(level, x, y) else {
+            bail!("tile {}/{}_{} is outside the image", level, x, y);
+        };
+
+        let scale = self.scale(level);
+        let (left, top) = (rect.x * scale, rect.y * scale);
+        let source = image.crop_imm(
+            left,
+            top,
+            (rect.width * scale).min(self.width - left),
+            (rect.height * scale).min(self.height - top),
+        );
+
+        if scale == 1 {
+            return Ok(source);
+        }
+
+        // area averaging, a filter kernel spanning thousands of pixels is very slow
+        Ok(source.thumbnail_exact(rect.width, rect.height))
+    }
+
+    /// Deep Zoom descriptor. `url` is where viewers fetch `<level>/<x>_<y>.<format>` tiles from.
+    pub fn dzi(&self, format: &str, url: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<Image xmlns="http://schemas.microsoft.com/deepzoom/2008" Url="{}" Format="{}" Overlap="0" TileSize="{}">
+  <Size Width="{}" Height="{}"/>
+</Image>
+"#,
+            escape_xml(url),
+            escape_xml(format),
+            TILE_SIZE,
+            self.width,
+            self.height
+        )
+    }
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let pyramid = Pyramid::new(4000, 3000);
+
+        assert_eq!(pyramid.max_level(), 12);
+        assert_eq!(pyramid.level_size(12), (4000, 3000));
+        assert_eq!(pyramid.level_size(11), (2000, 1500));
+        assert_eq!(pyramid.level_size(0), (1, 1));
+        assert_eq!(pyramid.scale_factors().len(), 13);
+
+        assert_eq!(Pyramid::new(1, 1).max_level(), 0);
+        assert_eq!(Pyramid::new(256, 100).max_level(), 8);
+        assert_eq!(Pyramid::new(257, 100).max_level(), 9);
+    }
+
+    #[test]
+    fn test_tile_rect() {
+        let pyramid = Pyramid::new(600, 300);
+
+        assert_eq!(
+            pyramid.tile_rect(10, 2, 1),
+            Some(TileRect {
+                x: 512,
+                y: 256,
+                width: 88,
+                height: 44
+            })
+        );
+        assert_eq!(pyramid.tile_rect(10, 3, 0), None);
+        assert_eq!(pyramid.tile_rect(11, 0, 0), None);
+        assert_eq!(pyramid.tile_rect(9, 1, 0).map(|r| r.width), Some(44));
+    }
+
+    #[test]
+    fn test_render_tile() -> anyhow::Result<()> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(600, 300, |x, _| {
+            if x < 512 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        }));
+        let pyramid = Pyramid::new(600, 300);
+
+        let corner = pyramid.render_tile(&image, 10, 2, 1)?.to_rgb8();
+        assert_eq!(corner.dimensions(), (88, 44));
+        assert_eq!(corner.get_pixel(0, 0).0, [0, 0, 255]);
+
+        let overview = pyramid.render_tile(&image, 8, 0, 0)?;
+        assert_eq!((overview.width(), overview.height()), (150, 75));
+
+        assert!(pyramid.render_tile(&image, 10, 5, 5).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dzi() {
+        let dzi = Pyramid::new(600, 300).dzi("jpg", "/a&b/tiles/");
+
+        assert!(dzi.contains(r#"Url="/a&amp;b/tiles/""#));
+        assert!(dzi.contains(r#"<Size Width="600" Height="300"/>"#));
+    }
+}
@@ -1,14 +1,20 @@
 use std::io::Cursor;
-use tracing::warn;
+use tracing::{debug, warn};
+
+use crate::{alpha, animation, codecs, depth, limits, pages, svg, OutputFormat};
 
 use anyhow::anyhow;
-use image::{guess_format, ImageFormat};
-use tracing::error;
+use configuration::config::{EncoderSettings, FrameSelection};
+use image::{guess_format, DynamicImage, Frame, ImageFormat};
 
 #[derive(Debug)]
 pub struct Position(u32, u32);
 
 impl Position {
+    pub fn new(x: u32, y: u32) -> Self {
+        Position(x, y)
+    }
+
     pub fn x(&self) -> &u32 {
         &self.0
     }
@@ -18,7 +24,7 @@ impl Position {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PixelSize(u32, u32);
 
 impl PixelSize {
@@ -26,6 +32,13 @@ impl PixelSize {
         PixelSize(width, height)
     }
 
+    /// Multiplies both sides by `factor`, rounded to the nearest pixel and at least one.
+    pub fn scale(&self, factor: f32) -> Self {
+        let side = |value: u32| ((value as f32 * factor).round() as u32).max(1);
+
+        PixelSize(side(self.0), side(self.1))
+    }
+
     pub fn width(&self) -> &u32 {
         &self.0
     }
@@ -35,10 +48,86 @@ impl PixelSize {
     }
 }
 
+/// Page of a document to render, one based, and its resolution in dots per inch.
+#[derive(Debug)]
+pub struct PageRender(u32, u32);
+
+impl PageRender {
+    pub fn new(page: u32, dpi: u32) -> Self {
+        PageRender(page, dpi)
+    }
+
+    pub fn page(&self) -> &u32 {
+        &self.0
+    }
+
+    pub fn dpi(&self) -> &u32 {
+        &self.1
+    }
+}
+
+/// Clockwise rotation by a multiple of 90 degrees, which loses no pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Quarter,
+    Half,
+    ThreeQuarters,
+}
+
+impl Rotation {
+    /// `None` for a full turn and for angles that are not a multiple of 90.
+    pub fn from_degrees(degrees: u32) -> Option<Self> {
+        match degrees % 360 {
+            90 => Some(Rotation::Quarter),
+            180 => Some(Rotation::Half),
+            270 => Some(Rotation::ThreeQuarters),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Operations {
     Resize(PixelSize),
     Crop(Position, PixelSize),
+    /// Keeps a single frame of animated images. Without it animations stay animated when the
+    /// target format supports it.
+    Frame(FrameSelection),
+    /// Renders a page of a PDF input, the first one at the default resolution without it. Needs
+    /// the `pdf` feature.
+    Page(PageRender),
+    Rotate(Rotation),
+    /// Flips the image horizontally.
+    Mirror,
+    /// Drops the colors, keeping the bit depth and alpha channel.
+    Grayscale,
+    /// Black and white only, split at mid gray.
+    Bitonal,
+}
+
+/// Formats accepted as input.
+const INPUT_FORMATS: [ImageFormat; 8] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Avif,
+    ImageFormat::WebP,
+    ImageFormat::Gif,
+    ImageFormat::Tiff,
+    ImageFormat::Bmp,
+    ImageFormat::Ico,
+];
+
+/// Luma from which bitonal pixels are white.
+const BITONAL_THRESHOLD: u8 = 128;
+
+/// Lowest quality tried when searching for an encoding that fits a size budget.
+const MIN_QUALITY: u8 = 1;
+
+#[derive(Debug)]
+pub struct Transcoded {
+    pub bytes: Vec<u8>,
+    /// Quality the encoder was configured with, `None` when the encoder defaults were used.
+    pub quality: Option<u8>,
 }
 
 pub trait Encoder {
@@ -46,9 +135,31 @@ pub trait Encoder {
         &self,
         image: &[u8],
         extension: String,
-        target: ImageFormat,
+        target: OutputFormat,
+        ops: Option<Vec<Operations>>,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.transcode_with_settings(image, extension, target, ops, &EncoderSettings::default())
+    }
+
+    fn transcode_with_settings(
+        &self,
+        image: &[u8],
+        extension: String,
+        target: OutputFormat,
+        ops: Option<Vec<Operations>>,
+        settings: &EncoderSettings,
+    ) -> anyhow::Result<Vec<u8>> {
+        Ok(self.encode(image, extension, target, ops, settings)?.bytes)
+    }
+
+    fn encode(
+        &self,
+        image: &[u8],
+        extension: String,
+        target: OutputFormat,
         ops: Option<Vec<Operations>>,
-    ) -> anyhow::Result<Vec<u8>>;
+        settings: &EncoderSettings,
+    ) -> anyhow::Result<Transcoded>;
 }
 
 #[derive(Debug)]
@@ -56,60 +167,327 @@ pub struct Transcoder;
 
 impl Encoder for Transcoder {
     #[tracing::instrument(skip(image))]
-    fn transcode(
+    fn encode(
         &self,
         image: &[u8],
         extension: String,
-        target: ImageFormat,
+        target: OutputFormat,
         ops: Option<Vec<Operations>>,
-    ) -> anyhow::Result<Vec<u8>> {
-        let format_result = guess_format(image);
-
-        let format = match format_result {
-            Ok(format) => format,
-            Err(err) => {
-                warn!("error while trying to validate image format: {:?}", err);
-
-                match extension.as_str(){
-                    "png"=> ImageFormat::Png,
-                    "jpg"|"jpeg"=>ImageFormat::Jpeg,
-                    "avif" => ImageFormat::Avif,
-                    _=> return Err(anyhow!("error while trying to validate image format by  unsupported extension: {:?}", extension))
+        settings: &EncoderSettings,
+    ) -> anyhow::Result<Transcoded> {
+        let ops = ops.unwrap_or_default();
+
+        let limits = limits::current();
+        limits::check_input(image, &limits)?;
+
+        // image-rs can't read JPEG XL, which has no animation or pages to pick from
+        if codecs::jxl::is_jxl(image) {
+            return encode_still(codecs::jxl::decode(image)?, &ops, target, settings);
+        }
+
+        // vectors are rendered straight at the requested size instead of resizing a raster
+        if svg::is_svg(image) {
+            let (image, ops) = match ops.split_first() {
+                Some((Operations::Resize(size), rest)) => {
+                    (svg::rasterize(image, *size.width(), *size.height())?, rest)
+                }
+                _ => (svg::decode(image)?, &ops[..]),
+            };
+
+            return encode_still(image, ops, target, settings);
+        }
+
+        #[cfg(feature = "pdf")]
+        if crate::pdf::is_pdf(image) {
+            let (page, dpi) = ops
+                .iter()
+                .find_map(|op| match op {
+                    Operations::Page(render) => Some((*render.page(), *render.dpi())),
+                    _ => None,
+                })
+                .unwrap_or((1, configuration::config::DEFAULT_PDF_DPI));
+
+            let image = crate::pdf::render_page(image, page, dpi)?;
+
+            return encode_still(image, &ops, target, settings);
+        }
+
+        let format = input_format(image, &extension)?;
+
+        let selection = ops.iter().find_map(|op| match op {
+            Operations::Frame(selection) => Some(*selection),
+            _ => None,
+        });
+
+        let animated_target = match target {
+            OutputFormat::Image(format) => {
+                Some(format).filter(|f| animation::supports_animation(*f))
+            }
+            OutputFormat::JpegXl => None,
+        };
+
+        let image = match (
+            animation::decode(image, format)?,
+            selection,
+            animated_target,
+        ) {
+            (Some(frames), None, Some(animated_target)) => {
+                let frames = frames
+                    .into_iter()
+                    .map(|f| {
+                        let delay = f.delay();
+                        let image =
+                            apply_operations(DynamicImage::ImageRgba8(f.into_buffer()), &ops);
+
+                        Frame::from_parts(image.to_rgba8(), 0, 0, delay)
+                    })
+                    .collect();
+
+                return Ok(Transcoded {
+                    bytes: animation::encode(frames, animated_target)?,
+                    quality: None,
+                });
+            }
+            (Some(frames), selection, _) => {
+                if selection.is_none() {
+                    warn!(
+                        "{:?} can't store animations, keeping the first frame",
+                        target
+                    );
                 }
+
+                animation::select(frames, selection.unwrap_or(FrameSelection::Index(0)))?
+            }
+            (None, Some(FrameSelection::Index(i)), _) if i > 0 && format == ImageFormat::Tiff => {
+                pages::decode_tiff_page(image, i as usize)?
             }
+            (None, Some(FrameSelection::Index(i)), _) if i > 0 => {
+                return Err(anyhow!("frame {} out of range, image is not animated", i));
+            }
+            (None, _, _) => limits::load(image, format, &limits)?,
         };
 
-        let bytes: Vec<u8> = Vec::new();
-        let mut cursor = Cursor::new(bytes);
-        let is_jpg = target == ImageFormat::Jpeg;
-
-        let mut image = image::load_from_memory_with_format(image, format)?;
-
-        if let Some(operations) = ops {
-            for op in operations {
-                match op {
-                    Operations::Resize(s) => {
-                        image = image.resize_exact(
-                            *s.width(),
-                            *s.height(),
-                            image::imageops::FilterType::CatmullRom,
-                        );
-                    }
-                    Operations::Crop(p, s) => {
-                        image = image.crop_imm(*p.x(), *p.y(), *s.width(), *s.height());
-                    }
+        encode_still(image, &ops, target, settings)
+    }
+}
+
+/// Encodes an image that is already decoded, e.g. a tile cut out of a master.
+pub fn encode_decoded(
+    image: DynamicImage,
+    target: OutputFormat,
+    settings: &EncoderSettings,
+) -> anyhow::Result<Transcoded> {
+    encode_still(image, &[], target, settings)
+}
+
+fn encode_still(
+    image: DynamicImage,
+    ops: &[Operations],
+    target: OutputFormat,
+    settings: &EncoderSettings,
+) -> anyhow::Result<Transcoded> {
+    let mut image = apply_operations(image, ops);
+
+    if !supports_alpha(target) && alpha::has_transparency(&image) {
+        image = alpha::flatten(&image, &settings.background);
+    }
+
+    match settings.max_kb {
+        Some(kb) => encode_to_size(&image, target, settings, kb as usize * 1024),
+        None => Ok(Transcoded {
+            bytes: encode_image(&image, target, settings, settings.quality)?,
+            quality: settings.quality.filter(|_| has_quality(target)),
+        }),
+    }
+}
+
+/// Detects the format of `image` from its content, falling back to `extension` when the content
+/// is not recognized. Fails for formats that are not accepted as input.
+pub fn input_format(image: &[u8], extension: &str) -> anyhow::Result<ImageFormat> {
+    let format = match guess_format(image) {
+        Ok(format) => format,
+        Err(err) => {
+            warn!("error while trying to validate image format: {:?}", err);
+
+            ImageFormat::from_extension(extension).ok_or_else(|| {
+                anyhow!(
+                    "unknown image format, unsupported extension {:?}",
+                    extension
+                )
+            })?
+        }
+    };
+
+    if !INPUT_FORMATS.contains(&format) {
+        return Err(anyhow!("unsupported input format {:?}", format));
+    }
+
+    Ok(format)
+}
+
+fn apply_operations(mut image: DynamicImage, ops: &[Operations]) -> DynamicImage {
+    for op in ops {
+        match op {
+            Operations::Resize(s) => {
+                image = image.resize_exact(
+                    *s.width(),
+                    *s.height(),
+                    image::imageops::FilterType::CatmullRom,
+                );
+            }
+            Operations::Crop(p, s) => {
+                image = image.crop_imm(*p.x(), *p.y(), *s.width(), *s.height());
+            }
+            Operations::Rotate(Rotation::Quarter) => image = image.rotate90(),
+            Operations::Rotate(Rotation::Half) => image = image.rotate180(),
+            Operations::Rotate(Rotation::ThreeQuarters) => image = image.rotate270(),
+            Operations::Mirror => image = image.fliph(),
+            Operations::Grayscale => image = image.grayscale(),
+            Operations::Bitonal => {
+                let mut luma = image.to_luma8();
+
+                for p in luma.pixels_mut() {
+                    p.0[0] = if p.0[0] < BITONAL_THRESHOLD {
+                        0
+                    } else {
+                        u8::MAX
+                    };
                 }
+
+                image = DynamicImage::ImageLuma8(luma);
             }
+            Operations::Frame(_) | Operations::Page(_) => {}
         }
+    }
 
-        if is_jpg {
-            let rgb8 = image.to_rgb8();
-            rgb8.write_to(&mut cursor, target)?;
-        } else {
+    image
+}
+
+fn supports_alpha(target: OutputFormat) -> bool {
+    matches!(
+        target,
+        OutputFormat::Image(
+            ImageFormat::Png | ImageFormat::Avif | ImageFormat::WebP | ImageFormat::Gif
+        ) | OutputFormat::JpegXl
+    )
+}
+
+fn has_quality(target: OutputFormat) -> bool {
+    matches!(
+        target,
+        OutputFormat::Image(ImageFormat::Jpeg | ImageFormat::Avif | ImageFormat::Png)
+            | OutputFormat::JpegXl
+    )
+}
+
+fn encode_image(
+    image: &DynamicImage,
+    target: OutputFormat,
+    settings: &EncoderSettings,
+    quality: Option<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    let target = match target {
+        OutputFormat::Image(format) => format,
+        OutputFormat::JpegXl => return codecs::jxl::encode(image, quality),
+    };
+
+    let bytes: Vec<u8> = Vec::new();
+    let mut cursor = Cursor::new(bytes);
+
+    match (target, quality) {
+        (ImageFormat::Png, _) => return codecs::png::encode(image, &settings.png, quality),
+        (ImageFormat::Jpeg, _) => return codecs::jpeg::encode(image, &settings.jpeg, quality),
+        (ImageFormat::Avif, _) => return codecs::avif::encode(image, quality),
+        (ImageFormat::Tiff, _) => {
+            // TIFF stores 16 bits per channel as well
             image.write_to(&mut cursor, target)?;
         }
+        (ImageFormat::WebP, _) => {
+            // the WebP encoder only takes 8 bits per channel
+            DynamicImage::ImageRgba8(depth::to_rgba8(image)).write_to(&mut cursor, target)?;
+        }
+        _ => {
+            depth::to_8_bit(image).write_to(&mut cursor, target)?;
+        }
+    }
+
+    Ok(cursor.into_inner())
+}
+
+/// Searches the highest quality whose output fits in `max_bytes`. When not even the lowest
+/// quality fits, the smallest output produced is returned instead.
+#[tracing::instrument(skip(image))]
+fn encode_to_size(
+    image: &DynamicImage,
+    target: OutputFormat,
+    settings: &EncoderSettings,
+    max_bytes: usize,
+) -> anyhow::Result<Transcoded> {
+    if !has_quality(target) {
+        let bytes = encode_image(image, target, settings, None)?;
+
+        if bytes.len() > max_bytes {
+            warn!(
+                "{:?} has no quality setting, output has {} bytes over the {} budget",
+                target,
+                bytes.len(),
+                max_bytes
+            );
+        }
 
-        Ok(cursor.get_ref().to_vec())
+        return Ok(Transcoded {
+            bytes,
+            quality: None,
+        });
+    }
+
+    let mut low = MIN_QUALITY;
+    let mut high = settings.quality.unwrap_or(100).max(MIN_QUALITY);
+
+    let mut fitting: Option<Transcoded> = None;
+    let mut smallest: Option<Transcoded> = None;
+
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let bytes = encode_image(image, target, settings, Some(quality))?;
+
+        debug!("quality {} produced {} bytes", quality, bytes.len());
+
+        let candidate = Transcoded {
+            bytes,
+            quality: Some(quality),
+        };
+
+        if candidate.bytes.len() <= max_bytes {
+            fitting = Some(candidate);
+            low = quality + 1;
+        } else {
+            if smallest
+                .as_ref()
+                .is_none_or(|s| candidate.bytes.len() < s.bytes.len())
+            {
+                smallest = Some(candidate);
+            }
+
+            if quality == MIN_QUALITY {
+                break;
+            }
+            high = quality - 1;
+        }
+    }
+
+    match fitting {
+        Some(t) => Ok(t),
+        None => {
+            // the loop always encodes at least once, so there is a smallest candidate
+            let t = smallest.expect("no encoding attempt was made");
+            warn!(
+                "could not fit in {} bytes, smallest output has {} bytes",
+                max_bytes,
+                t.bytes.len()
+            );
+            Ok(t)
+        }
     }
 }
 
@@ -117,7 +495,7 @@ impl Encoder for Transcoder {
 mod tests {
     use std::{env, fs, path::Path};
 
-    use image::DynamicImage;
+    use configuration::config::BackgroundColor;
 
     use super::*;
 
@@ -148,9 +526,9 @@ mod tests {
         ops: Option<Vec<Operations>>,
     ) -> anyhow::Result<DynamicImage> {
         let t = Transcoder;
-        let output_img = t.transcode(&img, "avif".to_owned(), output_format, ops)?;
+        let output_img = t.transcode(&img, "avif".to_owned(), output_format.into(), ops)?;
 
-        assert!(output_img.len() > 0);
+        assert!(!output_img.is_empty());
 
         Ok(image::load_from_memory_with_format(
             &output_img,
@@ -229,4 +607,330 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_transcoder_png_with_encoder_settings() -> anyhow::Result<()> {
+        let png_img = get_png_image();
+
+        let mut settings = EncoderSettings::default();
+        settings.png.quantize = true;
+        settings.png.optimize = Some(2);
+
+        let output = Transcoder.transcode_with_settings(
+            &png_img,
+            "png".to_owned(),
+            ImageFormat::Png.into(),
+            None,
+            &settings,
+        )?;
+
+        let img = image::load_from_memory_with_format(&output, ImageFormat::Png)?;
+
+        assert_eq!(img.width(), 20);
+        assert_eq!(img.height(), 20);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcoder_jpeg_quality() -> anyhow::Result<()> {
+        let avif_img = get_avif_image();
+
+        let mut settings = EncoderSettings {
+            quality: Some(20),
+            ..Default::default()
+        };
+
+        let low = Transcoder.encode(
+            &avif_img,
+            "avif".to_owned(),
+            ImageFormat::Jpeg.into(),
+            None,
+            &settings,
+        )?;
+
+        settings.quality = Some(95);
+
+        let high = Transcoder.encode(
+            &avif_img,
+            "avif".to_owned(),
+            ImageFormat::Jpeg.into(),
+            None,
+            &settings,
+        )?;
+
+        assert_eq!(low.quality, Some(20));
+        assert_eq!(high.quality, Some(95));
+        assert!(low.bytes.len() < high.bytes.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcoder_max_kb() -> anyhow::Result<()> {
+        let avif_img = get_avif_image();
+
+        let settings = EncoderSettings {
+            max_kb: Some(40),
+            ..Default::default()
+        };
+
+        let output = Transcoder.encode(
+            &avif_img,
+            "avif".to_owned(),
+            ImageFormat::Jpeg.into(),
+            None,
+            &settings,
+        )?;
+
+        assert!(output.bytes.len() <= 40 * 1024);
+        assert!(output.quality.is_some());
+
+        let img = image::load_from_memory_with_format(&output.bytes, ImageFormat::Jpeg)?;
+        assert_eq!(img.width(), 1204);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcoder_max_kb_unreachable() -> anyhow::Result<()> {
+        let avif_img = get_avif_image();
+
+        let settings = EncoderSettings {
+            max_kb: Some(1),
+            ..Default::default()
+        };
+
+        let output = Transcoder.encode(
+            &avif_img,
+            "avif".to_owned(),
+            ImageFormat::Jpeg.into(),
+            None,
+            &settings,
+        )?;
+
+        assert!(output.bytes.len() > 1024);
+        assert_eq!(output.quality, Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcoder_flattens_alpha_for_jpeg() -> anyhow::Result<()> {
+        let transparent = image::RgbaImage::from_pixel(8, 8, image::Rgba([0, 0, 0, 0]));
+
+        let mut png = Cursor::new(Vec::new());
+        transparent.write_to(&mut png, ImageFormat::Png)?;
+
+        let settings = EncoderSettings {
+            background: BackgroundColor(255, 0, 0),
+            ..Default::default()
+        };
+
+        let output = Transcoder.transcode_with_settings(
+            png.get_ref(),
+            "png".to_owned(),
+            ImageFormat::Jpeg.into(),
+            None,
+            &settings,
+        )?;
+
+        let img = image::load_from_memory_with_format(&output, ImageFormat::Jpeg)?.to_rgb8();
+        let p = img.get_pixel(4, 4).0;
+
+        assert!(p[0] > 240 && p[1] < 15 && p[2] < 15, "pixel was {:?}", p);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcoder_png_to_webp() -> anyhow::Result<()> {
+        let png_img = get_png_image();
+
+        let img = transcode(png_img, ImageFormat::WebP, None)?;
+
+        assert_eq!(img.width(), 20);
+        assert_eq!(img.height(), 20);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcoder_keeps_high_bit_depth() -> anyhow::Result<()> {
+        let sky = image::ImageBuffer::from_fn(32, 32, |x, _| image::Rgb([x as u16 * 300; 3]));
+
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb16(sky).write_to(&mut png, ImageFormat::Png)?;
+
+        let resize = || Some(vec![Operations::Resize(PixelSize(16, 16))]);
+
+        let img = transcode(png.get_ref().clone(), ImageFormat::Png, resize())?;
+        assert_eq!(img.color(), image::ColorType::Rgb16);
+
+        let img = transcode(png.get_ref().clone(), ImageFormat::Avif, resize())?;
+        assert!(depth::is_high_depth(&img));
+
+        let img = transcode(png.into_inner(), ImageFormat::Jpeg, resize())?;
+        assert_eq!(img.width(), 16);
+
+        Ok(())
+    }
+
+    fn animated_gif() -> anyhow::Result<Vec<u8>> {
+        let frames = (0..4u8)
+            .map(|i| {
+                let buffer = image::RgbaImage::from_pixel(40, 30, image::Rgba([i * 60, 0, 0, 255]));
+                Frame::from_parts(buffer, 0, 0, image::Delay::from_numer_denom_ms(50, 1))
+            })
+            .collect();
+
+        animation::encode(frames, ImageFormat::Gif)
+    }
+
+    #[test]
+    fn test_transcoder_keeps_animation() -> anyhow::Result<()> {
+        let ops = Some(vec![Operations::Resize(PixelSize(20, 15))]);
+        let output = Transcoder.transcode(
+            &animated_gif()?,
+            "gif".to_owned(),
+            ImageFormat::WebP.into(),
+            ops,
+        )?;
+
+        let frames = animation::decode(&output, ImageFormat::WebP)?.unwrap();
+
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[0].buffer().dimensions(), (20, 15));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcoder_extracts_frame() -> anyhow::Result<()> {
+        let ops = Some(vec![Operations::Frame(FrameSelection::Index(3))]);
+        let img = transcode(animated_gif()?, ImageFormat::Png, ops)?;
+
+        assert_eq!(img.to_rgba8().get_pixel(0, 0).0[0], 180);
+
+        let ops = Some(vec![Operations::Frame(FrameSelection::Index(4))]);
+        assert!(transcode(animated_gif()?, ImageFormat::Png, ops).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcoder_ingest_formats() -> anyhow::Result<()> {
+        let source = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            16,
+            16,
+            image::Rgba([200, 100, 50, 255]),
+        ));
+
+        for format in [
+            ImageFormat::Bmp,
+            ImageFormat::Ico,
+            ImageFormat::Tiff,
+            ImageFormat::Gif,
+        ] {
+            let mut input = Cursor::new(Vec::new());
+            source.write_to(&mut input, format)?;
+
+            let img = transcode(input.into_inner(), ImageFormat::Png, None)?;
+            assert_eq!(img.width(), 16, "{:?}", format);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcoder_tiff_page() -> anyhow::Result<()> {
+        let mut tiff = Cursor::new(Vec::new());
+        let mut encoder = tiff::encoder::TiffEncoder::new(&mut tiff)?;
+        encoder.write_image::<tiff::encoder::colortype::RGB8>(8, 8, &[0; 8 * 8 * 3])?;
+        encoder.write_image::<tiff::encoder::colortype::RGB8>(6, 4, &[255; 6 * 4 * 3])?;
+
+        let first = transcode(tiff.get_ref().clone(), ImageFormat::Png, None)?;
+        assert_eq!(first.width(), 8);
+
+        let ops = Some(vec![Operations::Frame(FrameSelection::Index(1))]);
+        let second = transcode(tiff.into_inner(), ImageFormat::Png, ops)?;
+        assert_eq!((second.width(), second.height()), (6, 4));
+        assert_eq!(second.to_rgb8().get_pixel(0, 0).0, [255, 255, 255]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcoder_renders_svg_at_requested_size() -> anyhow::Result<()> {
+        let logo = br##"<svg xmlns="http://www.w3.org/2000/svg" width="8" height="8">
+            <circle cx="4" cy="4" r="4" fill="#0000ff"/></svg>"##;
+
+        let ops = Some(vec![Operations::Resize(PixelSize(512, 512))]);
+        let img = transcode(logo.to_vec(), ImageFormat::Png, ops)?.to_rgba8();
+
+        assert_eq!(img.dimensions(), (512, 512));
+        // a resized 8px raster would blur the edge over dozens of pixels
+        assert_eq!(img.get_pixel(256, 4).0, [0, 0, 255, 255]);
+        assert_eq!(img.get_pixel(0, 0).0[3], 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pixel_size_scale() {
+        assert_eq!(PixelSize(300, 200).scale(1.5), PixelSize(450, 300));
+        assert_eq!(PixelSize(3, 1).scale(0.1), PixelSize(1, 1));
+    }
+
+    #[test]
+    fn test_transcoder_orientation_and_quality_operations() -> anyhow::Result<()> {
+        // 2x1: a dark red pixel then a light blue one
+        let mut input = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                image::Rgb([120, 0, 0])
+            } else {
+                image::Rgb([150, 200, 255])
+            }
+        }))
+        .write_to(&mut input, ImageFormat::Png)?;
+        let input = input.into_inner();
+
+        let ops = Some(vec![Operations::Rotate(Rotation::Quarter)]);
+        let rotated = transcode(input.clone(), ImageFormat::Png, ops)?.to_rgb8();
+        assert_eq!(rotated.dimensions(), (1, 2));
+        assert_eq!(rotated.get_pixel(0, 0).0, [120, 0, 0]);
+
+        let ops = Some(vec![Operations::Mirror]);
+        let mirrored = transcode(input.clone(), ImageFormat::Png, ops)?.to_rgb8();
+        assert_eq!(mirrored.get_pixel(0, 0).0, [150, 200, 255]);
+
+        let ops = Some(vec![Operations::Grayscale]);
+        let gray = transcode(input.clone(), ImageFormat::Png, ops)?;
+        assert!(!gray.color().has_color());
+
+        let ops = Some(vec![Operations::Bitonal]);
+        let bitonal = transcode(input, ImageFormat::Png, ops)?.to_luma8();
+        assert_eq!(bitonal.as_raw(), &vec![0, 255]);
+
+        assert_eq!(Rotation::from_degrees(450), Some(Rotation::Quarter));
+        assert_eq!(Rotation::from_degrees(360), None);
+        assert_eq!(Rotation::from_degrees(45), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_format() {
+        assert_eq!(
+            input_format(&get_png_image(), "jpg").unwrap(),
+            ImageFormat::Png
+        );
+        assert_eq!(
+            input_format(b"not an image", "tif").unwrap(),
+            ImageFormat::Tiff
+        );
+        assert!(input_format(b"not an image", "txt").is_err());
+        assert!(input_format(b"P3\n1 1\n255\n0 0 0\n", "ppm").is_err());
+    }
 }
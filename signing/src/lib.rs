@@ -0,0 +1,275 @@
+//! Signed URLs: an HMAC-SHA256 of the path and query computed with a secret, so only the URLs
+//! generated by whoever holds it are served.
+//!
+//! A signed URL carries the ID of the key used, an optional expiry and the signature:
+//! `/300/200/fox/webp?quality=80&key=2024&expires=1735689600&signature=...`.
+
+use std::fmt;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const KEY_PARAM: &str = "key";
+/// Expiry, in seconds since the Unix epoch.
+pub const EXPIRES_PARAM: &str = "expires";
+pub const SIGNATURE_PARAM: &str = "signature";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureError {
+    Missing,
+    /// Empty parameters or repeated signing parameters, which the signature wouldn't cover.
+    Malformed,
+    UnknownKey,
+    Invalid,
+    Expired,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "the URL is not signed"),
+            SignatureError::Malformed => write!(f, "the query of the URL is malformed"),
+            SignatureError::UnknownKey => write!(f, "the URL is signed with an unknown key"),
+            SignatureError::Invalid => write!(f, "the signature doesn't match the URL"),
+            SignatureError::Expired => write!(f, "the URL has expired"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Signs `url`, a path with an optional query, with the key `key_id`. Parameters of a previous
+/// signature are replaced.
+pub fn sign(url: &str, key_id: &str, secret: &[u8], expires: Option<u64>) -> String {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+
+    let key = format!("{}={}", KEY_PARAM, key_id);
+    let expires = expires.map(|e| format!("{}={}", EXPIRES_PARAM, e));
+
+    let params: Vec<&str> = params(query)
+        .filter(|p| {
+            ![KEY_PARAM, EXPIRES_PARAM, SIGNATURE_PARAM]
+                .iter()
+                .any(|n| is_param(p, n))
+        })
+        .chain(Some(key.as_str()))
+        .chain(expires.as_deref())
+        .collect();
+
+    let signature = URL_SAFE_NO_PAD.encode(mac(path, &params, secret).finalize().into_bytes());
+
+    format!(
+        "{}?{}&{}={}",
+        path,
+        params.join("&"),
+        SIGNATURE_PARAM,
+        signature
+    )
+}
+
+/// Checks the signature of a request for `path` with `query`. `secret` looks up the secret of
+/// a key ID and `now` is in seconds since the Unix epoch.
+pub fn verify<'a>(
+    path: &str,
+    query: Option<&str>,
+    secret: impl Fn(&str) -> Option<&'a [u8]>,
+    now: u64,
+) -> Result<(), SignatureError> {
+    let query = query.unwrap_or_default();
+    if query.is_empty() {
+        return Err(SignatureError::Missing);
+    }
+
+    // the MAC skips empty parameters and every signature, they can't be let through unsigned
+    let params: Vec<&str> = query.split('&').collect();
+    let repeated = [KEY_PARAM, EXPIRES_PARAM, SIGNATURE_PARAM]
+        .iter()
+        .any(|n| params.iter().filter(|p| is_param(p, n)).count() > 1);
+
+    if repeated || params.iter().any(|p| p.is_empty()) {
+        return Err(SignatureError::Malformed);
+    }
+
+    let signature = value(&params, SIGNATURE_PARAM).ok_or(SignatureError::Missing)?;
+    let key_id = value(&params, KEY_PARAM).ok_or(SignatureError::Missing)?;
+    let secret = secret(key_id).ok_or(SignatureError::UnknownKey)?;
+
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| SignatureError::Invalid)?;
+
+    // constant time, a timing difference would leak the signature byte by byte
+    mac(path, &params, secret)
+        .verify_slice(&signature)
+        .map_err(|_| SignatureError::Invalid)?;
+
+    match expires(query) {
+        Some(expires) if now > expires => Err(SignatureError::Expired),
+        _ => Ok(()),
+    }
+}
+
+/// Expiry of a signed URL, from its query.
+pub fn expires(query: &str) -> Option<u64> {
+    let params: Vec<&str> = params(query).collect();
+
+    value(&params, EXPIRES_PARAM)?.parse().ok()
+}
+
+/// HMAC of the path and the parameters, but the signature, sorted so their order doesn't matter.
+fn mac(path: &str, params: &[&str], secret: &[u8]) -> HmacSha256 {
+    let mut signed: Vec<&str> = params
+        .iter()
+        .copied()
+        .filter(|p| !is_param(p, SIGNATURE_PARAM))
+        .collect();
+    signed.sort_unstable();
+
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(path.as_bytes());
+    mac.update(b"?");
+    mac.update(signed.join("&").as_bytes());
+
+    mac
+}
+
+fn params(query: &str) -> impl Iterator<Item = &str> {
+    query.split('&').filter(|p| !p.is_empty())
+}
+
+fn value<'a>(params: &[&'a str], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+}
+
+fn is_param(param: &str, name: &str) -> bool {
+    param == name || param.strip_prefix(name).is_some_and(|v| v.starts_with('='))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"correct horse battery staple";
+
+    fn keys(id: &str) -> Option<&'static [u8]> {
+        match id {
+            "current" => Some(SECRET),
+            "previous" => Some(b"tr0ub4dor&3"),
+            _ => None,
+        }
+    }
+
+    fn check(url: &str, now: u64) -> Result<(), SignatureError> {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+
+        verify(path, Some(query), keys, now)
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let url = sign("/300/200/fox/webp?quality=80", "current", SECRET, None);
+
+        assert!(url.starts_with("/300/200/fox/webp?quality=80&key=current&signature="));
+        assert_eq!(check(&url, 0), Ok(()));
+
+        // parameters can be reordered by caches and proxies
+        let (path, query) = url.split_once('?').unwrap();
+        let mut params: Vec<&str> = query.split('&').collect();
+        params.reverse();
+        assert_eq!(check(&format!("{}?{}", path, params.join("&")), 0), Ok(()));
+
+        // signed with a rotated key
+        let url = sign("/fox/png", "previous", b"tr0ub4dor&3", None);
+        assert_eq!(check(&url, 0), Ok(()));
+    }
+
+    #[test]
+    fn test_tampered_urls() {
+        let url = sign("/300/200/fox/webp?quality=80", "current", SECRET, None);
+
+        assert_eq!(
+            check(&url.replace("/300/", "/3000/"), 0),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            check(&url.replace("quality=80", "quality=100"), 0),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            check(&format!("{}&dpr=4", url), 0),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            check(&url.replace("key=current", "key=previous"), 0),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            check(&url.replace("key=current", "key=other"), 0),
+            Err(SignatureError::UnknownKey)
+        );
+        assert_eq!(
+            check("/300/200/fox/webp?quality=80", 0),
+            Err(SignatureError::Missing)
+        );
+        assert_eq!(check("/fox/webp", 0), Err(SignatureError::Missing));
+    }
+
+    #[test]
+    fn test_malformed_queries() {
+        let url = sign(
+            "/300/200/fox/webp?quality=80",
+            "current",
+            SECRET,
+            Some(1000),
+        );
+        let (path, query) = url.split_once('?').unwrap();
+        let signature = query.rsplit_once('&').unwrap().1;
+
+        for query in [
+            format!("{}&", query),
+            format!("&{}", query),
+            query.replacen('&', "&&", 1),
+            format!("{}&{}", query, signature),
+            format!("{}&signature=", query),
+            format!("{}&key=previous", query),
+            format!("{}&expires=9999", query),
+        ] {
+            assert_eq!(
+                verify(path, Some(&query), keys, 0),
+                Err(SignatureError::Malformed),
+                "{}",
+                query
+            );
+        }
+
+        assert_eq!(
+            verify(path, Some(""), keys, 0),
+            Err(SignatureError::Missing)
+        );
+    }
+
+    #[test]
+    fn test_expiry() {
+        let url = sign("/fox/webp", "current", SECRET, Some(1000));
+
+        assert_eq!(expires(url.split_once('?').unwrap().1), Some(1000));
+        assert_eq!(check(&url, 1000), Ok(()));
+        assert_eq!(check(&url, 1001), Err(SignatureError::Expired));
+
+        // the expiry is signed
+        assert_eq!(
+            check(&url.replace("expires=1000", "expires=9999"), 1001),
+            Err(SignatureError::Invalid)
+        );
+
+        // signing again replaces the previous signature
+        let resigned = sign(&url, "current", SECRET, Some(2000));
+        assert_eq!(resigned.matches("signature=").count(), 1);
+        assert_eq!(check(&resigned, 1001), Ok(()));
+    }
+}
@@ -43,11 +43,40 @@ impl<'a> DiskStorage<'a> {
         let mut buf = BufWriter::new(file_handler);
 
         buf.write_all(data)?;
+        buf.flush()?;
         tracing::debug!("created new file at {:?}", file_path.to_str());
 
         Ok(file_path.to_path_buf())
     }
 
+    /// Like [`DiskStorage::add_new_file`], but fails with `AlreadyExists` instead of replacing a
+    /// file, atomically.
+    #[tracing::instrument(skip(data))]
+    pub fn create_new_file(&self, file: File, data: &[u8]) -> std::io::Result<PathBuf> {
+        let file_path = self.base_path.join(file.file_name());
+
+        let file_handler = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&file_path)?;
+        let mut buf = BufWriter::new(file_handler);
+
+        // a partly written file would keep the name taken, so it doesn't outlive a failed write
+        if let Err(e) = buf.write_all(data).and_then(|()| buf.flush()) {
+            drop(buf);
+            let _ = fs::remove_file(&file_path);
+            return Err(e);
+        }
+        tracing::debug!("created new file at {:?}", file_path.to_str());
+
+        Ok(file_path)
+    }
+
+    #[tracing::instrument]
+    pub fn read_file(&self, file: File) -> std::io::Result<Vec<u8>> {
+        fs::read(self.base_path.join(file.file_name()))
+    }
+
     #[tracing::instrument]
     pub fn delete_file(&self, file: File) -> std::io::Result<()> {
         let file_path = self.base_path.join(file.file_name());
@@ -144,6 +173,46 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_create_new_file() -> io::Result<()> {
+        let folder = create_random_folder();
+
+        let storage = DiskStorage::new(&folder)?;
+
+        let path = storage.create_new_file(super::File("master", "png"), b"first")?;
+
+        let error = storage
+            .create_new_file(super::File("master", "png"), b"second")
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path)?, b"first");
+
+        fs::remove_file(path)?;
+        fs::remove_dir(folder)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_file() -> io::Result<()> {
+        let folder = create_random_folder();
+
+        let storage = DiskStorage::new(&folder)?;
+
+        let mut data = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut data);
+
+        let path = storage.add_new_file(super::File("tile", "png"), &data)?;
+
+        assert_eq!(storage.read_file(super::File("tile", "png"))?, data);
+        assert!(storage.read_file(super::File("missing", "png")).is_err());
+
+        fs::remove_file(path)?;
+        fs::remove_dir(folder)?;
+
+        Ok(())
+    }
+
     #[test]
     fn test_delete_file() -> io::Result<()> {
         let folder = create_random_folder();
@@ -0,0 +1,493 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{BufWriter, Result, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+const INDEX_FILE_NAME: &str = "index.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub id: String,
+    /// Perceptual hash of the stored master.
+    pub phash: u64,
+    /// Closest already indexed image when this one was flagged as a near-duplicate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+    /// Width and height of the master, its header can't be read without decoding for AVIF.
+    /// Missing for masters indexed before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<[u32; 2]>,
+    /// Format of the master, as its usual extension.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Size of the master in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    /// Unix time, in seconds, the master was stored at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ingested_at: Option<u64>,
+    /// Dominant color of the master, `#rrggbb`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dominant_color: Option<String>,
+    /// Tells if the master has visibly transparent pixels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transparent: Option<bool>,
+}
+
+impl IndexEntry {
+    pub fn new(id: &str, phash: u64) -> Self {
+        Self {
+            id: id.to_string(),
+            phash,
+            duplicate_of: None,
+            dimensions: None,
+            format: None,
+            bytes: None,
+            ingested_at: None,
+            dominant_color: None,
+            transparent: None,
+        }
+    }
+}
+
+/// Filters, order and page of a listing of the index. Entries missing a value that is filtered
+/// on, indexed before it was recorded, are left out.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct IndexQuery {
+    /// Start of the ids.
+    pub prefix: Option<String>,
+    /// Format of the masters, as their usual extension.
+    pub format: Option<String>,
+    pub min_bytes: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub min_width: Option<u32>,
+    pub max_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Unix times in seconds, both included.
+    pub ingested_after: Option<u64>,
+    pub ingested_before: Option<u64>,
+    pub sort: SortKey,
+    pub order: SortOrder,
+    pub offset: usize,
+    /// [`DEFAULT_PAGE_SIZE`] when missing, at most [`MAX_PAGE_SIZE`].
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Id,
+    Ingested,
+    Bytes,
+    Width,
+    Height,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 500;
+
+impl IndexQuery {
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE)
+    }
+
+    pub fn matches(&self, entry: &IndexEntry) -> bool {
+        let width = entry.dimensions.map(|[w, _]| w);
+        let height = entry.dimensions.map(|[_, h]| h);
+
+        self.prefix.as_ref().is_none_or(|p| entry.id.starts_with(p))
+            && self.format.as_ref().is_none_or(|f| {
+                entry
+                    .format
+                    .as_ref()
+                    .is_some_and(|e| e.eq_ignore_ascii_case(f))
+            })
+            && within(entry.bytes, self.min_bytes, self.max_bytes)
+            && within(width, self.min_width, self.max_width)
+            && within(height, self.min_height, self.max_height)
+            && within(entry.ingested_at, self.ingested_after, self.ingested_before)
+    }
+}
+
+fn within<T: PartialOrd>(value: Option<T>, min: Option<T>, max: Option<T>) -> bool {
+    if min.is_none() && max.is_none() {
+        return true;
+    }
+
+    value.is_some_and(|v| min.is_none_or(|m| v >= m) && max.is_none_or(|m| v <= m))
+}
+
+/// A page of entries, and how many matched the query in total.
+#[derive(Debug, PartialEq)]
+pub struct IndexPage<'a> {
+    pub total: usize,
+    pub entries: Vec<&'a IndexEntry>,
+}
+
+/// Index of the masters kept in a storage folder. It is persisted as JSON next to the masters
+/// and rewritten on every change, so it survives restarts without scanning the folder again.
+#[derive(Debug)]
+pub struct ImageIndex {
+    path: PathBuf,
+    entries: BTreeMap<String, IndexEntry>,
+}
+
+impl ImageIndex {
+    #[tracing::instrument]
+    pub fn open(base_path: &Path) -> Result<Self> {
+        if !base_path.exists() {
+            tracing::info!("Path '{:?}' not found. Creating entire path.", base_path);
+            fs::create_dir_all(base_path)?
+        }
+
+        let path = base_path.join(INDEX_FILE_NAME);
+
+        let entries = if path.exists() {
+            let content = fs::read(&path)?;
+            let entries: Vec<IndexEntry> = serde_json::from_slice(&content)?;
+
+            entries.into_iter().map(|e| (e.id.clone(), e)).collect()
+        } else {
+            BTreeMap::new()
+        };
+
+        tracing::info!("Loaded {} entries from {:?}", entries.len(), path);
+
+        Ok(Self { path, entries })
+    }
+
+    pub fn get(&self, id: &str) -> Option<&IndexEntry> {
+        self.entries.get(id)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &IndexEntry> {
+        self.entries.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Adds or replaces the entry with the same id.
+    pub fn insert(&mut self, entry: IndexEntry) -> Result<()> {
+        self.entries.insert(entry.id.clone(), entry);
+        self.persist()
+    }
+
+    /// Adds or replaces the entries with the same ids, the index is persisted once for all of
+    /// them.
+    pub fn insert_all(&mut self, entries: impl IntoIterator<Item = IndexEntry>) -> Result<()> {
+        self.entries
+            .extend(entries.into_iter().map(|entry| (entry.id.clone(), entry)));
+        self.persist()
+    }
+
+    /// Removes the entry of `id`, entries flagged as its near-duplicates are not anymore.
+    pub fn remove(&mut self, id: &str) -> Result<Option<IndexEntry>> {
+        let removed = self.entries.remove(id);
+
+        if removed.is_some() {
+            for entry in self.entries.values_mut() {
+                if entry.duplicate_of.as_deref() == Some(id) {
+                    entry.duplicate_of = None;
+                }
+            }
+
+            self.persist()?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Entries matching `query`, sorted and paginated.
+    pub fn query(&self, query: &IndexQuery) -> IndexPage<'_> {
+        // the map is ordered by id and the sorts are stable, so ties stay ordered by id, in both
+        // orders
+        let mut entries: Vec<&IndexEntry> =
+            self.entries.values().filter(|e| query.matches(e)).collect();
+
+        let key = |e: &IndexEntry| -> Option<u64> {
+            match query.sort {
+                SortKey::Id => None,
+                SortKey::Ingested => e.ingested_at,
+                SortKey::Bytes => e.bytes,
+                SortKey::Width => e.dimensions.map(|[w, _]| w.into()),
+                SortKey::Height => e.dimensions.map(|[_, h]| h.into()),
+            }
+        };
+
+        match (query.sort, query.order) {
+            (SortKey::Id, SortOrder::Asc) => {}
+            (SortKey::Id, SortOrder::Desc) => entries.reverse(),
+            (_, SortOrder::Asc) => entries.sort_by_key(|e| key(e)),
+            (_, SortOrder::Desc) => entries.sort_by_key(|e| std::cmp::Reverse(key(e))),
+        }
+
+        let total = entries.len();
+        let entries = entries
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit())
+            .collect();
+
+        IndexPage { total, entries }
+    }
+
+    /// Entries whose hash is within `max_distance` of `phash`, closest first. The distance
+    /// between two hashes is left to the hashing code.
+    pub fn similar(
+        &self,
+        phash: u64,
+        max_distance: u32,
+        distance: impl Fn(u64, u64) -> u32,
+    ) -> Vec<(&IndexEntry, u32)> {
+        let mut similar: Vec<_> = self
+            .entries
+            .values()
+            .map(|e| (e, distance(e.phash, phash)))
+            .filter(|(_, distance)| *distance <= max_distance)
+            .collect();
+
+        similar.sort_by_key(|(e, distance)| (*distance, e.id.clone()));
+
+        similar
+    }
+
+    fn persist(&self) -> Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+
+        let file_handler = fs::File::create(&tmp_path)?;
+        let mut buf = BufWriter::new(file_handler);
+
+        let entries: Vec<&IndexEntry> = self.entries.values().collect();
+        serde_json::to_writer(&mut buf, &entries)?;
+        buf.flush()?;
+
+        // replace atomically so a crash never leaves a truncated index behind
+        fs::rename(tmp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io, path::Path};
+
+    use uuid::Uuid;
+
+    use super::{ImageIndex, IndexEntry, IndexQuery, SortKey, SortOrder};
+
+    const BASE_TMP_FOLDER: &str = "/tmp/pixel_index_tester";
+
+    fn create_random_folder() -> String {
+        format!("{}_{}", BASE_TMP_FOLDER, Uuid::now_v7().as_simple())
+    }
+
+    #[test]
+    fn test_index_persists_entries() -> io::Result<()> {
+        let folder = create_random_folder();
+
+        let mut index = ImageIndex::open(Path::new(&folder))?;
+        assert!(index.is_empty());
+
+        index.insert(IndexEntry::new("fox", 0b1010))?;
+        index.insert(IndexEntry::new("cat", 0b0101))?;
+
+        let index = ImageIndex::open(Path::new(&folder))?;
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get("fox"), Some(&IndexEntry::new("fox", 0b1010)));
+
+        fs::remove_dir_all(folder)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_insert_all() -> io::Result<()> {
+        let folder = create_random_folder();
+
+        let mut index = ImageIndex::open(Path::new(&folder))?;
+        index.insert(IndexEntry::new("fox", 1))?;
+        index.insert_all([IndexEntry::new("fox", 2), IndexEntry::new("cat", 3)])?;
+
+        let index = ImageIndex::open(Path::new(&folder))?;
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get("fox"), Some(&IndexEntry::new("fox", 2)));
+        assert_eq!(index.get("cat"), Some(&IndexEntry::new("cat", 3)));
+
+        fs::remove_dir_all(folder)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_remove() -> io::Result<()> {
+        let folder = create_random_folder();
+
+        let mut index = ImageIndex::open(Path::new(&folder))?;
+        index.insert(IndexEntry::new("fox", 1))?;
+        index.insert(IndexEntry {
+            duplicate_of: Some("fox".to_owned()),
+            ..IndexEntry::new("fox-copy", 1)
+        })?;
+
+        assert!(index.remove("fox")?.is_some());
+        assert!(index.remove("fox")?.is_none());
+
+        let index = ImageIndex::open(Path::new(&folder))?;
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.get("fox-copy"), Some(&IndexEntry::new("fox-copy", 1)));
+
+        fs::remove_dir_all(folder)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_similar() -> io::Result<()> {
+        let folder = create_random_folder();
+
+        let mut index = ImageIndex::open(Path::new(&folder))?;
+        index.insert(IndexEntry::new("same", 0xFF00))?;
+        index.insert(IndexEntry::new("close", 0xFF03))?;
+        index.insert(IndexEntry::new("far", 0x00FF))?;
+
+        let similar: Vec<_> = index
+            .similar(0xFF00, 4, |a, b| (a ^ b).count_ones())
+            .into_iter()
+            .map(|(e, d)| (e.id.as_str(), d))
+            .collect();
+
+        assert_eq!(similar, vec![("same", 0), ("close", 2)]);
+
+        fs::remove_dir_all(folder)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_reads_entries_without_dimensions() -> io::Result<()> {
+        let folder = create_random_folder();
+        fs::create_dir_all(&folder)?;
+        fs::write(
+            Path::new(&folder).join("index.json"),
+            r#"[{"id":"fox","phash":10}]"#,
+        )?;
+
+        let mut index = ImageIndex::open(Path::new(&folder))?;
+        assert_eq!(index.get("fox").unwrap().dimensions, None);
+
+        let mut entry = IndexEntry::new("cat", 5);
+        entry.dimensions = Some([640, 480]);
+        index.insert(entry)?;
+
+        let index = ImageIndex::open(Path::new(&folder))?;
+        assert_eq!(index.get("cat").unwrap().dimensions, Some([640, 480]));
+
+        fs::remove_dir_all(folder)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_query() -> io::Result<()> {
+        let folder = create_random_folder();
+        let mut index = ImageIndex::open(Path::new(&folder))?;
+
+        let entries = [
+            ("cat-1", "avif", 1000, [640, 480], 10),
+            ("cat-2", "avif", 3000, [1920, 1080], 30),
+            ("dog-1", "svg", 500, [100, 100], 20),
+        ];
+        for (id, format, bytes, dimensions, ingested_at) in entries {
+            let mut entry = IndexEntry::new(id, 0);
+            entry.format = Some(format.to_owned());
+            entry.bytes = Some(bytes);
+            entry.dimensions = Some(dimensions);
+            entry.ingested_at = Some(ingested_at);
+            index.insert(entry)?;
+        }
+        // indexed before the metadata was recorded
+        index.insert(IndexEntry::new("cat-0", 0))?;
+
+        let ids = |query: IndexQuery| -> (usize, Vec<String>) {
+            let page = index.query(&query);
+            (
+                page.total,
+                page.entries.iter().map(|e| e.id.clone()).collect(),
+            )
+        };
+
+        assert_eq!(ids(IndexQuery::default()).0, 4);
+        assert_eq!(
+            ids(IndexQuery {
+                prefix: Some("cat".to_owned()),
+                format: Some("AVIF".to_owned()),
+                ..Default::default()
+            }),
+            (2, vec!["cat-1".to_owned(), "cat-2".to_owned()])
+        );
+        assert_eq!(
+            ids(IndexQuery {
+                min_bytes: Some(600),
+                max_width: Some(1000),
+                ..Default::default()
+            }),
+            (1, vec!["cat-1".to_owned()])
+        );
+        assert_eq!(
+            ids(IndexQuery {
+                ingested_after: Some(20),
+                sort: SortKey::Ingested,
+                order: SortOrder::Desc,
+                ..Default::default()
+            }),
+            (2, vec!["cat-2".to_owned(), "dog-1".to_owned()])
+        );
+        assert_eq!(
+            ids(IndexQuery {
+                sort: SortKey::Bytes,
+                offset: 1,
+                limit: Some(2),
+                ..Default::default()
+            }),
+            (4, vec!["dog-1".to_owned(), "cat-1".to_owned()])
+        );
+
+        // ties stay ordered by id when the order is reversed
+        index.insert(IndexEntry {
+            bytes: Some(3000),
+            ..IndexEntry::new("bird-1", 0)
+        })?;
+        let page = index.query(&IndexQuery {
+            sort: SortKey::Bytes,
+            order: SortOrder::Desc,
+            limit: Some(3),
+            ..Default::default()
+        });
+        let ids: Vec<&str> = page.entries.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, ["bird-1", "cat-2", "cat-1"]);
+
+        fs::remove_dir_all(folder)?;
+
+        Ok(())
+    }
+}